@@ -32,13 +32,13 @@ fn generate_main_func(module: &mut Module) {
         Value::Temporary("r".into()),
         Type::Word,
         Instr::Call(
-            "add".into(),
+            Value::Global("add".into()),
             vec![(Type::Word, Value::Const(1)), (Type::Word, Value::Const(1))],
             None,
         ),
     );
     func.add_instr(Instr::Call(
-        "printf".into(),
+        Value::Global("printf".into()),
         vec![
             (Type::Long, Value::Global("fmt".into())),
             (Type::Word, Value::Temporary("r".into())),
@@ -52,7 +52,7 @@ fn generate_main_func(module: &mut Module) {
 
 fn generate_data(module: &mut Module) {
     let items = vec![
-        (Type::Byte, DataItem::Str("One and one make %d!\\n".into())),
+        (Type::Byte, DataItem::Str("One and one make %d!\n".into())),
         (Type::Byte, DataItem::Const(0)),
     ];
     let data = DataDef::new(Linkage::private(), "fmt", None, items);
@@ -61,6 +61,7 @@ fn generate_data(module: &mut Module) {
 
 fn main() {
     let mut module = Module::new();
+    module.add_extern(FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic());
     generate_add_func(&mut module);
     generate_main_func(&mut module);
     generate_data(&mut module);
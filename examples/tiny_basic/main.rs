@@ -19,7 +19,10 @@
 //!
 //! Expected output: `120` (factorial of 5).
 
-use qbe::{Cmp, DataDef, DataItem, Function, Instr, Linkage, Module, Type, Value};
+use qbe::{
+    BlockRef, Cmp, DataDef, DataItem, FunctionBuilder, FunctionDecl, Instr, Linkage, Module, Type,
+    TypedValue, Value, Var,
+};
 use std::collections::HashSet;
 use std::io::Read;
 use std::process::ExitCode;
@@ -365,167 +368,163 @@ fn parse(tokens: Vec<Token>) -> Result<Vec<(u32, Stmt)>, String> {
 }
 
 struct Codegen {
-    module: Module,
-    line_set: HashSet<u32>,
-    line_order: Vec<u32>,
-    next_temp: u32,
+    builder: FunctionBuilder,
+    /// Block of each line, in program order
+    lines: Vec<(u32, BlockRef)>,
+    end: BlockRef,
+    /// Each BASIC variable, in order of appearance
+    vars: Vec<(String, Var)>,
 }
 
 impl Codegen {
     fn new(program: &[(u32, Stmt)]) -> Self {
-        let line_order: Vec<u32> = program.iter().map(|(n, _)| *n).collect();
-        let line_set: HashSet<u32> = line_order.iter().copied().collect();
+        let mut builder = FunctionBuilder::new(Linkage::public(), "main", Some(Type::Word));
+        let vars = collect_vars(program)
+            .into_iter()
+            .map(|name| {
+                let var = builder.declare_var(Type::Word, &name);
+                (name, var)
+            })
+            .collect();
+        let lines = program
+            .iter()
+            .map(|(n, _)| (*n, builder.new_block(&format!("line_{n}"))))
+            .collect();
+        let end = builder.new_block("end_program");
         Self {
-            module: Module::new(),
-            line_set,
-            line_order,
-            next_temp: 0,
+            builder,
+            lines,
+            end,
+            vars,
         }
     }
 
-    fn line_label(n: u32) -> String {
-        format!("line_{n}")
+    fn line_block(&self, n: u32) -> Option<BlockRef> {
+        self.lines
+            .iter()
+            .find(|(line, _)| *line == n)
+            .map(|(_, block)| *block)
     }
 
-    fn next_label(&self, idx: usize) -> String {
-        match self.line_order.get(idx + 1) {
-            Some(n) => Self::line_label(*n),
-            None => "end_program".to_string(),
-        }
+    fn var(&self, name: &str) -> Var {
+        self.vars
+            .iter()
+            .find(|(var, _)| var == name)
+            .map(|(_, var)| *var)
+            .expect("variables are collected before lowering")
     }
 
-    fn fresh_temp(&mut self) -> Value {
-        let v = Value::Temporary(format!("t{}", self.next_temp));
-        self.next_temp += 1;
-        v
+    fn next_block(&self, idx: usize) -> BlockRef {
+        match self.lines.get(idx + 1) {
+            Some((_, block)) => *block,
+            None => self.end,
+        }
     }
 
-    fn lower_expr(&mut self, func: &mut Function, e: &Expr) -> Value {
+    fn lower_expr(&mut self, e: &Expr) -> TypedValue {
         match e {
-            Expr::Num(n) => Value::Const(*n as u64),
-            Expr::Var(name) => {
-                let dest = self.fresh_temp();
-                func.assign_instr(
-                    dest.clone(),
-                    Type::Word,
-                    Instr::Load(Type::Word, Value::Temporary(name.clone())),
-                );
-                dest
-            }
+            Expr::Num(n) => TypedValue::new(Type::Word, *n as u64),
+            Expr::Var(name) => self.builder.use_var(self.var(name)),
             Expr::BinOp(op, l, r) => {
-                let lv = self.lower_expr(func, l);
-                let rv = self.lower_expr(func, r);
-                let dest = self.fresh_temp();
-                let instr = match op {
-                    BinOp::Add => Instr::Add(lv, rv),
-                    BinOp::Sub => Instr::Sub(lv, rv),
-                    BinOp::Mul => Instr::Mul(lv, rv),
-                    BinOp::Div => Instr::Div(lv, rv),
-                    BinOp::Eq => Instr::Cmp(Type::Word, Cmp::Eq, lv, rv),
-                    BinOp::Ne => Instr::Cmp(Type::Word, Cmp::Ne, lv, rv),
-                    BinOp::Lt => Instr::Cmp(Type::Word, Cmp::Slt, lv, rv),
-                    BinOp::Gt => Instr::Cmp(Type::Word, Cmp::Sgt, lv, rv),
-                    BinOp::Le => Instr::Cmp(Type::Word, Cmp::Sle, lv, rv),
-                    BinOp::Ge => Instr::Cmp(Type::Word, Cmp::Sge, lv, rv),
-                };
-                func.assign_instr(dest.clone(), Type::Word, instr);
-                dest
+                let lv = self.lower_expr(l);
+                let rv = self.lower_expr(r);
+                let b = &mut self.builder;
+                match op {
+                    BinOp::Add => b.add(&lv, &rv),
+                    BinOp::Sub => b.sub(&lv, &rv),
+                    BinOp::Mul => b.mul(&lv, &rv),
+                    BinOp::Div => b.div(&lv, &rv),
+                    BinOp::Eq => b.cmp(Cmp::Eq, &lv, &rv),
+                    BinOp::Ne => b.cmp(Cmp::Ne, &lv, &rv),
+                    BinOp::Lt => b.cmp(Cmp::Slt, &lv, &rv),
+                    BinOp::Gt => b.cmp(Cmp::Sgt, &lv, &rv),
+                    BinOp::Le => b.cmp(Cmp::Sle, &lv, &rv),
+                    BinOp::Ge => b.cmp(Cmp::Sge, &lv, &rv),
+                }
             }
         }
     }
 
-    fn lower_stmt(
-        &mut self,
-        func: &mut Function,
-        stmt: &Stmt,
-        next_label: &str,
-    ) -> Result<(), String> {
+    fn lower_stmt(&mut self, stmt: &Stmt, next: BlockRef) -> Result<(), String> {
         match stmt {
             Stmt::Let(name, e) => {
-                let v = self.lower_expr(func, e);
-                func.add_instr(Instr::Store(Type::Word, Value::Temporary(name.clone()), v));
-                func.add_instr(Instr::Jmp(next_label.to_string()));
+                let v = self.lower_expr(e);
+                self.builder.def_var(self.var(name), &v);
+                self.builder.jmp(next);
             }
             Stmt::Print(e) => {
-                let v = self.lower_expr(func, e);
-                func.add_instr(Instr::Call(
-                    "printf".to_string(),
+                let v = self.lower_expr(e);
+                self.builder.add_instr(Instr::Call(
+                    Value::Global("printf".to_string()),
                     vec![
                         (Type::Long, Value::Global("fmt_int".to_string())),
-                        (Type::Word, v),
+                        (Type::Word, v.into()),
                     ],
                     Some(1),
                 ));
-                func.add_instr(Instr::Jmp(next_label.to_string()));
+                self.builder.jmp(next);
             }
             Stmt::Rem => {
-                func.add_instr(Instr::Jmp(next_label.to_string()));
+                self.builder.jmp(next);
             }
             Stmt::If(cond, target) => {
-                if !self.line_set.contains(target) {
+                let Some(target) = self.line_block(*target) else {
                     return Err(format!("IF...THEN target {target} is not a line number"));
-                }
-                let v = self.lower_expr(func, cond);
-                func.add_instr(Instr::Jnz(
-                    v,
-                    Self::line_label(*target),
-                    next_label.to_string(),
-                ));
+                };
+                let v = self.lower_expr(cond);
+                self.builder.jnz(v, target, next);
             }
             Stmt::Goto(target) => {
-                if !self.line_set.contains(target) {
+                let Some(target) = self.line_block(*target) else {
                     return Err(format!("GOTO target {target} is not a line number"));
-                }
-                func.add_instr(Instr::Jmp(Self::line_label(*target)));
+                };
+                self.builder.jmp(target);
             }
             Stmt::End => {
-                func.add_instr(Instr::Ret(Some(Value::Const(0))));
+                self.builder.ret(Some(Value::Const(0)));
             }
         }
         Ok(())
     }
 
     fn emit(mut self, program: &[(u32, Stmt)]) -> Result<Module, String> {
-        self.module.add_data(DataDef::new(
+        let mut module = Module::new();
+        module
+            .add_extern(FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic());
+        module.add_data(DataDef::new(
             Linkage::private(),
             "fmt_int",
             None,
             vec![
-                (Type::Byte, DataItem::Str("%d\\n".to_string())),
+                (Type::Byte, DataItem::Str("%d\n".to_string())),
                 (Type::Byte, DataItem::Const(0)),
             ],
         ));
 
-        let mut main = Function::new(Linkage::public(), "main", Vec::new(), Some(Type::Word));
-
-        let vars = collect_vars(program);
-
-        main.add_block("entry");
-        for v in &vars {
-            main.assign_instr(Value::Temporary(v.clone()), Type::Long, Instr::Alloc4(4));
-            main.add_instr(Instr::Store(
-                Type::Word,
-                Value::Temporary(v.clone()),
-                Value::Const(0),
-            ));
+        // Variables start out as zero
+        for (_, var) in &self.vars {
+            self.builder.def_var(*var, &TypedValue::new(Type::Word, 0));
         }
-        let first_label = match self.line_order.first() {
-            Some(n) => Self::line_label(*n),
-            None => "end_program".to_string(),
-        };
-        main.add_instr(Instr::Jmp(first_label));
-
-        for (idx, (n, stmt)) in program.iter().enumerate() {
-            main.add_block(Self::line_label(*n));
-            let next = self.next_label(idx);
-            self.lower_stmt(&mut main, stmt, &next)?;
+        let first = self.lines.first().map_or(self.end, |(_, block)| *block);
+        self.builder.jmp(first);
+
+        for (idx, (_, stmt)) in program.iter().enumerate() {
+            self.builder.switch_to(self.lines[idx].1);
+            let next = self.next_block(idx);
+            self.lower_stmt(stmt, next)?;
         }
 
-        main.add_block("end_program");
-        main.add_instr(Instr::Ret(Some(Value::Const(0))));
+        self.builder.switch_to(self.end);
+        self.builder.ret(Some(Value::Const(0)));
+
+        // Any line may jump to any other, so predecessors are only known now
+        for (_, block) in &self.lines {
+            self.builder.seal_block(*block);
+        }
+        self.builder.seal_block(self.end);
 
-        self.module.add_function(main);
-        Ok(self.module)
+        module.add_function(self.builder.build());
+        Ok(module)
     }
 }
 
@@ -581,6 +580,13 @@ fn run() -> Result<(), String> {
     let tokens = lex(&source)?;
     let program = parse(tokens)?;
     let module = codegen(&program)?;
+    module.check_calls().map_err(|errors| {
+        errors
+            .iter()
+            .map(|e| e.to_string())
+            .collect::<Vec<_>>()
+            .join("\n")
+    })?;
     print!("{module}");
     Ok(())
 }
@@ -0,0 +1,1503 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Building functions with generated temporaries and labels.
+
+use crate::typecheck::{fits, is_valid_comparison, temp_type, INTEGER};
+use crate::{
+    BlockItem, Cmp, DataDef, DataItem, Function, Instr, Linkage, Statement, Type, TypeErrorKind,
+    Value,
+};
+use std::collections::{HashMap, HashSet};
+
+#[cfg(test)]
+mod tests;
+
+/// A value together with its type, returned by [`FunctionBuilder`] for the
+/// temporaries it assigns
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TypedValue {
+    /// Type of the value, as used by instructions reading it
+    pub ty: Type,
+    /// The value itself
+    pub value: Value,
+}
+
+impl TypedValue {
+    /// Pairs `value` with its type, e.g. a constant with the type it is
+    /// used as
+    pub fn new(ty: Type, value: impl Into<Value>) -> Self {
+        Self {
+            ty,
+            value: value.into(),
+        }
+    }
+}
+
+impl From<TypedValue> for Value {
+    fn from(typed: TypedValue) -> Self {
+        typed.value
+    }
+}
+
+impl From<&TypedValue> for Value {
+    fn from(typed: &TypedValue) -> Self {
+        typed.value.clone()
+    }
+}
+
+/// How a conversion treats integers, see [`FunctionBuilder::convert`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Signedness {
+    /// Integers are signed: they are sign extended, and converted to and from
+    /// floats as signed values
+    Signed,
+    /// Integers are unsigned: they are zero extended, and converted to and
+    /// from floats as unsigned values
+    Unsigned,
+}
+
+/// Jump targets of a loop built by [`FunctionBuilder::loop_`] and friends
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Loop {
+    /// Block starting the next iteration
+    pub continue_to: BlockRef,
+    /// Block following the loop
+    pub break_to: BlockRef,
+}
+
+/// A variable of a function being built, see [`FunctionBuilder::declare_var`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Var(usize);
+
+/// A block of the function being built by a [`FunctionBuilder`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct BlockRef(usize);
+
+/// Hands out names that haven't been used yet
+#[derive(Debug, Default)]
+struct Names {
+    taken: HashSet<String>,
+    /// Next suffix to try for each hint
+    next: HashMap<String, usize>,
+}
+
+impl Names {
+    /// Returns `hint` if it is free, or `hint` followed by `.` and the
+    /// smallest free number otherwise
+    fn fresh(&mut self, hint: &str) -> String {
+        let mut name = hint.to_string();
+        if hint.is_empty() || self.taken.contains(&name) {
+            let next = self.next.entry(hint.to_string()).or_insert(1);
+            loop {
+                name = format!("{hint}.{next}");
+                *next += 1;
+                if !self.taken.contains(&name) {
+                    break;
+                }
+            }
+        }
+        self.taken.insert(name.clone());
+        name
+    }
+}
+
+/// Builds a [`Function`], generating unique temporaries and block labels and
+/// keeping track of the block instructions are added to.
+///
+/// Every temporary and label is handed out by the builder: asking for a name
+/// that is already taken, by a parameter, an earlier temporary or a name
+/// picked through [`FunctionBuilder::temp`], appends a numeric suffix
+/// (`x`, `x.1`, `x.2`...). Temporaries assigned without a name are called
+/// `.1`, `.2`, and so on. Names put into the function without going through
+/// the builder are not tracked.
+///
+/// The builder starts with an entry block labelled `start`, which is the
+/// current block. New blocks are added to the end of the function but
+/// become current only through [`FunctionBuilder::switch_to`]. Jumps between
+/// blocks must be added through the builder, which keeps track of them for
+/// [variables](FunctionBuilder::declare_var); a block doesn't fall through to
+/// the next one.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Cmp, FunctionBuilder, Instr, Linkage, Type, Value};
+///
+/// let mut builder = FunctionBuilder::new(Linkage::public(), "max", Some(Type::Word));
+/// let a = builder.param(Type::Word, "a");
+/// let b = builder.param(Type::Word, "b");
+///
+/// let a_wins = builder.new_block("a_wins");
+/// let b_wins = builder.new_block("b_wins");
+/// let greater = builder.assign(
+///     Type::Word,
+///     Instr::Cmp(Type::Word, Cmp::Sgt, a.value.clone(), b.value.clone()),
+/// );
+/// builder.jnz(greater, a_wins, b_wins);
+///
+/// builder.switch_to(a_wins);
+/// builder.ret(Some(a.into()));
+/// builder.switch_to(b_wins);
+/// builder.ret(Some(b.into()));
+///
+/// assert_eq!(
+///     builder.build().to_string(),
+///     "export function w $max(w %a, w %b) {\n\
+///      @start\n\
+///      \t%.1 =w csgtw %a, %b\n\
+///      \tjnz %.1, @a_wins, @b_wins\n\
+///      @a_wins\n\
+///      \tret %a\n\
+///      @b_wins\n\
+///      \tret %b\n\
+///      }"
+/// );
+/// ```
+#[derive(Debug)]
+pub struct FunctionBuilder {
+    func: Function,
+    current: BlockRef,
+    temps: Names,
+    labels: Names,
+    /// Block of each label
+    blocks: HashMap<String, BlockRef>,
+    /// Predecessors of each block, without duplicates
+    preds: Vec<Vec<BlockRef>>,
+    /// Whether each block is sealed, see [`FunctionBuilder::seal_block`]
+    sealed: Vec<bool>,
+    /// Type and name hint of each variable
+    vars: Vec<(Type, String)>,
+    /// Value of each variable at the end of each block, where known
+    defs: HashMap<(BlockRef, Var), Value>,
+    /// Operandless `phi`s of unsealed blocks, by temporary
+    incomplete: HashMap<BlockRef, Vec<(Var, String)>>,
+    /// Block and variable of each `phi` placed for a variable, by temporary
+    var_phis: HashMap<String, (BlockRef, Var)>,
+    /// Values replacing the temporaries of removed `phi`s
+    replaced: HashMap<String, Value>,
+}
+
+impl FunctionBuilder {
+    /// Starts a function without parameters, whose entry block is current
+    pub fn new(linkage: Linkage, name: impl Into<String>, return_ty: Option<Type>) -> Self {
+        let mut builder = Self {
+            func: Function::new(linkage, name, Vec::new(), return_ty),
+            current: BlockRef(0),
+            temps: Names::default(),
+            labels: Names::default(),
+            blocks: HashMap::new(),
+            preds: Vec::new(),
+            sealed: Vec::new(),
+            vars: Vec::new(),
+            defs: HashMap::new(),
+            incomplete: HashMap::new(),
+            var_phis: HashMap::new(),
+            replaced: HashMap::new(),
+        };
+        let start = builder.new_block("start");
+        builder.seal_block(start);
+        builder
+    }
+
+    /// Makes the function variadic
+    pub fn variadic(mut self) -> Self {
+        self.func.variadic = true;
+        self
+    }
+
+    /// Adds a parameter of type `ty` named after `hint` and returns it
+    pub fn param(&mut self, ty: Type, hint: &str) -> TypedValue {
+        let value = self.temp(hint);
+        self.func.arguments.push((ty.clone(), value.clone()));
+        TypedValue::new(temp_type(&ty), value)
+    }
+
+    /// Reserves a temporary named after `hint`, for instructions added
+    /// through [`FunctionBuilder::assign_to`] or for `phi` results
+    pub fn temp(&mut self, hint: &str) -> Value {
+        Value::Temporary(self.temps.fresh(hint))
+    }
+
+    /// Adds an empty block labelled after `hint` at the end of the function.
+    /// The current block doesn't change.
+    pub fn new_block(&mut self, hint: &str) -> BlockRef {
+        let label = self.labels.fresh(hint);
+        let block = BlockRef(self.func.blocks.len());
+        self.blocks.insert(label.clone(), block);
+        self.func.add_block(label);
+        self.preds.push(Vec::new());
+        self.sealed.push(false);
+        block
+    }
+
+    /// Makes `block` the block instructions are added to
+    pub fn switch_to(&mut self, block: BlockRef) {
+        self.current = block;
+    }
+
+    /// Returns the block instructions are added to
+    pub fn current_block(&self) -> BlockRef {
+        self.current
+    }
+
+    /// Returns the label of `block`
+    pub fn label(&self, block: BlockRef) -> &str {
+        &self.func.blocks[block.0].label
+    }
+
+    /// Returns true if the current block ends with a jump, a return or
+    /// `hlt`, so no more instructions can be added to it
+    pub fn is_terminated(&self) -> bool {
+        self.is_block_terminated(self.current)
+    }
+
+    /// Returns true if `block` ends with a jump, a return or `hlt`
+    pub fn is_block_terminated(&self, block: BlockRef) -> bool {
+        let last = self.func.blocks[block.0]
+            .items
+            .iter()
+            .rev()
+            .find(|item| !matches!(item, BlockItem::Comment(_)));
+        matches!(
+            last,
+            Some(BlockItem::Statement(Statement::Volatile(instr))) if instr.is_terminator()
+        )
+    }
+
+    /// Adds a comment to the current block
+    pub fn add_comment(&mut self, contents: impl Into<String>) {
+        self.func.blocks[self.current.0].add_comment(contents);
+    }
+
+    /// Adds an instruction whose result, if any, is discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current block is terminated, or if `instr` jumps to a
+    /// sealed block.
+    pub fn add_instr(&mut self, instr: Instr) {
+        let instr = self.prepare(instr);
+        if instr.is_terminator() {
+            for label in instr.labels() {
+                let Some(&target) = self.blocks.get(label) else {
+                    continue;
+                };
+                assert!(
+                    !self.sealed[target.0],
+                    "cannot jump to @{label}: the block is sealed"
+                );
+                if !self.preds[target.0].contains(&self.current) {
+                    self.preds[target.0].push(self.current);
+                }
+            }
+        }
+        self.func.blocks[self.current.0].add_instr(instr);
+    }
+
+    /// Assigns the result of `instr` to a new temporary and returns it. The
+    /// returned type is the one the temporary gets: the base type of `ty`,
+    /// which is the pointer type `l` for aggregates.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current block is terminated.
+    pub fn assign(&mut self, ty: Type, instr: Instr) -> TypedValue {
+        let temp = self.temp("");
+        self.assign_to(temp, ty, instr)
+    }
+
+    /// Like [`FunctionBuilder::assign`], naming the temporary after `hint`
+    pub fn assign_named(&mut self, hint: &str, ty: Type, instr: Instr) -> TypedValue {
+        let temp = self.temp(hint);
+        self.assign_to(temp, ty, instr)
+    }
+
+    /// Assigns the result of `instr` to `temp`, which should come from
+    /// [`FunctionBuilder::temp`], and returns it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the current block is terminated.
+    pub fn assign_to(&mut self, temp: Value, ty: Type, instr: Instr) -> TypedValue {
+        let typed = TypedValue::new(temp_type(&ty), temp.clone());
+        let instr = self.prepare(instr);
+        self.func.blocks[self.current.0].assign_instr(temp, ty, instr);
+        typed
+    }
+
+    /// Ends the current block with a jump to `target`
+    pub fn jmp(&mut self, target: BlockRef) {
+        let target = self.label(target).to_string();
+        self.add_instr(Instr::Jmp(target));
+    }
+
+    /// Ends the current block with a jump to `if_nonzero` if `cond` is
+    /// nonzero and to `if_zero` otherwise
+    pub fn jnz(&mut self, cond: impl Into<Value>, if_nonzero: BlockRef, if_zero: BlockRef) {
+        let if_nonzero = self.label(if_nonzero).to_string();
+        let if_zero = self.label(if_zero).to_string();
+        self.add_instr(Instr::Jnz(cond.into(), if_nonzero, if_zero));
+    }
+
+    /// Ends the current block by returning from the function
+    pub fn ret(&mut self, value: Option<Value>) {
+        self.add_instr(Instr::Ret(value));
+    }
+
+    /// Ends the current block by terminating the program
+    pub fn hlt(&mut self) {
+        self.add_instr(Instr::Hlt);
+    }
+
+    /// Returns the function built so far
+    pub fn function(&self) -> &Function {
+        &self.func
+    }
+
+    /// Returns the built function
+    pub fn build(self) -> Function {
+        self.func
+    }
+
+    /// Checks that `instr` can be added to the current block and replaces
+    /// operands naming removed `phi`s
+    fn prepare(&self, mut instr: Instr) -> Instr {
+        assert!(
+            !self.is_terminated(),
+            "block @{} is already terminated",
+            self.label(self.current)
+        );
+        for operand in instr.operands_mut() {
+            *operand = self.resolve(operand);
+        }
+        instr
+    }
+}
+
+/// Instructions on typed values. Result types are inferred from the
+/// operands, and operands whose types don't make sense for an instruction
+/// are rejected right away.
+///
+/// Operands must have base types and, for binary instructions, the same
+/// type: mixing widths requires an explicit [`FunctionBuilder::convert`] or
+/// [`FunctionBuilder::unify`].
+///
+/// # Panics
+///
+/// These methods panic if an operand has a type the instruction can't
+/// take, naming the instruction and the types involved, or if the current
+/// block is terminated.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Cmp, FunctionBuilder, Linkage, Signedness, Type, TypedValue};
+///
+/// let mut builder = FunctionBuilder::new(Linkage::public(), "average", Some(Type::Double));
+/// let total = builder.param(Type::Long, "total");
+/// let count = builder.param(Type::Word, "count");
+///
+/// let (total, count) = builder.unify(&total, &count, Signedness::Unsigned);
+/// let total = builder.convert(&total, Type::Double, Signedness::Signed);
+/// let count = builder.convert(&count, Type::Double, Signedness::Unsigned);
+/// let average = builder.div(&total, &count);
+/// assert_eq!(average.ty, Type::Double);
+/// builder.ret(Some(average.into()));
+///
+/// assert_eq!(
+///     builder.build().to_string(),
+///     "export function d $average(l %total, w %count) {\n\
+///      @start\n\
+///      \t%.1 =l extuw %count\n\
+///      \t%.2 =d sltof %total\n\
+///      \t%.3 =d ultof %.1\n\
+///      \t%.4 =d div %.2, %.3\n\
+///      \tret %.4\n\
+///      }"
+/// );
+/// ```
+impl FunctionBuilder {
+    /// Adds two integers or floats
+    pub fn add(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("add", lhs, rhs, NUMBER, Instr::Add)
+    }
+
+    /// Subtracts `rhs` from `lhs`
+    pub fn sub(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("sub", lhs, rhs, NUMBER, Instr::Sub)
+    }
+
+    /// Multiplies two integers or floats
+    pub fn mul(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("mul", lhs, rhs, NUMBER, Instr::Mul)
+    }
+
+    /// Divides `lhs` by `rhs`, as signed integers or floats
+    pub fn div(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("div", lhs, rhs, NUMBER, Instr::Div)
+    }
+
+    /// Returns the remainder of the signed division of two integers
+    pub fn rem(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("rem", lhs, rhs, INTEGER, Instr::Rem)
+    }
+
+    /// Divides `lhs` by `rhs` as unsigned integers
+    pub fn udiv(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("udiv", lhs, rhs, INTEGER, Instr::Udiv)
+    }
+
+    /// Returns the remainder of the unsigned division of two integers
+    pub fn urem(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("urem", lhs, rhs, INTEGER, Instr::Urem)
+    }
+
+    /// Bitwise AND of two integers
+    pub fn and(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("and", lhs, rhs, INTEGER, Instr::And)
+    }
+
+    /// Bitwise OR of two integers
+    pub fn or(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("or", lhs, rhs, INTEGER, Instr::Or)
+    }
+
+    /// Bitwise XOR of two integers
+    pub fn xor(&mut self, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        self.binary("xor", lhs, rhs, INTEGER, Instr::Xor)
+    }
+
+    /// Shifts an integer left by `amount`, a word or long
+    pub fn shl(&mut self, value: &TypedValue, amount: &TypedValue) -> TypedValue {
+        self.shift("shl", value, amount, Instr::Shl)
+    }
+
+    /// Shifts an integer right, filling with zeros
+    pub fn shr(&mut self, value: &TypedValue, amount: &TypedValue) -> TypedValue {
+        self.shift("shr", value, amount, Instr::Shr)
+    }
+
+    /// Shifts an integer right, preserving its sign
+    pub fn sar(&mut self, value: &TypedValue, amount: &TypedValue) -> TypedValue {
+        self.shift("sar", value, amount, Instr::Sar)
+    }
+
+    /// Negates an integer or float
+    pub fn neg(&mut self, value: &TypedValue) -> TypedValue {
+        expect_one_of("neg", value, NUMBER);
+        self.assign(value.ty.clone(), Instr::Neg(value.into()))
+    }
+
+    /// Copies a value into a new temporary
+    pub fn copy(&mut self, value: &TypedValue) -> TypedValue {
+        expect_one_of("copy", value, NUMBER);
+        self.assign(value.ty.clone(), Instr::Copy(value.into()))
+    }
+
+    /// Compares two values of the same type, returning 1 or 0 as a word
+    pub fn cmp(&mut self, cmp: Cmp, lhs: &TypedValue, rhs: &TypedValue) -> TypedValue {
+        expect_same("comparison", lhs, rhs);
+        assert!(
+            is_valid_comparison(cmp, &lhs.ty),
+            "{}",
+            TypeErrorKind::InvalidComparison(cmp, lhs.ty.clone())
+        );
+        self.assign(
+            Type::Word,
+            Instr::Cmp(lhs.ty.clone(), cmp, lhs.into(), rhs.into()),
+        )
+    }
+
+    /// Loads a value of type `ty` from the address `addr`. Sub-word types
+    /// must say whether to sign or zero extend, and the result is a word.
+    pub fn load(&mut self, ty: Type, addr: &TypedValue) -> TypedValue {
+        expect_address("load", addr);
+        assert!(
+            ty.is_base()
+                || matches!(
+                    ty,
+                    Type::SignedByte
+                        | Type::UnsignedByte
+                        | Type::SignedHalfword
+                        | Type::UnsignedHalfword
+                ),
+            "cannot load `{ty}`: use a base type or a signed/unsigned sub-word type"
+        );
+        self.assign(ty.clone(), Instr::Load(ty, addr.into()))
+    }
+
+    /// Stores `value` as type `ty` at the address `addr`. Sub-word types
+    /// store the low bits of a word.
+    pub fn store(&mut self, ty: Type, addr: &TypedValue, value: &TypedValue) {
+        expect_address("store", addr);
+        assert!(
+            !matches!(ty, Type::Aggregate(_) | Type::Env | Type::Zero),
+            "cannot store `{ty}`: use a base or sub-word type"
+        );
+        let expected = temp_type(&ty);
+        assert!(
+            fits(&value.ty, &expected),
+            "cannot store `{}` as `{ty}`: convert the value to `{expected}` first",
+            value.ty
+        );
+        self.add_instr(Instr::Store(ty, addr.into(), value.into()));
+    }
+
+    /// Converts a value to the base type `to`, adding the instruction that
+    /// does it: `extsw`/`extuw` and `exts`/`truncd` between widths, or one
+    /// of the float-integer conversions. Longs are narrowed to words with a
+    /// `copy`. `signedness` tells how integers are extended or converted
+    /// and is ignored for conversions between floats.
+    pub fn convert(&mut self, value: &TypedValue, to: Type, signedness: Signedness) -> TypedValue {
+        expect_one_of("conversion", value, NUMBER);
+        let signed = signedness == Signedness::Signed;
+        let val = Value::from(value);
+        let instr = match (&value.ty, &to) {
+            (from, to) if from == to => return value.clone(),
+            (Type::Word, Type::Long) if signed => Instr::Extsw(val),
+            (Type::Word, Type::Long) => Instr::Extuw(val),
+            (Type::Long, Type::Word) => Instr::Copy(val),
+            (Type::Single, Type::Double) => Instr::Exts(val),
+            (Type::Double, Type::Single) => Instr::Truncd(val),
+            (Type::Word, Type::Single | Type::Double) if signed => Instr::Swtof(val),
+            (Type::Word, Type::Single | Type::Double) => Instr::Uwtof(val),
+            (Type::Long, Type::Single | Type::Double) if signed => Instr::Sltof(val),
+            (Type::Long, Type::Single | Type::Double) => Instr::Ultof(val),
+            (Type::Single, Type::Word | Type::Long) if signed => Instr::Stosi(val),
+            (Type::Single, Type::Word | Type::Long) => Instr::Stoui(val),
+            (Type::Double, Type::Word | Type::Long) if signed => Instr::Dtosi(val),
+            (Type::Double, Type::Word | Type::Long) => Instr::Dtoui(val),
+            (from, to) => panic!("cannot convert `{from}` to `{to}`: use a base type"),
+        };
+        self.assign(to, instr)
+    }
+
+    /// Reinterprets the bits of an integer as a float of the same width, or
+    /// the other way around
+    pub fn cast(&mut self, value: &TypedValue) -> TypedValue {
+        let to = match value.ty {
+            Type::Word => Type::Single,
+            Type::Long => Type::Double,
+            Type::Single => Type::Word,
+            Type::Double => Type::Long,
+            ref ty => panic!("cannot cast `{ty}`: use a base type"),
+        };
+        self.assign(to, Instr::Cast(value.into()))
+    }
+
+    /// Brings two integers or two floats to the same width by extending the
+    /// narrower one, so they can be combined by a binary instruction.
+    /// Mixing integers and floats is refused, as it needs a choice between
+    /// the two that only the caller can make.
+    pub fn unify(
+        &mut self,
+        lhs: &TypedValue,
+        rhs: &TypedValue,
+        signedness: Signedness,
+    ) -> (TypedValue, TypedValue) {
+        expect_one_of("unify", lhs, NUMBER);
+        expect_one_of("unify", rhs, NUMBER);
+        let wider = match (&lhs.ty, &rhs.ty) {
+            (a, b) if a == b => return (lhs.clone(), rhs.clone()),
+            (Type::Word, Type::Long) | (Type::Long, Type::Word) => Type::Long,
+            (Type::Single, Type::Double) | (Type::Double, Type::Single) => Type::Double,
+            (a, b) => panic!("cannot unify `{a}` and `{b}`: convert one operand explicitly"),
+        };
+        (
+            self.convert(lhs, wider.clone(), signedness),
+            self.convert(rhs, wider, signedness),
+        )
+    }
+
+    fn binary(
+        &mut self,
+        name: &str,
+        lhs: &TypedValue,
+        rhs: &TypedValue,
+        types: &[Type],
+        make: fn(Value, Value) -> Instr,
+    ) -> TypedValue {
+        expect_same(name, lhs, rhs);
+        expect_one_of(name, lhs, types);
+        self.assign(lhs.ty.clone(), make(lhs.into(), rhs.into()))
+    }
+
+    fn shift(
+        &mut self,
+        name: &str,
+        value: &TypedValue,
+        amount: &TypedValue,
+        make: fn(Value, Value) -> Instr,
+    ) -> TypedValue {
+        expect_one_of(name, value, INTEGER);
+        expect_one_of(name, amount, INTEGER);
+        self.assign(value.ty.clone(), make(value.into(), amount.into()))
+    }
+}
+
+/// Structured control flow. Each helper creates the blocks it needs, adds
+/// the instructions to the current block and leaves the builder in the
+/// block following the construct. The blocks are sealed once all jumps to
+/// them have been added, so jumping to them from outside the construct
+/// panics.
+///
+/// Closures get the builder with their first block current and may create
+/// blocks of their own. A closure that ends its last block itself, with a
+/// return or a [`FunctionBuilder::break_`] for example, doesn't fall through
+/// to the code following the construct.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Cmp, FunctionBuilder, Instr, Linkage, Type, TypedValue};
+///
+/// // Returns the number of steps the Collatz sequence takes from `n` to 1
+/// let mut builder = FunctionBuilder::new(Linkage::public(), "collatz", Some(Type::Word));
+/// let n = builder.param(Type::Word, "n");
+/// let slot = builder.assign(Type::Long, Instr::Alloc4(4.into()));
+/// let steps = builder.assign(Type::Long, Instr::Alloc4(4.into()));
+/// let zero = TypedValue::new(Type::Word, 0);
+/// let one = TypedValue::new(Type::Word, 1);
+/// builder.store(Type::Word, &slot, &n);
+/// builder.store(Type::Word, &steps, &zero);
+///
+/// builder.loop_(|b, lp| {
+///     let n = b.load(Type::Word, &slot);
+///     let done = b.cmp(Cmp::Ule, &n, &one);
+///     b.if_then(&done, |b| b.break_(lp));
+///
+///     let odd = b.and(&n, &one);
+///     let next = b.if_else_value(
+///         &odd,
+///         |b| {
+///             let three = TypedValue::new(Type::Word, 3);
+///             let tripled = b.mul(&n, &three);
+///             b.add(&tripled, &one)
+///         },
+///         |b| b.shr(&n, &one),
+///     );
+///     b.store(Type::Word, &slot, &next);
+///
+///     let count = b.load(Type::Word, &steps);
+///     let count = b.add(&count, &one);
+///     b.store(Type::Word, &steps, &count);
+/// });
+///
+/// let count = builder.load(Type::Word, &steps);
+/// builder.ret(Some(count.into()));
+///
+/// let func = builder.build();
+/// assert_eq!(func.verify_ssa(), Ok(()));
+/// ```
+impl FunctionBuilder {
+    /// Runs `then` if `cond` is nonzero
+    pub fn if_then(&mut self, cond: &TypedValue, then: impl FnOnce(&mut Self)) {
+        expect_one_of("jnz", cond, INTEGER);
+        let then_block = self.new_block("then");
+        let end = self.new_block("endif");
+        self.jnz(cond, then_block, end);
+        self.seal_block(then_block);
+
+        self.switch_to(then_block);
+        then(self);
+        self.fall_through(end);
+        self.seal_block(end);
+        self.switch_to(end);
+    }
+
+    /// Runs `then` if `cond` is nonzero and `else_` otherwise
+    pub fn if_else(
+        &mut self,
+        cond: &TypedValue,
+        then: impl FnOnce(&mut Self),
+        else_: impl FnOnce(&mut Self),
+    ) {
+        self.branch(cond, then, else_);
+    }
+
+    /// Like [`FunctionBuilder::if_else`], merging the values the branches
+    /// return with a `phi`. A branch that doesn't fall through doesn't
+    /// contribute a value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the values have different types or if neither branch falls
+    /// through.
+    pub fn if_else_value(
+        &mut self,
+        cond: &TypedValue,
+        then: impl FnOnce(&mut Self) -> TypedValue,
+        else_: impl FnOnce(&mut Self) -> TypedValue,
+    ) -> TypedValue {
+        let arms = self.branch(cond, then, else_);
+        if let [(Some(_), lhs), (Some(_), rhs)] = &arms {
+            expect_same("phi", lhs, rhs);
+        }
+        let incoming: Vec<(BlockRef, TypedValue)> = arms
+            .into_iter()
+            .filter_map(|(end, value)| Some((end?, value)))
+            .collect();
+        assert!(
+            !incoming.is_empty(),
+            "neither branch of `if_else_value` falls through"
+        );
+        self.phi(&incoming)
+    }
+
+    /// Merges values coming from the given predecessors of the current block
+    /// with a `phi`, which must come before other instructions of the block.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the values have different types or if there are none.
+    pub fn phi(&mut self, incoming: &[(BlockRef, TypedValue)]) -> TypedValue {
+        let Some((_, first)) = incoming.first() else {
+            panic!("`phi` needs at least one value");
+        };
+        let args = incoming
+            .iter()
+            .map(|(block, value)| {
+                expect_same("phi", first, value);
+                (self.label(*block).to_string(), value.into())
+            })
+            .collect();
+        self.assign(first.ty.clone(), Instr::Phi(args))
+    }
+
+    /// Repeats `body` until it breaks out of the loop. `continue` starts the
+    /// body over.
+    pub fn loop_(&mut self, body: impl FnOnce(&mut Self, Loop)) {
+        let header = self.new_block("loop");
+        let end = self.new_block("loop_end");
+        self.jmp(header);
+
+        self.switch_to(header);
+        body(
+            self,
+            Loop {
+                continue_to: header,
+                break_to: end,
+            },
+        );
+        self.fall_through(header);
+        self.seal_block(header);
+        self.seal_block(end);
+        self.switch_to(end);
+    }
+
+    /// Repeats `body` while the value computed by `cond` is nonzero.
+    /// `continue` evaluates the condition again.
+    pub fn while_(
+        &mut self,
+        cond: impl FnOnce(&mut Self) -> TypedValue,
+        body: impl FnOnce(&mut Self, Loop),
+    ) {
+        self.for_(cond, |_| {}, body);
+    }
+
+    /// Repeats `body` followed by `step` while the value computed by `cond`
+    /// is nonzero, like C's `for (; cond; step) body`. `continue` jumps to
+    /// `step`.
+    pub fn for_(
+        &mut self,
+        cond: impl FnOnce(&mut Self) -> TypedValue,
+        step: impl FnOnce(&mut Self),
+        body: impl FnOnce(&mut Self, Loop),
+    ) {
+        let header = self.new_block("loop");
+        let body_block = self.new_block("loop_body");
+        let latch = self.new_block("loop_step");
+        let end = self.new_block("loop_end");
+        self.jmp(header);
+
+        self.switch_to(header);
+        let cond = cond(self);
+        expect_one_of("jnz", &cond, INTEGER);
+        self.jnz(&cond, body_block, end);
+        self.seal_block(body_block);
+
+        self.switch_to(body_block);
+        body(
+            self,
+            Loop {
+                continue_to: latch,
+                break_to: end,
+            },
+        );
+        self.fall_through(latch);
+        self.seal_block(latch);
+        self.seal_block(end);
+
+        self.switch_to(latch);
+        step(self);
+        self.fall_through(header);
+        self.seal_block(header);
+        self.switch_to(end);
+    }
+
+    /// Jumps out of `lp`
+    pub fn break_(&mut self, lp: Loop) {
+        self.jmp(lp.break_to);
+    }
+
+    /// Jumps to the next iteration of `lp`
+    pub fn continue_(&mut self, lp: Loop) {
+        self.jmp(lp.continue_to);
+    }
+
+    /// Emits both arms of an `if`/`else` and switches to the block following
+    /// them. Returns what each arm returned, along with the block it ended
+    /// in if it falls through.
+    fn branch<R>(
+        &mut self,
+        cond: &TypedValue,
+        then: impl FnOnce(&mut Self) -> R,
+        else_: impl FnOnce(&mut Self) -> R,
+    ) -> [(Option<BlockRef>, R); 2] {
+        expect_one_of("jnz", cond, INTEGER);
+        let then_block = self.new_block("then");
+        let else_block = self.new_block("else");
+        self.jnz(cond, then_block, else_block);
+        self.seal_block(then_block);
+        self.seal_block(else_block);
+
+        self.switch_to(then_block);
+        let then_value = then(self);
+        let then_end = (!self.is_terminated()).then_some(self.current);
+
+        self.switch_to(else_block);
+        let else_value = else_(self);
+        let else_end = (!self.is_terminated()).then_some(self.current);
+
+        let end = self.new_block("endif");
+        for block in [then_end, else_end].into_iter().flatten() {
+            self.switch_to(block);
+            self.jmp(end);
+        }
+        self.seal_block(end);
+        self.switch_to(end);
+        [(then_end, then_value), (else_end, else_value)]
+    }
+
+    /// Jumps to `target` unless the current block is terminated
+    fn fall_through(&mut self, target: BlockRef) {
+        if !self.is_terminated() {
+            self.jmp(target);
+        }
+    }
+}
+
+/// Variables, turned into SSA form as the function is built.
+///
+/// A variable holds a value of a base type that may be redefined, in
+/// different blocks and any number of times. Reading it yields the value
+/// of its latest definition, with `phi` instructions placed where
+/// definitions from different predecessors meet, following the algorithm of
+/// Braun et al., "Simple and Efficient Construction of Static Single
+/// Assignment Form". The resulting function needs no stack slots for its
+/// variables and only has the `phi`s it needs.
+///
+/// Reading a variable in a block whose predecessors aren't all known yet
+/// places a `phi` that is completed when the block is sealed with
+/// [`FunctionBuilder::seal_block`]. The entry block and the blocks created
+/// by the structured control flow helpers are sealed for you. Reading a
+/// variable that has no definition on some path yields zero on that path.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Cmp, FunctionBuilder, Linkage, Type, TypedValue};
+///
+/// // Computes n!
+/// let mut builder = FunctionBuilder::new(Linkage::public(), "factorial", Some(Type::Long));
+/// let n = builder.param(Type::Long, "n");
+/// let one = TypedValue::new(Type::Long, 1);
+///
+/// let i = builder.declare_var(Type::Long, "i");
+/// let acc = builder.declare_var(Type::Long, "acc");
+/// builder.def_var(i, &n);
+/// builder.def_var(acc, &one);
+/// builder.while_(
+///     |b| {
+///         let i = b.use_var(i);
+///         b.cmp(Cmp::Sgt, &i, &one)
+///     },
+///     |b, _| {
+///         let (value, product) = (b.use_var(i), b.use_var(acc));
+///         let product = b.mul(&product, &value);
+///         b.def_var(acc, &product);
+///         let value = b.sub(&value, &one);
+///         b.def_var(i, &value);
+///     },
+/// );
+/// let result = builder.use_var(acc);
+/// builder.ret(Some(result.into()));
+///
+/// let func = builder.build();
+/// assert_eq!(func.verify_ssa(), Ok(()));
+/// assert_eq!(
+///     func.to_string(),
+///     "export function l $factorial(l %n) {\n\
+///      @start\n\
+///      \tjmp @loop\n\
+///      @loop\n\
+///      \t%i =l phi @start %n, @loop_step %.3\n\
+///      \t%acc =l phi @start 1, @loop_step %.2\n\
+///      \t%.1 =w csgtl %i, 1\n\
+///      \tjnz %.1, @loop_body, @loop_end\n\
+///      @loop_body\n\
+///      \t%.2 =l mul %acc, %i\n\
+///      \t%.3 =l sub %i, 1\n\
+///      \tjmp @loop_step\n\
+///      @loop_step\n\
+///      \tjmp @loop\n\
+///      @loop_end\n\
+///      \tret %acc\n\
+///      }"
+/// );
+/// ```
+impl FunctionBuilder {
+    /// Declares a variable of the base type `ty`, whose `phi`s are named
+    /// after `hint`
+    pub fn declare_var(&mut self, ty: Type, hint: &str) -> Var {
+        assert!(
+            NUMBER.contains(&ty),
+            "variables must have a base type, got `{ty}`"
+        );
+        self.vars.push((ty, hint.to_string()));
+        Var(self.vars.len() - 1)
+    }
+
+    /// Sets `var` to `value` from here on in the current block
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` doesn't have the variable's type.
+    pub fn def_var(&mut self, var: Var, value: &TypedValue) {
+        let ty = &self.vars[var.0].0;
+        assert!(
+            value.ty == *ty,
+            "cannot set a `{ty}` variable to `{}`: convert the value first",
+            value.ty
+        );
+        let value = self.resolve(&value.value);
+        self.defs.insert((self.current, var), value);
+    }
+
+    /// Returns the value of `var` at this point of the current block
+    pub fn use_var(&mut self, var: Var) -> TypedValue {
+        let value = self.read_var(var, self.current);
+        TypedValue::new(self.vars[var.0].0.clone(), value)
+    }
+
+    /// Declares that all predecessors of `block` have been added, completing
+    /// the `phi`s placed for variables read in it. No jumps to `block` can
+    /// be added afterwards. Sealing a block more than once does nothing.
+    pub fn seal_block(&mut self, block: BlockRef) {
+        if self.sealed[block.0] {
+            return;
+        }
+        for (var, phi) in self.incomplete.remove(&block).unwrap_or_default() {
+            self.add_phi_operands(var, block, &phi);
+        }
+        self.sealed[block.0] = true;
+    }
+
+    fn read_var(&mut self, var: Var, block: BlockRef) -> Value {
+        if let Some(value) = self.defs.get(&(block, var)) {
+            return self.resolve(value);
+        }
+
+        let value = if !self.sealed[block.0] {
+            let phi = self.insert_phi(var, block);
+            self.incomplete
+                .entry(block)
+                .or_default()
+                .push((var, phi.clone()));
+            Value::Temporary(phi)
+        } else if let [pred] = self.preds[block.0][..] {
+            self.read_var(var, pred)
+        } else if self.preds[block.0].is_empty() {
+            zero(&self.vars[var.0].0)
+        } else {
+            // Defining the variable before reading the predecessors stops
+            // the recursion at loops
+            let phi = self.insert_phi(var, block);
+            self.defs
+                .insert((block, var), Value::Temporary(phi.clone()));
+            self.add_phi_operands(var, block, &phi)
+        };
+        self.defs.insert((block, var), value.clone());
+        value
+    }
+
+    /// Adds an operandless `phi` for `var` after the other `phi`s of `block`
+    fn insert_phi(&mut self, var: Var, block: BlockRef) -> String {
+        let (ty, hint) = self.vars[var.0].clone();
+        let Value::Temporary(phi) = self.temp(&hint) else {
+            unreachable!()
+        };
+        let items = &mut self.func.blocks[block.0].items;
+        let at = items
+            .iter()
+            .position(|item| {
+                !matches!(
+                    item,
+                    BlockItem::Statement(Statement::Assign(_, _, Instr::Phi(_)))
+                )
+            })
+            .unwrap_or(items.len());
+        items.insert(
+            at,
+            BlockItem::Statement(Statement::Assign(
+                Value::Temporary(phi.clone()),
+                ty,
+                Instr::Phi(Vec::new()),
+            )),
+        );
+        self.var_phis.insert(phi.clone(), (block, var));
+        phi
+    }
+
+    fn add_phi_operands(&mut self, var: Var, block: BlockRef, phi: &str) -> Value {
+        for pred in self.preds[block.0].clone() {
+            let value = self.read_var(var, pred);
+            let label = self.label(pred).to_string();
+            if let Some(args) = self.phi_args(phi) {
+                args.push((label, value));
+            }
+        }
+        self.remove_trivial_phi(phi)
+    }
+
+    /// Replaces `phi` by its only operand other than itself, if it has one,
+    /// and then the `phi`s that used it if they became trivial in turn.
+    /// Returns the value standing for `phi` afterwards.
+    fn remove_trivial_phi(&mut self, phi: &str) -> Value {
+        let this = Value::Temporary(phi.to_string());
+        let Some(args) = self.phi_args(phi) else {
+            return self.resolve(&this);
+        };
+        let mut same = None;
+        for (_, value) in args.iter() {
+            if Some(value) == same.as_ref() || *value == this {
+                continue;
+            }
+            if same.is_some() {
+                return this;
+            }
+            same = Some(value.clone());
+        }
+        let (block, var) = self.var_phis.remove(phi).unwrap();
+        let same = same.unwrap_or_else(|| zero(&self.vars[var.0].0));
+
+        let mut users: Vec<String> = self
+            .var_phis
+            .keys()
+            .filter(|user| {
+                self.phi_args_of(user)
+                    .is_some_and(|args| args.iter().any(|(_, value)| *value == this))
+            })
+            .cloned()
+            .collect();
+        users.sort();
+
+        self.func.blocks[block.0].items.retain(|item| {
+            !matches!(
+                item,
+                BlockItem::Statement(Statement::Assign(temp, _, Instr::Phi(_))) if *temp == this
+            )
+        });
+        self.replace(phi, &same);
+
+        for user in users {
+            self.remove_trivial_phi(&user);
+        }
+        self.resolve(&same)
+    }
+
+    /// Replaces every use of the temporary `old` with `new`
+    fn replace(&mut self, old: &str, new: &Value) {
+        let old_value = Value::Temporary(old.to_string());
+        for block in &mut self.func.blocks {
+            for item in &mut block.items {
+                let BlockItem::Statement(
+                    Statement::Assign(_, _, instr) | Statement::Volatile(instr),
+                ) = item
+                else {
+                    continue;
+                };
+                for operand in instr.operands_mut() {
+                    if *operand == old_value {
+                        *operand = new.clone();
+                    }
+                }
+            }
+        }
+        for value in self.defs.values_mut().chain(self.replaced.values_mut()) {
+            if *value == old_value {
+                *value = new.clone();
+            }
+        }
+        self.replaced.insert(old.to_string(), new.clone());
+    }
+
+    /// Returns the value standing for `value` once removed `phi`s are
+    /// replaced
+    fn resolve(&self, value: &Value) -> Value {
+        match value {
+            Value::Temporary(temp) => match self.replaced.get(temp) {
+                Some(new) => self.resolve(new),
+                None => value.clone(),
+            },
+            _ => value.clone(),
+        }
+    }
+
+    fn phi_args(&mut self, phi: &str) -> Option<&mut Vec<(String, Value)>> {
+        let &(block, _) = self.var_phis.get(phi)?;
+        self.func.blocks[block.0]
+            .items
+            .iter_mut()
+            .find_map(|item| match item {
+                BlockItem::Statement(Statement::Assign(
+                    Value::Temporary(temp),
+                    _,
+                    Instr::Phi(args),
+                )) if temp == phi => Some(args),
+                _ => None,
+            })
+    }
+
+    fn phi_args_of(&self, phi: &str) -> Option<&Vec<(String, Value)>> {
+        let &(block, _) = self.var_phis.get(phi)?;
+        self.func.blocks[block.0]
+            .items
+            .iter()
+            .find_map(|item| match item {
+                BlockItem::Statement(Statement::Assign(
+                    Value::Temporary(temp),
+                    _,
+                    Instr::Phi(args),
+                )) if temp == phi => Some(args),
+                _ => None,
+            })
+    }
+}
+
+/// Function addresses for consecutive case values, dispatched to by
+/// [`FunctionBuilder::switch_call`]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CallTable {
+    /// Name of the data definition holding the addresses
+    pub name: String,
+    /// Case value of the first target
+    pub first: i64,
+    /// Names of the functions to call, for case values `first`,
+    /// `first + 1`, and so on
+    pub targets: Vec<String>,
+}
+
+impl CallTable {
+    /// Creates a table named `name` dispatching case value `first` to the
+    /// first of `targets`, `first + 1` to the second, and so on. Values
+    /// outside this range go to the default block of
+    /// [`FunctionBuilder::switch_call`].
+    pub fn new(name: impl Into<String>, first: i64, targets: Vec<String>) -> Self {
+        Self {
+            name: name.into(),
+            first,
+            targets,
+        }
+    }
+
+    /// Returns the data definition holding the addresses, which must be
+    /// added to the module calling through the table
+    pub fn data(&self) -> DataDef {
+        DataDef::new(
+            Linkage::private(),
+            self.name.clone(),
+            Some(8),
+            self.targets
+                .iter()
+                .map(|target| (Type::Long, DataItem::Symbol(target.clone(), None)))
+                .collect(),
+        )
+    }
+}
+
+/// A run of consecutive case values jumping to the same block
+struct CaseRange {
+    low: i64,
+    high: i64,
+    target: BlockRef,
+}
+
+/// Multi-way branches.
+///
+/// QBE has no switch instruction, so [`FunctionBuilder::switch`] compares
+/// the value against the cases: consecutive values with the same target
+/// are checked at once with a range check, and a balanced binary search
+/// over the ranges picks the one to check, so a switch over `n` ranges
+/// takes about `log2(n)` comparisons. When each case calls a different
+/// function, [`FunctionBuilder::switch_call`] instead calls through a table
+/// of function addresses indexed by the value.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{FunctionBuilder, Linkage, Type, TypedValue};
+///
+/// // Returns the number of days in a month, or 0 for invalid months
+/// let mut builder = FunctionBuilder::new(Linkage::public(), "days", Some(Type::Word));
+/// let month = builder.param(Type::Word, "month");
+///
+/// let days = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+/// let mut blocks = std::collections::BTreeMap::new();
+/// let mut cases = Vec::new();
+/// for (i, days) in days.into_iter().enumerate() {
+///     let block = *blocks.entry(days).or_insert_with(|| builder.new_block("days"));
+///     cases.push((i as i64 + 1, block));
+/// }
+/// let invalid = builder.new_block("invalid");
+/// builder.switch(&month, &cases, invalid);
+///
+/// for (days, block) in blocks {
+///     builder.switch_to(block);
+///     builder.ret(Some(TypedValue::new(Type::Word, days).into()));
+/// }
+/// builder.switch_to(invalid);
+/// builder.ret(Some(0.into()));
+///
+/// assert_eq!(builder.build().verify_ssa(), Ok(()));
+/// ```
+impl FunctionBuilder {
+    /// Ends the current block with a jump to the block of the case equal to
+    /// `value`, an integer, or to `default` if there is none.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a case value appears more than once, or doesn't fit in a
+    /// word when `value` is one.
+    pub fn switch(&mut self, value: &TypedValue, cases: &[(i64, BlockRef)], default: BlockRef) {
+        expect_one_of("switch", value, INTEGER);
+        let mut cases = cases.to_vec();
+        cases.sort_by_key(|&(case, _)| case);
+        for pair in cases.windows(2) {
+            assert!(
+                pair[0].0 != pair[1].0,
+                "case {} appears more than once in `switch`",
+                pair[0].0
+            );
+        }
+        if value.ty == Type::Word {
+            for &(case, _) in &cases {
+                assert!(
+                    i32::try_from(case).is_ok(),
+                    "case {case} doesn't fit in the `w` value of `switch`"
+                );
+            }
+        }
+
+        let mut ranges: Vec<CaseRange> = Vec::new();
+        for (case, target) in cases {
+            match ranges.last_mut() {
+                Some(last) if last.target == target && last.high + 1 == case => last.high = case,
+                _ => ranges.push(CaseRange {
+                    low: case,
+                    high: case,
+                    target,
+                }),
+            }
+        }
+        let (min, max) = match value.ty {
+            Type::Word => (i64::from(i32::MIN), i64::from(i32::MAX)),
+            _ => (i64::MIN, i64::MAX),
+        };
+        self.search(value, &ranges, min, max, default);
+    }
+
+    /// Calls the function `table` holds for `value` with `args` and
+    /// continues in a new block, returning the result if `return_ty` is
+    /// given. Values outside the table jump to `default` instead.
+    ///
+    /// The data definition from [`CallTable::data`] must be added to the
+    /// module.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{CallTable, FunctionBuilder, Linkage, Module, Type};
+    ///
+    /// let table = CallTable::new(
+    ///     "handlers",
+    ///     1,
+    ///     vec!["on_open".into(), "on_read".into(), "on_close".into()],
+    /// );
+    ///
+    /// let mut builder = FunctionBuilder::new(Linkage::public(), "dispatch", Some(Type::Word));
+    /// let event = builder.param(Type::Word, "event");
+    /// let unknown = builder.new_block("unknown");
+    /// let result = builder
+    ///     .switch_call(&event, &table, vec![], Some(Type::Word), unknown)
+    ///     .unwrap();
+    /// builder.ret(Some(result.into()));
+    /// builder.switch_to(unknown);
+    /// builder.ret(Some(0.into()));
+    ///
+    /// let mut module = Module::new();
+    /// module.add_function(builder.build());
+    /// module.add_data(table.data());
+    /// assert!(module.to_string().contains("data $handlers = align 8 { l $on_open, l $on_read, l $on_close }"));
+    /// ```
+    pub fn switch_call(
+        &mut self,
+        value: &TypedValue,
+        table: &CallTable,
+        args: Vec<(Type, Value)>,
+        return_ty: Option<Type>,
+        default: BlockRef,
+    ) -> Option<TypedValue> {
+        expect_one_of("switch", value, INTEGER);
+        let index = self.sub(value, &TypedValue::new(value.ty.clone(), table.first));
+        let in_table = self.cmp(
+            Cmp::Ult,
+            &index,
+            &TypedValue::new(value.ty.clone(), table.targets.len() as u64),
+        );
+        let call = self.new_block("switch_call");
+        self.jnz(&in_table, call, default);
+        self.seal_block(call);
+
+        self.switch_to(call);
+        let index = self.convert(&index, Type::Long, Signedness::Unsigned);
+        let offset = self.mul(&index, &TypedValue::new(Type::Long, 8));
+        let base = TypedValue::new(Type::Long, Value::Global(table.name.clone()));
+        let entry = self.add(&base, &offset);
+        let callee = self.load(Type::Long, &entry);
+        let instr = Instr::Call(callee.value, args, None);
+        match return_ty {
+            Some(ty) => Some(self.assign(ty, instr)),
+            None => {
+                self.add_instr(instr);
+                None
+            }
+        }
+    }
+
+    /// Jumps to the range holding `value`, which is known to be between `min`
+    /// and `max`, or to `default`
+    fn search(
+        &mut self,
+        value: &TypedValue,
+        ranges: &[CaseRange],
+        min: i64,
+        max: i64,
+        default: BlockRef,
+    ) {
+        if ranges.len() <= 3 {
+            for (i, range) in ranges.iter().enumerate() {
+                let next = if i + 1 == ranges.len() {
+                    default
+                } else {
+                    self.new_block("switch")
+                };
+                self.check_range(value, range, min, max, next);
+                if next != default {
+                    self.seal_block(next);
+                    self.switch_to(next);
+                }
+            }
+            if ranges.is_empty() {
+                self.jmp(default);
+            }
+            return;
+        }
+
+        let mid = ranges.len() / 2;
+        let pivot = ranges[mid].low;
+        let below = self.cmp(Cmp::Slt, value, &TypedValue::new(value.ty.clone(), pivot));
+        let left = self.new_block("switch");
+        let right = self.new_block("switch");
+        self.jnz(&below, left, right);
+        self.seal_block(left);
+        self.seal_block(right);
+
+        self.switch_to(left);
+        self.search(value, &ranges[..mid], min, pivot - 1, default);
+        self.switch_to(right);
+        self.search(value, &ranges[mid..], pivot, max, default);
+    }
+
+    /// Jumps to the target of `range` if it holds `value`, which is known to
+    /// be between `min` and `max`, and to `otherwise` if not
+    fn check_range(
+        &mut self,
+        value: &TypedValue,
+        range: &CaseRange,
+        min: i64,
+        max: i64,
+        otherwise: BlockRef,
+    ) {
+        let constant = |n: i64| TypedValue::new(value.ty.clone(), n);
+        let inside = match (range.low <= min, range.high >= max) {
+            (true, true) => return self.jmp(range.target),
+            _ if range.low == range.high => self.cmp(Cmp::Eq, value, &constant(range.low)),
+            (true, false) => self.cmp(Cmp::Sle, value, &constant(range.high)),
+            (false, true) => self.cmp(Cmp::Sge, value, &constant(range.low)),
+            (false, false) => {
+                let offset = self.sub(value, &constant(range.low));
+                self.cmp(Cmp::Ule, &offset, &constant(range.high - range.low))
+            }
+        };
+        self.jnz(&inside, range.target, otherwise);
+    }
+}
+
+/// Returns the zero of a base type
+fn zero(ty: &Type) -> Value {
+    match ty {
+        Type::Single => Value::SingleConst(0.0),
+        Type::Double => Value::DoubleConst(0.0),
+        _ => Value::Const(0),
+    }
+}
+
+const NUMBER: &[Type] = &[Type::Word, Type::Long, Type::Single, Type::Double];
+
+fn expect_one_of(name: &str, value: &TypedValue, types: &[Type]) {
+    assert!(
+        types.contains(&value.ty),
+        "`{name}` cannot take `{}`: expected one of {}",
+        value.ty,
+        types
+            .iter()
+            .map(|ty| format!("`{ty}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+}
+
+fn expect_same(name: &str, lhs: &TypedValue, rhs: &TypedValue) {
+    assert!(
+        lhs.ty == rhs.ty,
+        "`{name}` operands are `{}` and `{}`: unify or convert them first",
+        lhs.ty,
+        rhs.ty
+    );
+}
+
+fn expect_address(name: &str, addr: &TypedValue) {
+    assert!(
+        addr.ty == Type::Long,
+        "`{name}` address is `{}`, expected `l`",
+        addr.ty
+    );
+}
@@ -0,0 +1,968 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::tests::temp;
+use crate::*;
+use std::collections::HashMap;
+
+#[test]
+fn names_are_unique() {
+    let mut builder = FunctionBuilder::new(Linkage::private(), "f", None);
+    let x = builder.param(Type::Word, "x");
+    assert_eq!(x.value, temp("x"));
+    assert_eq!(builder.temp("x"), temp("x.1"));
+    // A hand-picked name that looks generated is handed out only once
+    assert_eq!(builder.temp("x.2"), temp("x.2"));
+    assert_eq!(builder.temp("x"), temp("x.3"));
+    assert_eq!(builder.temp("x.2"), temp("x.2.1"));
+
+    let first = builder.assign(Type::Word, Instr::Copy(Value::Const(1)));
+    let second = builder.assign(Type::Word, Instr::Copy(Value::Const(2)));
+    assert_eq!(first.value, temp(".1"));
+    assert_eq!(second.value, temp(".2"));
+
+    let blocks = [
+        builder.new_block("loop"),
+        builder.new_block("loop"),
+        builder.new_block("start"),
+    ];
+    let labels: Vec<&str> = blocks.iter().map(|&block| builder.label(block)).collect();
+    assert_eq!(labels, ["loop", "loop.1", "start.1"]);
+
+    // Temporaries and labels are separate namespaces
+    assert_eq!(builder.temp("loop"), temp("loop"));
+}
+
+#[test]
+fn instructions_go_to_the_current_block() {
+    let mut builder = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Long));
+    let entry = builder.current_block();
+    let exit = builder.new_block("exit");
+    let body = builder.new_block("body");
+    assert_eq!(builder.current_block(), entry);
+
+    builder.jmp(body);
+    assert!(builder.is_terminated());
+    builder.switch_to(body);
+    assert!(!builder.is_terminated());
+    let sum = builder.assign_named(
+        "sum",
+        Type::Long,
+        Instr::Add(Value::Const(1), Value::Const(2)),
+    );
+    builder.add_comment("done");
+    builder.jmp(exit);
+    builder.add_comment("comments may follow a jump");
+    assert!(builder.is_block_terminated(body));
+    assert!(!builder.is_block_terminated(exit));
+
+    builder.switch_to(exit);
+    builder.ret(Some(sum.value));
+
+    let func = builder.build();
+    assert_eq!(
+        func.to_string(),
+        "function l $f() {\n\
+         @start\n\
+         \tjmp @body\n\
+         @exit\n\
+         \tret %sum\n\
+         @body\n\
+         \t%sum =l add 1, 2\n\
+         \t# done\n\
+         \tjmp @exit\n\
+         \t# comments may follow a jump\n\
+         }"
+    );
+    assert_eq!(func.verify_ssa(), Ok(()));
+}
+
+#[test]
+fn assigned_types_are_returned() {
+    let mut builder = FunctionBuilder::new(Linkage::private(), "f", None);
+    let pair = std::sync::Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    });
+    let arg = builder.param(Type::Aggregate(pair), "p");
+    assert_eq!(arg.ty, Type::Long);
+    let byte = builder.param(Type::SignedByte, "b");
+    assert_eq!(byte.ty, Type::Word);
+
+    let call = builder.assign(
+        Type::UnsignedHalfword,
+        Instr::Call(Value::Global("g".into()), vec![], None),
+    );
+    assert_eq!(call.ty, Type::Word);
+    let loaded = builder.assign(Type::Single, Instr::Load(Type::Single, arg.value));
+    assert_eq!(loaded, TypedValue::new(Type::Single, temp(".2")));
+}
+
+#[test]
+#[should_panic(expected = "block @start is already terminated")]
+fn terminated_blocks_are_closed() {
+    let mut builder = FunctionBuilder::new(Linkage::private(), "f", None);
+    builder.ret(None);
+    builder.add_instr(Instr::Hlt);
+}
+
+/// Wraps a function built by `build` in a module and typechecks it
+fn typechecked(build: impl FnOnce(&mut FunctionBuilder)) -> Function {
+    let mut builder = FunctionBuilder::new(Linkage::private(), "f", None);
+    build(&mut builder);
+    let func = builder.build();
+    let mut module = Module::new();
+    module.add_function(func.clone());
+    assert_eq!(module.typecheck(), Ok(()));
+    func
+}
+
+#[test]
+fn result_types_are_inferred() {
+    typechecked(|b| {
+        let w = b.param(Type::Word, "w");
+        let l = b.param(Type::Long, "l");
+        let d = b.param(Type::Double, "d");
+
+        assert_eq!(b.add(&w, &w).ty, Type::Word);
+        assert_eq!(b.rem(&l, &l).ty, Type::Long);
+        assert_eq!(b.mul(&d, &d).ty, Type::Double);
+        assert_eq!(b.shl(&l, &w).ty, Type::Long);
+        assert_eq!(b.neg(&d).ty, Type::Double);
+        assert_eq!(b.cmp(Cmp::Lt, &d, &d).ty, Type::Word);
+        assert_eq!(b.cmp(Cmp::Ult, &l, &l).ty, Type::Word);
+        assert_eq!(b.cast(&d).ty, Type::Long);
+
+        let byte = b.load(Type::SignedByte, &l);
+        assert_eq!(byte.ty, Type::Word);
+        b.store(Type::Byte, &l, &byte);
+        b.store(Type::Word, &l, &l);
+        b.ret(None);
+    });
+}
+
+#[test]
+fn conversions_pick_the_instruction() {
+    let func = typechecked(|b| {
+        let w = b.param(Type::Word, "w");
+        let l = b.param(Type::Long, "l");
+        let s = b.param(Type::Single, "s");
+        let d = b.param(Type::Double, "d");
+
+        assert_eq!(b.convert(&w, Type::Word, Signedness::Signed), w);
+        b.convert(&w, Type::Long, Signedness::Signed);
+        b.convert(&w, Type::Long, Signedness::Unsigned);
+        b.convert(&l, Type::Word, Signedness::Signed);
+        b.convert(&s, Type::Double, Signedness::Unsigned);
+        b.convert(&d, Type::Single, Signedness::Signed);
+        b.convert(&w, Type::Double, Signedness::Unsigned);
+        b.convert(&l, Type::Single, Signedness::Signed);
+        b.convert(&s, Type::Long, Signedness::Unsigned);
+        b.convert(&d, Type::Word, Signedness::Signed);
+
+        let (x, y) = b.unify(&s, &d, Signedness::Signed);
+        assert_eq!((x.ty, y), (Type::Double, d.clone()));
+        b.ret(None);
+    });
+
+    let instrs: Vec<String> = func.blocks[0]
+        .items
+        .iter()
+        .map(|item| item.to_string())
+        .collect();
+    assert_eq!(
+        instrs,
+        [
+            "%.1 =l extsw %w",
+            "%.2 =l extuw %w",
+            "%.3 =w copy %l",
+            "%.4 =d exts %s",
+            "%.5 =s truncd %d",
+            "%.6 =d uwtof %w",
+            "%.7 =s sltof %l",
+            "%.8 =l stoui %s",
+            "%.9 =w dtosi %d",
+            "%.10 =d exts %s",
+            "ret",
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "`add` operands are `w` and `l`: unify or convert them first")]
+fn mixed_widths_are_refused() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let w = b.param(Type::Word, "w");
+    let l = b.param(Type::Long, "l");
+    b.add(&w, &l);
+}
+
+#[test]
+#[should_panic(expected = "`rem` cannot take `d`: expected one of `w`, `l`")]
+fn integer_instructions_refuse_floats() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let d = b.param(Type::Double, "d");
+    b.rem(&d, &d);
+}
+
+#[test]
+#[should_panic(expected = "unsigned comparisons need an integer type, got `s`")]
+fn invalid_comparisons_are_refused() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let s = b.param(Type::Single, "s");
+    b.cmp(Cmp::Uge, &s, &s);
+}
+
+#[test]
+#[should_panic(expected = "signed comparisons need an integer type, got `d`")]
+fn signed_comparisons_of_floats_are_refused() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let d = b.param(Type::Double, "d");
+    b.cmp(Cmp::Slt, &d, &d);
+}
+
+#[test]
+#[should_panic(expected = "cannot unify `w` and `s`: convert one operand explicitly")]
+fn integers_and_floats_are_not_unified() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let w = b.param(Type::Word, "w");
+    let s = b.param(Type::Single, "s");
+    b.unify(&w, &s, Signedness::Signed);
+}
+
+#[test]
+#[should_panic(expected = "`load` address is `w`, expected `l`")]
+fn addresses_are_longs() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let w = b.param(Type::Word, "w");
+    b.load(Type::Word, &w);
+}
+
+/// Runs `func` on `args` and returns what it returns. Values are 64-bit
+/// integers, truncated to 32 bits when assigned as words, and memory maps
+/// the address of each stored value to the value.
+fn run(func: &Function, args: &[i64]) -> Option<i64> {
+    run_with(func, &[], &|name, _| panic!("call to ${name}"), args)
+}
+
+/// Like [`run`], with `data` laid out in memory and calls to functions
+/// handled by `call`. Each symbol, named by data or by the function, gets
+/// an address of its own.
+fn run_with(
+    func: &Function,
+    data: &[DataDef],
+    call: &dyn Fn(&str, &[i64]) -> i64,
+    args: &[i64],
+) -> Option<i64> {
+    let mut symbols: Vec<&str> = data.iter().map(|def| def.name.as_str()).collect();
+    for def in data {
+        for (_, item) in &def.items {
+            if let DataItem::Symbol(name, None) = item {
+                symbols.push(name);
+            }
+        }
+    }
+    let address = |name: &str| {
+        let index = symbols.iter().position(|symbol| *symbol == name);
+        (1 << 40) + (index.expect(name) as i64) * (1 << 20)
+    };
+
+    let mut memory: HashMap<i64, i64> = HashMap::new();
+    for def in data {
+        for (i, (_, item)) in def.items.iter().enumerate() {
+            let DataItem::Symbol(name, None) = item else {
+                unimplemented!("{item}")
+            };
+            memory.insert(address(&def.name) + 8 * i as i64, address(name));
+        }
+    }
+
+    let mut temps: HashMap<&str, i64> = HashMap::new();
+    for ((_, param), arg) in func.arguments.iter().zip(args) {
+        let Value::Temporary(name) = param else {
+            unreachable!()
+        };
+        temps.insert(name, *arg);
+    }
+    let labels: HashMap<&str, usize> = func
+        .blocks
+        .iter()
+        .enumerate()
+        .map(|(i, block)| (block.label.as_str(), i))
+        .collect();
+    let mut next_alloc = 8;
+
+    let mut block = 0;
+    let mut prev: Option<&str> = None;
+    for _ in 0..100_000 {
+        let mut next = block + 1;
+        let mut phis = Vec::new();
+        for item in &func.blocks[block].items {
+            let (dest, ty, instr) = match item {
+                BlockItem::Statement(Statement::Assign(Value::Temporary(dest), ty, instr)) => {
+                    (Some(dest.as_str()), ty, instr)
+                }
+                BlockItem::Statement(Statement::Volatile(instr)) => (None, &Type::Long, instr),
+                _ => continue,
+            };
+            if !matches!(instr, Instr::Phi(_)) {
+                // Phis read the values from before the block, so they are
+                // assigned together
+                temps.extend(phis.drain(..));
+            }
+            let val = |v: &Value| match v {
+                Value::Temporary(name) => *temps.get(name.as_str()).expect(name),
+                Value::Const(c) => *c as i64,
+                Value::SignedConst(c) => *c,
+                Value::Global(name) => address(name),
+                _ => unimplemented!("{v}"),
+            };
+            let result = match instr {
+                Instr::Phi(args) => {
+                    let (_, value) = args
+                        .iter()
+                        .find(|(label, _)| Some(label.as_str()) == prev)
+                        .expect("phi has a value for the predecessor");
+                    phis.push((dest.unwrap(), val(value)));
+                    continue;
+                }
+                Instr::Jmp(label) => {
+                    next = labels[label.as_str()];
+                    break;
+                }
+                Instr::Jnz(cond, if_nonzero, if_zero) => {
+                    let cond = val(cond) as i32 != 0;
+                    next = labels[if cond { if_nonzero } else { if_zero }.as_str()];
+                    break;
+                }
+                Instr::Ret(value) => return value.as_ref().map(val),
+                Instr::Hlt => panic!("hlt"),
+                Instr::Copy(a) => val(a),
+                Instr::Neg(a) => val(a).wrapping_neg(),
+                Instr::Add(a, b) => val(a).wrapping_add(val(b)),
+                Instr::Sub(a, b) => val(a).wrapping_sub(val(b)),
+                Instr::Mul(a, b) => val(a).wrapping_mul(val(b)),
+                Instr::Div(a, b) => val(a) / val(b),
+                Instr::Rem(a, b) => val(a) % val(b),
+                Instr::And(a, b) => val(a) & val(b),
+                Instr::Or(a, b) => val(a) | val(b),
+                Instr::Xor(a, b) => val(a) ^ val(b),
+                Instr::Shl(a, b) => val(a) << val(b),
+                Instr::Shr(a, b) => ((val(a) as u64) >> val(b)) as i64,
+                Instr::Sar(a, b) => val(a) >> val(b),
+                Instr::Extsw(a) => val(a) as i32 as i64,
+                Instr::Extuw(a) => val(a) as u32 as i64,
+                Instr::Call(callee, args, _) => {
+                    let callee = val(callee);
+                    let name = symbols
+                        .iter()
+                        .find(|name| address(name) == callee)
+                        .expect("callee is a symbol");
+                    let args: Vec<i64> = args.iter().map(|(_, arg)| val(arg)).collect();
+                    call(name, &args)
+                }
+                Instr::Cmp(ty, cmp, a, b) => {
+                    let (a, b) = match ty {
+                        Type::Word => (val(a) as i32 as i64, val(b) as i32 as i64),
+                        _ => (val(a), val(b)),
+                    };
+                    let (ua, ub) = match ty {
+                        Type::Word => (a as u32 as u64, b as u32 as u64),
+                        _ => (a as u64, b as u64),
+                    };
+                    i64::from(match cmp {
+                        Cmp::Slt => a < b,
+                        Cmp::Sle => a <= b,
+                        Cmp::Sgt => a > b,
+                        Cmp::Sge => a >= b,
+                        Cmp::Eq => a == b,
+                        Cmp::Ne => a != b,
+                        Cmp::Ult => ua < ub,
+                        Cmp::Ule => ua <= ub,
+                        Cmp::Ugt => ua > ub,
+                        Cmp::Uge => ua >= ub,
+                        Cmp::O | Cmp::Uo | Cmp::Lt | Cmp::Le | Cmp::Gt | Cmp::Ge => {
+                            unimplemented!()
+                        }
+                    })
+                }
+                Instr::Alloc4(size) | Instr::Alloc8(size) | Instr::Alloc16(size) => {
+                    let addr = next_alloc;
+                    next_alloc += val(size).max(8);
+                    addr
+                }
+                Instr::Load(_, addr) => memory.get(&val(addr)).copied().unwrap_or_default(),
+                Instr::Store(_, addr, value) => {
+                    memory.insert(val(addr), val(value));
+                    continue;
+                }
+                _ => unimplemented!("{instr}"),
+            };
+            let result = match ty {
+                Type::Word => result as i32 as i64,
+                _ => result,
+            };
+            if let Some(dest) = dest {
+                temps.insert(dest, result);
+            }
+        }
+        prev = Some(&func.blocks[block].label);
+        block = next;
+    }
+    panic!("function doesn't return");
+}
+
+fn word(value: i64) -> TypedValue {
+    TypedValue::new(Type::Word, value)
+}
+
+#[test]
+fn if_else_merges_values() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "abs", Some(Type::Word));
+    let x = b.param(Type::Word, "x");
+    let negative = b.cmp(Cmp::Slt, &x, &word(0));
+    let abs = b.if_else_value(&negative, |b| b.neg(&x), |_| x.clone());
+    b.ret(Some(abs.value));
+    let func = b.build();
+
+    assert_eq!(
+        func.to_string(),
+        "function w $abs(w %x) {\n\
+         @start\n\
+         \t%.1 =w csltw %x, 0\n\
+         \tjnz %.1, @then, @else\n\
+         @then\n\
+         \t%.2 =w neg %x\n\
+         \tjmp @endif\n\
+         @else\n\
+         \tjmp @endif\n\
+         @endif\n\
+         \t%.3 =w phi @then %.2, @else %x\n\
+         \tret %.3\n\
+         }"
+    );
+    assert_eq!(func.verify_ssa(), Ok(()));
+    for x in [-7, 0, 12] {
+        assert_eq!(run(&func, &[x]), Some(x.abs()));
+    }
+}
+
+#[test]
+fn branches_that_return_do_not_merge() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Word));
+    let x = b.param(Type::Word, "x");
+    let value = b.if_else_value(
+        &x,
+        |b| {
+            b.ret(Some(Value::Const(100)));
+            word(0)
+        },
+        |b| {
+            // Nested constructs end in a block of their own
+            b.if_then(&x, |b| b.hlt());
+            b.add(&x, &word(1))
+        },
+    );
+    b.ret(Some(value.value));
+    let func = b.build();
+
+    let endif = func.blocks.last().unwrap();
+    assert_eq!(endif.label, "endif.1");
+    assert_eq!(endif.items[0].to_string(), "%.2 =w phi @endif %.1");
+    assert_eq!(func.verify_ssa(), Ok(()));
+    assert_eq!(run(&func, &[1]), Some(100));
+    assert_eq!(run(&func, &[0]), Some(1));
+}
+
+#[test]
+#[should_panic(expected = "neither branch of `if_else_value` falls through")]
+fn if_else_value_needs_a_value() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let x = b.param(Type::Word, "x");
+    b.if_else_value(
+        &x,
+        |b| {
+            b.hlt();
+            word(0)
+        },
+        |b| {
+            b.hlt();
+            word(1)
+        },
+    );
+}
+
+/// Builds `$f(n, limit)`, which sums the odd numbers below `n` but stops at
+/// the first one above `limit`
+fn odd_sum() -> Function {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Word));
+    let n = b.param(Type::Word, "n");
+    let limit = b.param(Type::Word, "limit");
+    let i = b.assign_named("i", Type::Long, Instr::Alloc4(4.into()));
+    let sum = b.assign_named("sum", Type::Long, Instr::Alloc4(4.into()));
+    b.store(Type::Word, &i, &word(0));
+    b.store(Type::Word, &sum, &word(0));
+
+    b.for_(
+        |b| {
+            let value = b.load(Type::Word, &i);
+            b.cmp(Cmp::Slt, &value, &n)
+        },
+        |b| {
+            let value = b.load(Type::Word, &i);
+            let value = b.add(&value, &word(1));
+            b.store(Type::Word, &i, &value);
+        },
+        |b, lp| {
+            let value = b.load(Type::Word, &i);
+            let odd = b.and(&value, &word(1));
+            let even = b.cmp(Cmp::Eq, &odd, &word(0));
+            b.if_then(&even, |b| b.continue_(lp));
+            let above = b.cmp(Cmp::Sgt, &value, &limit);
+            b.if_then(&above, |b| b.break_(lp));
+            let total = b.load(Type::Word, &sum);
+            let total = b.add(&total, &value);
+            b.store(Type::Word, &sum, &total);
+        },
+    );
+    let total = b.load(Type::Word, &sum);
+    b.ret(Some(total.value));
+    b.build()
+}
+
+#[test]
+fn loops_break_and_continue() {
+    let func = odd_sum();
+    assert_eq!(func.verify_ssa(), Ok(()));
+    for n in 0..12 {
+        for limit in [0, 3, 100] {
+            let expected: i64 = (0..n)
+                .filter(|i| i % 2 == 1)
+                .take_while(|&i| i <= limit)
+                .sum();
+            assert_eq!(
+                run(&func, &[n, limit]),
+                Some(expected),
+                "n = {n}, limit = {limit}"
+            );
+        }
+    }
+
+    let labels: Vec<&str> = func
+        .blocks
+        .iter()
+        .map(|block| block.label.as_str())
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "start",
+            "loop",
+            "loop_body",
+            "loop_step",
+            "loop_end",
+            "then",
+            "endif",
+            "then.1",
+            "endif.1"
+        ]
+    );
+}
+
+#[test]
+fn loop_runs_until_break() {
+    // Counts the steps from `n` down to 0
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Word));
+    let n = b.param(Type::Word, "n");
+    let slot = b.assign(Type::Long, Instr::Alloc4(4.into()));
+    b.store(Type::Word, &slot, &n);
+    let steps = b.assign(Type::Long, Instr::Alloc4(4.into()));
+    b.store(Type::Word, &steps, &word(0));
+    b.loop_(|b, lp| {
+        let value = b.load(Type::Word, &slot);
+        let done = b.cmp(Cmp::Sle, &value, &word(0));
+        b.if_else(&done, |b| b.break_(lp), |_| {});
+        let value = b.sub(&value, &word(1));
+        b.store(Type::Word, &slot, &value);
+        let count = b.load(Type::Word, &steps);
+        let count = b.add(&count, &word(1));
+        b.store(Type::Word, &steps, &count);
+    });
+    let count = b.load(Type::Word, &steps);
+    b.ret(Some(count.value));
+    let func = b.build();
+
+    assert_eq!(func.verify_ssa(), Ok(()));
+    assert_eq!(run(&func, &[5]), Some(5));
+    assert_eq!(run(&func, &[-1]), Some(0));
+}
+
+fn phis(func: &Function) -> Vec<String> {
+    func.blocks
+        .iter()
+        .flat_map(|block| &block.items)
+        .filter(|item| {
+            matches!(
+                item,
+                BlockItem::Statement(Statement::Assign(_, _, Instr::Phi(_)))
+            )
+        })
+        .map(|item| item.to_string())
+        .collect()
+}
+
+#[test]
+fn variables_get_minimal_phis() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Word));
+    let n = b.param(Type::Word, "n");
+    let x = b.declare_var(Type::Word, "x");
+    let unchanged = b.declare_var(Type::Word, "unchanged");
+    let one_sided = b.declare_var(Type::Word, "one_sided");
+    b.def_var(x, &word(0));
+    b.def_var(unchanged, &n);
+    b.def_var(one_sided, &word(5));
+
+    let positive = b.cmp(Cmp::Sgt, &n, &word(0));
+    b.if_else(
+        &positive,
+        |b| {
+            b.def_var(x, &word(1));
+            b.def_var(one_sided, &word(6));
+        },
+        |b| b.def_var(x, &word(2)),
+    );
+    let (x, unchanged, one_sided) = (b.use_var(x), b.use_var(unchanged), b.use_var(one_sided));
+    assert_eq!(unchanged, n);
+    let sum = b.add(&x, &unchanged);
+    let sum = b.add(&sum, &one_sided);
+    b.ret(Some(sum.value));
+    let func = b.build();
+
+    assert_eq!(
+        phis(&func),
+        [
+            "%x =w phi @then 1, @else 2",
+            "%one_sided =w phi @then 6, @else 5"
+        ]
+    );
+    assert_eq!(func.verify_ssa(), Ok(()));
+    assert_eq!(run(&func, &[3]), Some(1 + 3 + 6));
+    assert_eq!(run(&func, &[-3]), Some(2 - 3 + 5));
+}
+
+#[test]
+fn loops_only_merge_changed_variables() {
+    // Sums 1..=n
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Long));
+    let n = b.param(Type::Long, "n");
+    let one = TypedValue::new(Type::Long, 1);
+    let i = b.declare_var(Type::Long, "i");
+    let sum = b.declare_var(Type::Long, "sum");
+    let limit = b.declare_var(Type::Long, "limit");
+    b.def_var(i, &one);
+    b.def_var(limit, &n);
+
+    b.while_(
+        |b| {
+            let (i, limit) = (b.use_var(i), b.use_var(limit));
+            b.cmp(Cmp::Sle, &i, &limit)
+        },
+        |b, _| {
+            let (value, total) = (b.use_var(i), b.use_var(sum));
+            let total = b.add(&total, &value);
+            b.def_var(sum, &total);
+            let value = b.add(&value, &one);
+            b.def_var(i, &value);
+        },
+    );
+    let total = b.use_var(sum);
+    b.ret(Some(total.value));
+    let func = b.build();
+
+    // `limit` never changes in the loop and `sum` starts out undefined
+    assert_eq!(
+        phis(&func),
+        [
+            "%i =l phi @start 1, @loop_step %.3",
+            "%sum =l phi @start 0, @loop_step %.2"
+        ]
+    );
+    assert_eq!(func.verify_ssa(), Ok(()));
+    for n in [0, 1, 10] {
+        assert_eq!(run(&func, &[n]), Some(n * (n + 1) / 2));
+    }
+}
+
+#[test]
+fn blocks_are_sealed_by_hand() {
+    // Counts down from `n` with gotos, keeping a stale handle to a removed
+    // phi
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Word));
+    let n = b.param(Type::Word, "n");
+    let count = b.declare_var(Type::Word, "count");
+    let base = b.declare_var(Type::Word, "base");
+    b.def_var(count, &n);
+    b.def_var(base, &word(100));
+
+    let head = b.new_block("head");
+    let body = b.new_block("body");
+    let exit = b.new_block("exit");
+    b.jmp(head);
+
+    b.switch_to(head);
+    let stale = b.use_var(base);
+    let value = b.use_var(count);
+    b.jnz(&value, body, exit);
+
+    b.switch_to(body);
+    let value = b.use_var(count);
+    let value = b.sub(&value, &word(1));
+    b.def_var(count, &value);
+    b.jmp(head);
+
+    for block in [head, body, exit] {
+        b.seal_block(block);
+    }
+    b.switch_to(exit);
+    let result = b.add(&stale, &word(1));
+    b.ret(Some(result.value));
+    let func = b.build();
+
+    assert_eq!(phis(&func), ["%count =w phi @start %n, @body %.1"]);
+    assert_eq!(
+        func.blocks.last().unwrap().items[0].to_string(),
+        "%.2 =w add 100, 1"
+    );
+    assert_eq!(func.verify_ssa(), Ok(()));
+    assert_eq!(run(&func, &[4]), Some(101));
+}
+
+#[test]
+#[should_panic(expected = "cannot jump to @start: the block is sealed")]
+fn sealed_blocks_get_no_new_predecessors() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let start = b.current_block();
+    b.jmp(start);
+}
+
+#[test]
+#[should_panic(expected = "cannot set a `w` variable to `d`: convert the value first")]
+fn variables_keep_their_type() {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", None);
+    let d = b.param(Type::Double, "d");
+    let x = b.declare_var(Type::Word, "x");
+    b.def_var(x, &d);
+}
+
+/// Builds a function switching on its `ty` parameter over `cases`, where
+/// each case names the arm it jumps to. Arm `n` returns `n` and the default
+/// returns -1.
+fn switch_over(ty: Type, cases: &[(i64, usize)]) -> Function {
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(ty.clone()));
+    let x = b.param(ty.clone(), "x");
+    let arms = cases
+        .iter()
+        .map(|&(_, arm)| arm)
+        .max()
+        .map_or(0, |max| max + 1);
+    let blocks: Vec<BlockRef> = (0..arms).map(|_| b.new_block("arm")).collect();
+    let default = b.new_block("default");
+    let cases: Vec<(i64, BlockRef)> = cases
+        .iter()
+        .map(|&(case, arm)| (case, blocks[arm]))
+        .collect();
+    b.switch(&x, &cases, default);
+
+    for (arm, block) in blocks.into_iter().enumerate() {
+        b.switch_to(block);
+        b.ret(Some(Value::from(arm as i64)));
+    }
+    b.switch_to(default);
+    b.ret(Some(Value::from(-1)));
+    let func = b.build();
+    assert_eq!(func.verify_ssa(), Ok(()));
+    func
+}
+
+/// Checks that `func` built by [`switch_over`] picks the right arm for
+/// every case value, the values around them and `extra` values
+fn check_switch(func: &Function, cases: &[(i64, usize)], extra: &[i64]) {
+    let mut values: Vec<i64> = extra.to_vec();
+    for &(case, _) in cases {
+        values.extend([case.saturating_sub(1), case, case.saturating_add(1)]);
+    }
+    for value in values {
+        let expected = cases
+            .iter()
+            .find(|&&(case, _)| case == value)
+            .map_or(-1, |&(_, arm)| arm as i64);
+        assert_eq!(run(func, &[value]), Some(expected), "value {value}");
+    }
+}
+
+#[test]
+fn switch_picks_the_case_arm() {
+    // Sparse cases, searched in a tree
+    let sparse: Vec<(i64, usize)> = [-100, -7, 0, 3, 50, 1000, 1 << 20, 77, 78, 12]
+        .into_iter()
+        .enumerate()
+        .map(|(arm, case)| (case, arm))
+        .collect();
+    let func = switch_over(Type::Word, &sparse);
+    check_switch(&func, &sparse, &[i64::from(i32::MIN), i64::from(i32::MAX)]);
+
+    // Runs of cases with the same arm, checked as ranges
+    let mut dense: Vec<(i64, usize)> = Vec::new();
+    dense.extend((1..=10).map(|case| (case, 0)));
+    dense.extend((11..=20).map(|case| (case, 1)));
+    dense.push((21, 2));
+    dense.extend((30..=35).map(|case| (case, 0)));
+    dense.extend((-5..=-2).map(|case| (case, 3)));
+    let func = switch_over(Type::Word, &dense);
+    check_switch(&func, &dense, &(-10..50).collect::<Vec<_>>());
+
+    // Cases at the ends of the range of longs
+    let extremes = [
+        (i64::MIN, 0),
+        (i64::MIN + 1, 0),
+        (-1, 1),
+        (i64::MAX - 1, 2),
+        (i64::MAX, 2),
+    ];
+    let func = switch_over(Type::Long, &extremes);
+    check_switch(&func, &extremes, &[0, 1 << 40]);
+
+    // No cases at all
+    let func = switch_over(Type::Word, &[]);
+    check_switch(&func, &[], &[0, 1]);
+}
+
+#[test]
+fn switch_checks_ranges_at_once() {
+    let cases: Vec<(i64, usize)> = (10..20).map(|case| (case, 0)).chain([(30, 1)]).collect();
+    let func = switch_over(Type::Word, &cases);
+    assert_eq!(
+        func.blocks[0]
+            .items
+            .iter()
+            .map(|item| item.to_string())
+            .collect::<Vec<_>>(),
+        [
+            "%.1 =w sub %x, 10",
+            "%.2 =w culew %.1, 9",
+            "jnz %.2, @arm, @switch",
+        ]
+    );
+    check_switch(&func, &cases, &[]);
+}
+
+#[test]
+fn switch_searches_a_balanced_tree() {
+    // 64 sparse cases need at most 6 levels of search, ending in a chain of
+    // at most 3 checks
+    let cases: Vec<(i64, usize)> = (0..64).map(|arm| (arm as i64 * 10, arm)).collect();
+    let func = switch_over(Type::Long, &cases);
+    check_switch(&func, &cases, &[]);
+
+    let comparisons = func
+        .blocks
+        .iter()
+        .flat_map(|block| &block.items)
+        .filter(|item| item.to_string().contains(" =w c"))
+        .count();
+    assert!(comparisons < 2 * cases.len(), "{comparisons} comparisons");
+
+    // Follow the path to each case, counting the checks on the way
+    let labels: HashMap<&str, &Block> = func
+        .blocks
+        .iter()
+        .map(|block| (block.label.as_str(), block))
+        .collect();
+    let depth = depth_of(&labels, "start");
+    assert!(depth <= 6 + 3, "depth {depth}");
+}
+
+/// Number of checks on the longest path through the `switch` blocks
+fn depth_of(labels: &HashMap<&str, &Block>, label: &str) -> usize {
+    match labels[label].items.last() {
+        Some(BlockItem::Statement(Statement::Volatile(Instr::Jnz(_, a, b)))) => {
+            1 + [a, b]
+                .iter()
+                .filter(|label| label.starts_with("switch"))
+                .map(|label| depth_of(labels, label))
+                .max()
+                .unwrap_or(0)
+        }
+        _ => 0,
+    }
+}
+
+#[test]
+#[should_panic(expected = "case 3 appears more than once in `switch`")]
+fn switch_cases_are_unique() {
+    switch_over(Type::Word, &[(3, 0), (4, 1), (3, 1)]);
+}
+
+#[test]
+#[should_panic(expected = "case 4294967296 doesn't fit in the `w` value of `switch`")]
+fn switch_cases_fit_the_value() {
+    switch_over(Type::Word, &[(1 << 32, 0)]);
+}
+
+#[test]
+fn switch_call_dispatches_through_a_table() {
+    let table = CallTable::new(
+        "handlers",
+        -1,
+        vec!["minus_one".into(), "zero".into(), "one".into()],
+    );
+    let mut b = FunctionBuilder::new(Linkage::private(), "f", Some(Type::Long));
+    let x = b.param(Type::Word, "x");
+    let extra = b.param(Type::Long, "extra");
+    let unknown = b.new_block("unknown");
+    let result = b
+        .switch_call(
+            &x,
+            &table,
+            vec![(Type::Long, extra.value)],
+            Some(Type::Long),
+            unknown,
+        )
+        .unwrap();
+    b.ret(Some(result.value));
+    b.switch_to(unknown);
+    b.ret(Some(Value::from(-100)));
+    let func = b.build();
+    assert_eq!(func.verify_ssa(), Ok(()));
+
+    let call = |name: &str, args: &[i64]| {
+        let base = match name {
+            "minus_one" => 10,
+            "zero" => 20,
+            "one" => 30,
+            _ => unreachable!(),
+        };
+        base + args[0]
+    };
+    for (x, expected) in [
+        (-2, -100),
+        (-1, 15),
+        (0, 25),
+        (1, 35),
+        (2, -100),
+        (1 << 31, -100),
+    ] {
+        assert_eq!(
+            run_with(&func, &[table.data()], &call, &[x, 5]),
+            Some(expected),
+            "x = {x}"
+        );
+    }
+}
@@ -0,0 +1,263 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Checking calls against the signatures of their callees.
+
+use crate::{BlockItem, FunctionDecl, Instr, Location, Module, Statement, Type, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// A call that doesn't match its callee, found by [`Module::check_calls`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct CallError {
+    /// Where the problem was found
+    pub location: Location,
+    /// What is wrong
+    pub kind: CallErrorKind,
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// Kind of a [`CallError`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum CallErrorKind {
+    /// A direct call to a function that is neither defined in the module
+    /// nor declared with [`Module::add_extern`]
+    UnknownFunction(String),
+    /// A function declared more than once, or both declared and defined
+    DuplicateDeclaration(String),
+    /// A call passing a different number of fixed arguments than the
+    /// callee has parameters
+    ArgumentCount { expected: usize, found: usize },
+    /// A fixed argument whose type differs from the parameter's
+    ArgumentType {
+        index: usize,
+        expected: Type,
+        found: Type,
+    },
+    /// A variadic marker missing, misplaced, or passed to a function that
+    /// isn't variadic
+    VariadicIndex {
+        expected: Option<u64>,
+        found: Option<u64>,
+    },
+    /// A call result assigned with a type other than the callee's return
+    /// type, or assigned although the callee returns nothing
+    ReturnType { expected: Option<Type>, found: Type },
+}
+
+impl fmt::Display for CallErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownFunction(name) => write!(
+                f,
+                "call to unknown function ${name}: define it or declare it as an extern"
+            ),
+            Self::DuplicateDeclaration(name) => write!(f, "${name} is declared more than once"),
+            Self::ArgumentCount { expected, found } => {
+                write!(f, "expected {expected} fixed arguments, got {found}")
+            }
+            Self::ArgumentType {
+                index,
+                expected,
+                found,
+            } => write!(f, "argument {index} is `{found}`, expected `{expected}`"),
+            Self::VariadicIndex {
+                expected: Some(expected),
+                ..
+            } => write!(
+                f,
+                "call to a variadic function must place `...` after {expected} arguments"
+            ),
+            Self::VariadicIndex { expected: None, .. } => {
+                write!(f, "`...` in a call to a function that isn't variadic")
+            }
+            Self::ReturnType {
+                expected: Some(expected),
+                found,
+            } => write!(f, "call result is `{found}`, expected `{expected}`"),
+            Self::ReturnType {
+                expected: None,
+                found,
+            } => write!(
+                f,
+                "call result assigned as `{found}` but the function returns nothing"
+            ),
+        }
+    }
+}
+
+impl Module {
+    /// Checks every direct call against the signature of its callee: a
+    /// function defined in the module or one declared with
+    /// [`Module::add_extern`].
+    ///
+    /// The fixed arguments must match the parameters in number and type,
+    /// including a [`Type::Env`] argument for an `env` parameter. Calls to
+    /// variadic functions must put the variadic marker of [`Instr::Call`]
+    /// right after the fixed arguments; the variadic arguments themselves
+    /// are not checked. A call result that is assigned must have the
+    /// callee's return type. Calls through a temporary are not checked.
+    ///
+    /// All problems are reported, in module order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{CallErrorKind, Function, FunctionDecl, Instr, Linkage, Module, Type, Value};
+    ///
+    /// let mut module = Module::new();
+    /// module.add_extern(FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic());
+    ///
+    /// let mut main = Function::new(Linkage::public(), "main", vec![], None);
+    /// let start = main.add_block("start");
+    /// start.add_instr(Instr::Call(
+    ///     Value::Global("printf".into()),
+    ///     vec![(Type::Word, Value::Const(0))],
+    ///     None,
+    /// ));
+    /// start.add_instr(Instr::Ret(None));
+    /// module.add_function(main);
+    ///
+    /// let kinds: Vec<CallErrorKind> = module
+    ///     .check_calls()
+    ///     .unwrap_err()
+    ///     .into_iter()
+    ///     .map(|err| err.kind)
+    ///     .collect();
+    /// assert_eq!(
+    ///     kinds,
+    ///     vec![
+    ///         CallErrorKind::VariadicIndex { expected: Some(1), found: None },
+    ///         CallErrorKind::ArgumentType {
+    ///             index: 0,
+    ///             expected: Type::Long,
+    ///             found: Type::Word,
+    ///         },
+    ///     ]
+    /// );
+    /// ```
+    pub fn check_calls(&self) -> Result<(), Vec<CallError>> {
+        let mut errors = Vec::new();
+
+        let mut decls: HashMap<String, FunctionDecl> = HashMap::new();
+        let locals = self.functions.iter().map(|func| (func.decl(), false));
+        let externs = self.externs.iter().map(|decl| (decl.clone(), true));
+        for (decl, is_extern) in locals.chain(externs) {
+            if decls.contains_key(&decl.name) {
+                let location = if is_extern {
+                    Location::Extern(decl.name.clone())
+                } else {
+                    Location::Function {
+                        name: decl.name.clone(),
+                        block: None,
+                        item: None,
+                    }
+                };
+                errors.push(CallError {
+                    location,
+                    kind: CallErrorKind::DuplicateDeclaration(decl.name.clone()),
+                });
+            } else {
+                decls.insert(decl.name.clone(), decl);
+            }
+        }
+
+        for func in &self.functions {
+            for block in &func.blocks {
+                for (i, item) in block.items.iter().enumerate() {
+                    let (result, instr) = match item {
+                        BlockItem::Statement(Statement::Assign(_, ty, instr)) => (Some(ty), instr),
+                        BlockItem::Statement(Statement::Volatile(instr)) => (None, instr),
+                        BlockItem::Comment(_) => continue,
+                    };
+                    let Instr::Call(Value::Global(name), args, variadic) = instr else {
+                        continue;
+                    };
+                    let location = Location::Function {
+                        name: func.name.clone(),
+                        block: Some(block.label.clone()),
+                        item: Some(i),
+                    };
+                    let kinds = match decls.get(name) {
+                        Some(decl) => check_call(decl, args, *variadic, result),
+                        None => vec![CallErrorKind::UnknownFunction(name.clone())],
+                    };
+                    errors.extend(kinds.into_iter().map(|kind| CallError {
+                        location: location.clone(),
+                        kind,
+                    }));
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+fn check_call(
+    decl: &FunctionDecl,
+    args: &[(Type, Value)],
+    variadic: Option<u64>,
+    result: Option<&Type>,
+) -> Vec<CallErrorKind> {
+    let mut errors = Vec::new();
+
+    let expected = decl.variadic.then_some(decl.params.len() as u64);
+    if variadic != expected {
+        errors.push(CallErrorKind::VariadicIndex {
+            expected,
+            found: variadic,
+        });
+    }
+
+    let fixed = match variadic {
+        Some(i) => &args[..(i as usize).min(args.len())],
+        None => args,
+    };
+    if fixed.len() != decl.params.len() {
+        errors.push(CallErrorKind::ArgumentCount {
+            expected: decl.params.len(),
+            found: fixed.len(),
+        });
+    }
+    for (index, (param, (ty, _))) in decl.params.iter().zip(fixed).enumerate() {
+        if param != ty {
+            errors.push(CallErrorKind::ArgumentType {
+                index,
+                expected: param.clone(),
+                found: ty.clone(),
+            });
+        }
+    }
+
+    if let Some(found) = result {
+        if decl.return_ty.as_ref() != Some(found) {
+            errors.push(CallErrorKind::ReturnType {
+                expected: decl.return_ty.clone(),
+                found: found.clone(),
+            });
+        }
+    }
+
+    errors
+}
@@ -0,0 +1,271 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::*;
+
+fn global(name: &str) -> Value {
+    Value::Global(name.into())
+}
+
+fn at(item: usize) -> Location {
+    Location::Function {
+        name: "main".into(),
+        block: Some("start".into()),
+        item: Some(item),
+    }
+}
+
+/// Builds a module declaring `$printf` and defining `$add(w, w) -> w`, whose
+/// `$main` holds the statements added by `build`
+fn module_with(build: impl FnOnce(&mut Block)) -> Module {
+    let mut module = Module::new();
+    module.add_extern(FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic());
+
+    let mut add = Function::new(
+        Linkage::private(),
+        "add",
+        vec![
+            (Type::Word, Value::Temporary("a".into())),
+            (Type::Word, Value::Temporary("b".into())),
+        ],
+        Some(Type::Word),
+    );
+    add.add_block("start")
+        .add_instr(Instr::Ret(Some(Value::Temporary("a".into()))));
+    module.add_function(add);
+
+    let mut main = Function::new(Linkage::public(), "main", vec![], None);
+    let start = main.add_block("start");
+    build(start);
+    start.add_instr(Instr::Ret(None));
+    module.add_function(main);
+    module
+}
+
+fn errors_of(module: &Module) -> Vec<(Location, CallErrorKind)> {
+    match module.check_calls() {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .into_iter()
+            .map(|err| (err.location, err.kind))
+            .collect(),
+    }
+}
+
+#[test]
+fn matching_calls_pass() {
+    let module = module_with(|b| {
+        b.assign_instr(
+            Value::Temporary("r".into()),
+            Type::Word,
+            Instr::Call(
+                global("add"),
+                vec![(Type::Word, 1.into()), (Type::Word, 2.into())],
+                None,
+            ),
+        );
+        b.add_instr(Instr::Call(
+            global("printf"),
+            vec![
+                (Type::Long, global("fmt")),
+                (Type::Word, Value::Temporary("r".into())),
+                (Type::Double, 1.0.into()),
+            ],
+            Some(1),
+        ));
+        // Results may be discarded, and indirect calls aren't checked
+        b.add_instr(Instr::Call(
+            global("add"),
+            vec![(Type::Word, 1.into()), (Type::Word, 2.into())],
+            None,
+        ));
+        b.add_instr(Instr::Call(Value::Temporary("fp".into()), vec![], None));
+    });
+    assert_eq!(errors_of(&module), vec![]);
+}
+
+#[test]
+fn arguments() {
+    let module = module_with(|b| {
+        b.add_instr(Instr::Call(
+            global("add"),
+            vec![(Type::Word, 1.into())],
+            None,
+        ));
+        b.add_instr(Instr::Call(
+            global("add"),
+            vec![(Type::Word, 1.into()), (Type::Long, 2.into())],
+            None,
+        ));
+        b.add_instr(Instr::Call(global("missing"), vec![], None));
+    });
+    assert_eq!(
+        errors_of(&module),
+        vec![
+            (
+                at(0),
+                CallErrorKind::ArgumentCount {
+                    expected: 2,
+                    found: 1
+                }
+            ),
+            (
+                at(1),
+                CallErrorKind::ArgumentType {
+                    index: 1,
+                    expected: Type::Word,
+                    found: Type::Long,
+                }
+            ),
+            (at(2), CallErrorKind::UnknownFunction("missing".into())),
+        ]
+    );
+}
+
+#[test]
+fn variadic_index() {
+    let module = module_with(|b| {
+        b.add_instr(Instr::Call(
+            global("printf"),
+            vec![(Type::Long, global("fmt")), (Type::Word, 1.into())],
+            Some(2),
+        ));
+        b.add_instr(Instr::Call(
+            global("add"),
+            vec![(Type::Word, 1.into()), (Type::Word, 2.into())],
+            Some(2),
+        ));
+    });
+    let errors: Vec<String> = module
+        .check_calls()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "function $main, block @start, item 0: call to a variadic function must place `...` after 1 arguments",
+            "function $main, block @start, item 0: expected 1 fixed arguments, got 2",
+            "function $main, block @start, item 1: `...` in a call to a function that isn't variadic",
+        ]
+    );
+}
+
+#[test]
+fn return_types() {
+    let mut module = module_with(|b| {
+        b.assign_instr(
+            Value::Temporary("r".into()),
+            Type::Long,
+            Instr::Call(
+                global("add"),
+                vec![(Type::Word, 1.into()), (Type::Word, 2.into())],
+                None,
+            ),
+        );
+        b.assign_instr(
+            Value::Temporary("s".into()),
+            Type::Word,
+            Instr::Call(global("exit"), vec![(Type::Word, 0.into())], None),
+        );
+    });
+    module.add_extern(FunctionDecl::new("exit", vec![Type::Word], None));
+
+    assert_eq!(
+        errors_of(&module),
+        vec![
+            (
+                at(0),
+                CallErrorKind::ReturnType {
+                    expected: Some(Type::Word),
+                    found: Type::Long,
+                }
+            ),
+            (
+                at(1),
+                CallErrorKind::ReturnType {
+                    expected: None,
+                    found: Type::Word,
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn env_parameters() {
+    let mut module = module_with(|b| {
+        b.add_instr(Instr::Call(
+            global("closure"),
+            vec![
+                (Type::Env, Value::Temporary("e".into())),
+                (Type::Word, 1.into()),
+            ],
+            None,
+        ));
+        b.add_instr(Instr::Call(
+            global("closure"),
+            vec![(Type::Word, 1.into())],
+            None,
+        ));
+    });
+    module.add_extern(FunctionDecl::new(
+        "closure",
+        vec![Type::Env, Type::Word],
+        None,
+    ));
+    // Declaring a function that is defined in the module is a conflict
+    module.add_extern(FunctionDecl::new("add", vec![], None));
+
+    assert_eq!(
+        errors_of(&module),
+        vec![
+            (
+                Location::Extern("add".into()),
+                CallErrorKind::DuplicateDeclaration("add".into()),
+            ),
+            (
+                at(1),
+                CallErrorKind::ArgumentCount {
+                    expected: 2,
+                    found: 1
+                }
+            ),
+            (
+                at(1),
+                CallErrorKind::ArgumentType {
+                    index: 0,
+                    expected: Type::Env,
+                    found: Type::Word,
+                }
+            ),
+        ]
+    );
+}
+
+#[test]
+fn duplicate_declarations() {
+    let mut module = module_with(|_| {});
+    module.add_extern(FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic());
+    let mut add = Function::new(Linkage::private(), "add", vec![], None);
+    add.add_block("start").add_instr(Instr::Ret(None));
+    module.add_function(add);
+
+    let errors = module.check_calls().unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "function $add: $add is declared more than once",
+            "extern $printf: $printf is declared more than once",
+        ]
+    );
+    assert_eq!(errors[1].location, Location::Extern("printf".into()));
+}
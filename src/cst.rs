@@ -0,0 +1,505 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lossless concrete syntax trees for QBE IL.
+
+use crate::parse::{is_item_start, lex, Token, TokenKind};
+use crate::{Module, ParseErrors};
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// Kind of a [`SyntaxNode`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum NodeKind {
+    /// The whole source: definitions and everything between them
+    Module,
+    /// `type :name = { ... }`
+    TypeDef,
+    /// `data $name = { ... }`
+    DataDef,
+    /// A function header, its blocks and the closing brace. Lines before the
+    /// first label are direct children.
+    Function,
+    /// A label and the lines up to the next label or the end of the function
+    Block,
+    /// A single statement, without surrounding whitespace or comments
+    Statement,
+    /// Tokens that don't start a definition, up to the end of the line
+    Error,
+}
+
+/// A token of a syntax tree, owning its text
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SyntaxToken {
+    pub kind: TokenKind,
+    pub text: String,
+}
+
+impl SyntaxToken {
+    /// Returns true for whitespace, newlines and comments
+    pub fn is_trivia(&self) -> bool {
+        is_trivia(self.kind)
+    }
+}
+
+fn is_trivia(kind: TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Whitespace | TokenKind::Newline | TokenKind::Comment
+    )
+}
+
+/// A child of a [`SyntaxNode`]
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum SyntaxElement {
+    Node(SyntaxNode),
+    Token(SyntaxToken),
+}
+
+/// A node of a lossless syntax tree.
+///
+/// Unlike [`Module`], the tree keeps every character of the source,
+/// including whitespace, blank lines and comments anywhere in the file, so
+/// printing it reproduces the input exactly. Any text can be parsed; input
+/// that isn't valid IL ends up in [`NodeKind::Error`] nodes or unbalanced
+/// definitions and is reported by [`SyntaxNode::to_module`].
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{NodeKind, SyntaxNode};
+///
+/// let src = "# runtime helpers\n\nfunction $nop() {\n@start   # entry\n\tret\n}\n";
+/// let tree = SyntaxNode::parse(src);
+/// assert_eq!(tree.to_string(), src);
+///
+/// let function = tree.nodes().next().unwrap();
+/// assert_eq!(function.kind, NodeKind::Function);
+///
+/// // The formatter normalizes spacing but keeps comments and blank lines
+/// let formatted = SyntaxNode::parse("function   $nop( ) {\n@start # entry\n  ret\n}")
+///     .format()
+///     .unwrap();
+/// assert_eq!(formatted.to_string(), "function $nop() {\n@start # entry\n\tret\n}\n");
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct SyntaxNode {
+    pub kind: NodeKind,
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// Builds the syntax tree of `src`. The result is a [`NodeKind::Module`]
+    /// node.
+    pub fn parse(src: &str) -> SyntaxNode {
+        let mut tokens = lex(src);
+        tokens.pop(); // Eof
+        Builder { tokens, pos: 0 }.module()
+    }
+
+    /// Returns the tokens of the node and its descendants in source order
+    pub fn tokens(&self) -> Vec<&SyntaxToken> {
+        let mut tokens = Vec::new();
+        self.collect_tokens(&mut tokens);
+        tokens
+    }
+
+    fn collect_tokens<'a>(&'a self, tokens: &mut Vec<&'a SyntaxToken>) {
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) => node.collect_tokens(tokens),
+                SyntaxElement::Token(tok) => tokens.push(tok),
+            }
+        }
+    }
+
+    /// Returns the child nodes, skipping child tokens
+    pub fn nodes(&self) -> impl Iterator<Item = &SyntaxNode> {
+        self.children.iter().filter_map(|child| match child {
+            SyntaxElement::Node(node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        })
+    }
+
+    /// Converts the tree into a [`Module`], see
+    /// [`Module::from_str`](std::str::FromStr::from_str).
+    ///
+    /// Comments and blank lines that [`Module`] can't represent are dropped.
+    pub fn to_module(&self) -> Result<Module, ParseErrors> {
+        self.to_string().parse()
+    }
+
+    /// Returns a copy of the tree with normalized layout.
+    ///
+    /// Tokens are separated the way [`Module`]'s
+    /// [`Display`](fmt::Display) implementation separates them, statements
+    /// are indented by a tab, and trailing whitespace is removed. Comments
+    /// are kept, and runs of blank lines are collapsed into one. Formatting
+    /// the output of [`Module`]'s `Display` implementation returns it
+    /// unchanged.
+    ///
+    /// Returns an error instead if the tree isn't valid IL, since its layout
+    /// can't be normalized safely.
+    pub fn format(&self) -> Result<SyntaxNode, ParseErrors> {
+        self.to_module()?;
+
+        let mut lines = Lines::default();
+        for child in &self.children {
+            match child {
+                SyntaxElement::Node(node) if node.kind == NodeKind::Function => {
+                    lines.function(node);
+                }
+                SyntaxElement::Node(node) => lines.flat("", &node.tokens(), false),
+                SyntaxElement::Token(tok) => lines.trivia(tok, ""),
+            }
+        }
+
+        Ok(SyntaxNode::parse(&lines.finish()))
+    }
+}
+
+impl From<&Module> for SyntaxNode {
+    fn from(module: &Module) -> Self {
+        SyntaxNode::parse(&module.to_string())
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for tok in self.tokens() {
+            write!(f, "{}", tok.text)?;
+        }
+        Ok(())
+    }
+}
+
+/// Groups lexer tokens into a syntax tree
+struct Builder<'a> {
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+}
+
+impl<'a> Builder<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> SyntaxElement {
+        let tok = self.tokens[self.pos];
+        self.pos += 1;
+        SyntaxElement::Token(SyntaxToken {
+            kind: tok.kind,
+            text: tok.text.to_string(),
+        })
+    }
+
+    /// Returns true if only whitespace precedes the next token on its line
+    fn at_line_start(&self) -> bool {
+        self.tokens[..self.pos]
+            .iter()
+            .rev()
+            .find(|tok| tok.kind != TokenKind::Whitespace)
+            .is_none_or(|tok| tok.kind == TokenKind::Newline)
+    }
+
+    /// Returns true if the next token starts a definition after `start`,
+    /// which ends an unterminated one
+    fn at_next_item(&self, start: usize) -> bool {
+        self.pos > start && self.peek().is_some_and(is_item_start) && self.at_line_start()
+    }
+
+    /// Returns true if the next token is a label starting a block. Labels
+    /// inside statements are consumed with them, so any label seen here
+    /// starts a block, even on the line of the previous one or of `{`.
+    fn at_block_start(&self) -> bool {
+        self.peek().is_some_and(|tok| tok.kind == TokenKind::Label)
+    }
+
+    fn module(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        while let Some(tok) = self.peek() {
+            if is_trivia(tok.kind) {
+                children.push(self.bump());
+            } else {
+                children.push(SyntaxElement::Node(self.item()));
+            }
+        }
+        SyntaxNode {
+            kind: NodeKind::Module,
+            children,
+        }
+    }
+
+    fn item(&mut self) -> SyntaxNode {
+        match self.item_kind() {
+            NodeKind::Function => self.function(),
+            kind @ (NodeKind::TypeDef | NodeKind::DataDef) => self.braced(kind),
+            _ => self.rest_of_line(),
+        }
+    }
+
+    /// Looks past the linkage to find out what kind of definition follows
+    fn item_kind(&self) -> NodeKind {
+        for tok in &self.tokens[self.pos..] {
+            match (tok.kind, tok.text) {
+                (TokenKind::Whitespace | TokenKind::Str, _) => {}
+                (TokenKind::Ident, "export" | "thread" | "section") => {}
+                (TokenKind::Ident, "type") => return NodeKind::TypeDef,
+                (TokenKind::Ident, "data") => return NodeKind::DataDef,
+                (TokenKind::Ident, "function") => return NodeKind::Function,
+                _ => break,
+            }
+        }
+        NodeKind::Error
+    }
+
+    fn rest_of_line(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        while self
+            .peek()
+            .is_some_and(|tok| tok.kind != TokenKind::Newline)
+        {
+            children.push(self.bump());
+        }
+        SyntaxNode {
+            kind: NodeKind::Error,
+            children,
+        }
+    }
+
+    /// Collects a type or data definition up to its matching closing brace
+    fn braced(&mut self, kind: NodeKind) -> SyntaxNode {
+        let start = self.pos;
+        let mut children = Vec::new();
+        let mut depth = 0usize;
+        while let Some(tok) = self.peek() {
+            if self.at_next_item(start) {
+                break;
+            }
+            children.push(self.bump());
+            match tok.kind {
+                TokenKind::LBrace => depth += 1,
+                TokenKind::RBrace if depth == 1 => break,
+                TokenKind::RBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        SyntaxNode { kind, children }
+    }
+
+    fn function(&mut self) -> SyntaxNode {
+        let start = self.pos;
+        let mut children = Vec::new();
+
+        // Header, up to and including the opening brace
+        while let Some(tok) = self.peek() {
+            if self.at_next_item(start) {
+                break;
+            }
+            children.push(self.bump());
+            if tok.kind == TokenKind::LBrace {
+                break;
+            }
+        }
+
+        while let Some(tok) = self.peek() {
+            if self.at_next_item(start) {
+                break;
+            }
+            if tok.kind == TokenKind::RBrace {
+                children.push(self.bump());
+                break;
+            }
+            if self.at_block_start() {
+                children.push(SyntaxElement::Node(self.block(start)));
+            } else if is_trivia(tok.kind) {
+                children.push(self.bump());
+            } else {
+                children.push(SyntaxElement::Node(self.statement()));
+            }
+        }
+
+        SyntaxNode {
+            kind: NodeKind::Function,
+            children,
+        }
+    }
+
+    fn block(&mut self, start: usize) -> SyntaxNode {
+        let mut children = vec![self.bump()];
+        while let Some(tok) = self.peek() {
+            if tok.kind == TokenKind::RBrace || self.at_block_start() || self.at_next_item(start) {
+                break;
+            }
+            if is_trivia(tok.kind) {
+                children.push(self.bump());
+            } else {
+                children.push(SyntaxElement::Node(self.statement()));
+            }
+        }
+        SyntaxNode {
+            kind: NodeKind::Block,
+            children,
+        }
+    }
+
+    fn statement(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        while let Some(tok) = self.peek() {
+            if matches!(
+                tok.kind,
+                TokenKind::Newline | TokenKind::Comment | TokenKind::RBrace
+            ) {
+                break;
+            }
+            children.push(self.bump());
+        }
+
+        // Leave trailing whitespace to the enclosing block
+        while let Some(SyntaxElement::Token(tok)) = children.last() {
+            if tok.kind != TokenKind::Whitespace {
+                break;
+            }
+            children.pop();
+            self.pos -= 1;
+        }
+
+        SyntaxNode {
+            kind: NodeKind::Statement,
+            children,
+        }
+    }
+}
+
+/// Returns true if a space separates two adjacent tokens on a line
+fn spaced(prev: &SyntaxToken, next: &SyntaxToken, statement: bool) -> bool {
+    use TokenKind::*;
+    let tight = matches!(prev.kind, LParen | Plus)
+        || matches!(next.kind, Comma | RParen)
+        || (next.kind == LParen && matches!(prev.kind, Global | Temporary))
+        // `%x =w ...`
+        || (statement && prev.kind == Equals);
+    !tight
+}
+
+/// Output of the formatter, one line at a time
+#[derive(Default)]
+struct Lines {
+    lines: Vec<String>,
+    /// Newlines in the source since the last output line
+    newlines: usize,
+}
+
+impl Lines {
+    /// Starts a new line, preceded by a blank one if the source had any
+    fn push(&mut self, line: String) {
+        if self.newlines >= 2 && !self.lines.is_empty() {
+            self.lines.push(String::new());
+        }
+        self.lines.push(line);
+        self.newlines = 0;
+    }
+
+    /// Adds a comment to the last line if it was on the same line in the
+    /// source, or on a new line otherwise
+    fn comment(&mut self, comment: &str, indent: &str) {
+        let comment = comment.trim_end();
+        match self.lines.last_mut() {
+            Some(line) if self.newlines == 0 => {
+                line.push(' ');
+                line.push_str(comment);
+            }
+            _ => self.push(format!("{indent}{comment}")),
+        }
+    }
+
+    fn trivia(&mut self, tok: &SyntaxToken, indent: &str) {
+        match tok.kind {
+            TokenKind::Newline => self.newlines += 1,
+            TokenKind::Comment => self.comment(&tok.text, indent),
+            _ => {}
+        }
+    }
+
+    /// Writes tokens on one line, breaking it only after comments
+    fn flat(&mut self, indent: &str, tokens: &[&SyntaxToken], statement: bool) {
+        let mut line = indent.to_string();
+        let mut prev: Option<&SyntaxToken> = None;
+
+        for &tok in tokens {
+            match tok.kind {
+                TokenKind::Whitespace => {}
+                TokenKind::Newline if prev.is_none() => self.newlines += 1,
+                TokenKind::Newline => {}
+                TokenKind::Comment => {
+                    if prev.is_some() {
+                        self.push(line);
+                        line = format!("{indent}\t");
+                        prev = None;
+                    }
+                    self.comment(&tok.text, indent);
+                }
+                _ => {
+                    if prev.is_some_and(|prev| spaced(prev, tok, statement)) {
+                        line.push(' ');
+                    }
+                    line.push_str(&tok.text);
+                    prev = Some(tok);
+                }
+            }
+        }
+
+        if prev.is_some() {
+            self.push(line);
+        }
+    }
+
+    fn function(&mut self, node: &SyntaxNode) {
+        let mut header = Vec::new();
+        let mut children = node.children.iter();
+        for child in children.by_ref() {
+            if let SyntaxElement::Token(tok) = child {
+                header.push(tok);
+                if tok.kind == TokenKind::LBrace {
+                    break;
+                }
+            }
+        }
+        self.flat("", &header, false);
+
+        for child in children {
+            match child {
+                SyntaxElement::Token(tok) if tok.kind == TokenKind::RBrace => {
+                    self.push("}".to_string());
+                }
+                SyntaxElement::Token(tok) => self.trivia(tok, "\t"),
+                SyntaxElement::Node(node) if node.kind == NodeKind::Block => self.block(node),
+                SyntaxElement::Node(node) => self.flat("\t", &node.tokens(), true),
+            }
+        }
+    }
+
+    fn block(&mut self, node: &SyntaxNode) {
+        for child in &node.children {
+            match child {
+                SyntaxElement::Token(tok) if tok.kind == TokenKind::Label => {
+                    self.push(tok.text.clone());
+                }
+                SyntaxElement::Token(tok) => self.trivia(tok, "\t"),
+                SyntaxElement::Node(node) => self.flat("\t", &node.tokens(), true),
+            }
+        }
+    }
+
+    fn finish(self) -> String {
+        self.lines.into_iter().map(|line| line + "\n").collect()
+    }
+}
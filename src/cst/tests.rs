@@ -0,0 +1,225 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::*;
+
+const MESSY: &str = "\
+# Runtime support, written by hand
+
+
+type :pair = { w,   w }   # two words
+export   data $greeting = { b \"hi\\n\",
+    b 0 }  # NUL terminated
+
+# Adds two numbers
+export function w $add(w %a,w %b) {
+  # before the first label
+@start   # entry
+    %sum =w add %a,%b    # the sum
+
+
+    ret %sum
+@dead
+}
+";
+
+fn kinds(node: &SyntaxNode) -> Vec<NodeKind> {
+    node.nodes().map(|node| node.kind).collect()
+}
+
+#[test]
+fn tree_is_lossless() {
+    let tree = SyntaxNode::parse(MESSY);
+    assert_eq!(tree.to_string(), MESSY);
+
+    let broken = "function $f( {\n@a\n\t%x =w add\n}}\ngarbage \"open\ntype :t = {";
+    assert_eq!(SyntaxNode::parse(broken).to_string(), broken);
+    assert_eq!(SyntaxNode::parse("").to_string(), "");
+}
+
+#[test]
+fn tree_structure() {
+    let tree = SyntaxNode::parse(MESSY);
+    assert_eq!(tree.kind, NodeKind::Module);
+    assert_eq!(
+        kinds(&tree),
+        vec![NodeKind::TypeDef, NodeKind::DataDef, NodeKind::Function]
+    );
+
+    // Top-level comments are children of the module
+    let comments: Vec<&str> = tree
+        .children
+        .iter()
+        .filter_map(|child| match child {
+            SyntaxElement::Token(tok) if tok.kind == TokenKind::Comment => Some(&*tok.text),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        comments,
+        vec![
+            "# Runtime support, written by hand",
+            "# two words",
+            "# NUL terminated",
+            "# Adds two numbers",
+        ]
+    );
+
+    let function = tree.nodes().nth(2).unwrap();
+    assert_eq!(kinds(function), vec![NodeKind::Block, NodeKind::Block]);
+    let start = function.nodes().next().unwrap();
+    assert_eq!(kinds(start), vec![NodeKind::Statement, NodeKind::Statement]);
+    assert_eq!(
+        start.nodes().next().unwrap().to_string(),
+        "%sum =w add %a,%b"
+    );
+
+    let broken = SyntaxNode::parse("garbage here\ndata $d = { w 1\nfunction $f() {\n");
+    assert_eq!(
+        kinds(&broken),
+        vec![NodeKind::Error, NodeKind::DataDef, NodeKind::Function]
+    );
+}
+
+#[test]
+fn tree_converts_to_and_from_module() {
+    let tree = SyntaxNode::parse(MESSY);
+    let module = tree.to_module().unwrap();
+    assert_eq!(module, MESSY.parse::<Module>().unwrap());
+
+    let from_module = SyntaxNode::from(&module);
+    assert_eq!(from_module.to_string(), module.to_string());
+    assert_eq!(from_module.to_module().unwrap(), module);
+
+    let errors = SyntaxNode::parse("function $f() {")
+        .to_module()
+        .unwrap_err();
+    assert_eq!(errors.errors.len(), 1);
+}
+
+#[test]
+fn format_keeps_comments_and_blank_lines() {
+    let formatted = SyntaxNode::parse(MESSY).format().unwrap();
+    assert_eq!(
+        formatted.to_string(),
+        "\
+# Runtime support, written by hand
+
+type :pair = { w, w } # two words
+export data $greeting = { b \"hi\\n\", b 0 } # NUL terminated
+
+# Adds two numbers
+export function w $add(w %a, w %b) {
+\t# before the first label
+@start # entry
+\t%sum =w add %a, %b # the sum
+
+\tret %sum
+@dead
+}
+"
+    );
+    assert_eq!(
+        formatted.to_module().unwrap(),
+        MESSY.parse::<Module>().unwrap()
+    );
+
+    // Formatting is idempotent
+    assert_eq!(formatted.format().unwrap(), formatted);
+}
+
+#[test]
+fn format_breaks_lines_after_comments() {
+    let formatted = SyntaxNode::parse("data $d = { w 1, # one\nw 2 }\n")
+        .format()
+        .unwrap();
+    assert_eq!(formatted.to_string(), "data $d = { w 1, # one\n\tw 2 }\n");
+}
+
+#[test]
+fn format_leaves_display_output_unchanged() {
+    let mut module = Module::new();
+    let pair = std::sync::Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: Some(8),
+        items: vec![(Type::Word, 2)],
+    });
+    module.add_type(pair.clone());
+    module.add_data(DataDef::new(
+        Linkage::public_with_section("rodata"),
+        "table",
+        None,
+        vec![
+            (Type::Long, DataItem::Symbol("table".into(), Some(8))),
+            (Type::Byte, "a \"quoted\" string".into()),
+            (Type::Word, DataItem::List(vec![1.into(), (-1).into()])),
+            (Type::Byte, DataItem::Zero(3)),
+        ],
+    ));
+
+    let mut func = Function::new(
+        Linkage::public(),
+        "main",
+        vec![(Type::Aggregate(pair), Value::Temporary("p".into()))],
+        Some(Type::Word),
+    );
+    func.variadic = true;
+    let block = func.add_block("start");
+    block.add_comment("call through a pointer");
+    block.assign_instr(
+        Value::Temporary("r".into()),
+        Type::Word,
+        Instr::Call(
+            Value::Global("printf".into()),
+            vec![
+                (Type::Long, Value::Global("table".into())),
+                (Type::Double, 1.5f64.into()),
+            ],
+            Some(1),
+        ),
+    );
+    block.add_instr(Instr::Jnz(1.into(), "empty".into(), "end".into()));
+    func.add_block("empty");
+    func.add_block("end")
+        .add_instr(Instr::Ret(Some(Value::Temporary("r".into()))));
+    module.add_function(func);
+
+    let tree = SyntaxNode::from(&module);
+    assert_eq!(tree.format().unwrap(), tree);
+}
+
+#[test]
+fn format_keeps_meaning() {
+    for src in [
+        MESSY,
+        "data $s = { b \"# not a comment\", b 0 }",
+        "data $d = { w 1 2 3, # ones\n z 4,\n\n l $d +8, l thread $t }",
+        "type :u = { { w } { s 2 } }\ntype :o = align 16 { 32 }",
+        "function $f(env %e, l %fmt, ...) {\n@start\n%x =d copy d_-1.5e+3\n\
+         %y =w call $g(env %e, w 1, ..., d %x)   # varargs\n\
+         %p =l alloc16 %x\n  jnz %y, @start, @end\n@end\n  ret\n}",
+        "function w $g() {@a jmp @b\n@b %v =w phi @a 1, @b %v # loop\n jmp @b}",
+    ] {
+        let formatted = SyntaxNode::parse(src).format().unwrap();
+        assert_eq!(
+            formatted.to_module().unwrap(),
+            src.parse::<Module>().unwrap(),
+            "{formatted}"
+        );
+        assert_eq!(formatted.format().unwrap(), formatted, "{formatted}");
+    }
+}
+
+#[test]
+fn format_rejects_invalid_il() {
+    let errors = SyntaxNode::parse("function $f() {\n@start\n\tfrob\n}")
+        .format()
+        .unwrap_err();
+    assert_eq!(errors.errors[0].message, "unknown instruction `frob`");
+}
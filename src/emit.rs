@@ -0,0 +1,161 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Writing IL without panicking on invalid input.
+
+use crate::validate::{assign_problems, data_item_problems, instr_problems, signature_problems};
+use crate::{
+    BlockItem, Function, Location, Module, Statement, ValidationError, ValidationErrorKind,
+};
+use std::fmt;
+use std::io;
+
+#[cfg(test)]
+mod tests;
+
+/// An error returned by [`Module::write_to`] and [`Module::try_to_string`]
+#[derive(Debug)]
+pub enum EmitError {
+    /// An item that has no IL representation, such as a load of an
+    /// aggregate or conflicting type definitions. Formatting the module with
+    /// `Display` would panic.
+    Invalid(ValidationError),
+    /// Writing to the output failed
+    Io(io::Error),
+}
+
+impl fmt::Display for EmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Invalid(err) => write!(f, "{err}"),
+            Self::Io(err) => write!(f, "failed to write IL: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for EmitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Invalid(err) => Some(err),
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for EmitError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl Module {
+    /// Writes the module as IL, like its `Display` implementation, but
+    /// returns an error instead of panicking on items that can't be
+    /// formatted.
+    ///
+    /// The whole module is checked before anything is written, so nothing
+    /// is written if it is invalid. Only the first problem is reported; use
+    /// [`Module::validate`] to find all of them.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{EmitError, Function, Instr, Linkage, Module, Type, Value};
+    ///
+    /// let mut module = Module::new();
+    /// let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    /// let start = func.add_block("start");
+    /// start.assign_instr(
+    ///     Value::Temporary("x".into()),
+    ///     Type::Word,
+    ///     Instr::Load(Type::Byte, Value::Global("g".into())),
+    /// );
+    /// start.add_instr(Instr::Ret(None));
+    /// module.add_function(func);
+    ///
+    /// let mut out = Vec::new();
+    /// let err = module.write_to(&mut out).unwrap_err();
+    /// assert!(matches!(err, EmitError::Invalid(_)));
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "function $f, block @start, item 0: cannot load `b`: use a base type or a signed/unsigned sub-word type"
+    /// );
+    /// assert!(out.is_empty());
+    /// ```
+    pub fn write_to(&self, w: &mut impl io::Write) -> Result<(), EmitError> {
+        if let Err(errors) = self.sorted_types() {
+            return Err(EmitError::Invalid(errors[0].clone()));
+        }
+        for data in &self.data {
+            let problem = data
+                .items
+                .iter()
+                .flat_map(|(_, item)| data_item_problems(item))
+                .next();
+            if let Some(kind) = problem {
+                return Err(EmitError::Invalid(ValidationError {
+                    location: Location::Data(data.name.clone()),
+                    kind,
+                }));
+            }
+        }
+        for func in &self.functions {
+            check_function(func).map_err(EmitError::Invalid)?;
+        }
+
+        write!(w, "{self}")?;
+        Ok(())
+    }
+
+    /// Formats the module as IL, returning an error instead of panicking on
+    /// items that can't be formatted. See [`Module::write_to`].
+    pub fn try_to_string(&self) -> Result<String, EmitError> {
+        let mut out = Vec::new();
+        self.write_to(&mut out)?;
+        Ok(String::from_utf8(out).expect("formatted IL is valid UTF-8"))
+    }
+}
+
+/// Returns the first problem that would make formatting `func` panic
+fn check_function(func: &Function) -> Result<(), ValidationError> {
+    let error =
+        |block: Option<&str>, item: Option<usize>, kind: ValidationErrorKind| ValidationError {
+            location: Location::Function {
+                name: func.name.clone(),
+                block: block.map(str::to_string),
+                item,
+            },
+            kind,
+        };
+
+    if let Some(kind) = signature_problems(func).into_iter().next() {
+        return Err(error(None, None, kind));
+    }
+
+    for block in &func.blocks {
+        for (i, item) in block.items.iter().enumerate() {
+            let BlockItem::Statement(statement) = item else {
+                continue;
+            };
+            let problems = match statement {
+                Statement::Assign(temp, ty, instr) => {
+                    let mut problems = assign_problems(temp, ty, instr);
+                    problems.extend(instr_problems(instr));
+                    problems
+                }
+                Statement::Volatile(instr) => instr_problems(instr),
+            };
+            if let Some(kind) = problems.into_iter().next() {
+                return Err(error(Some(&block.label), Some(i), kind));
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,186 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::tests::temp;
+use crate::*;
+use std::io;
+use std::sync::Arc;
+
+/// Returns the problem reported when emitting a module holding only `func`
+fn emit_error(func: Function) -> ValidationError {
+    let mut module = Module::new();
+    module.add_function(func);
+    match module.try_to_string() {
+        Err(EmitError::Invalid(err)) => err,
+        other => panic!("expected an invalid item, got {other:?}"),
+    }
+}
+
+#[test]
+fn matches_display() {
+    let mut module = Module::new();
+    module.add_type(Arc::new(TypeDef::Opaque {
+        ident: "blob".into(),
+        align: 8,
+        size: 16,
+    }));
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "msg",
+        None,
+        vec![(Type::Byte, DataItem::Str("hi".into()))],
+    ));
+    let mut func = Function::new(Linkage::public(), "main", vec![], Some(Type::Word));
+    let start = func.add_block("start");
+    start.assign_instr(
+        temp("r"),
+        Type::Word,
+        Instr::Call(
+            Value::Global("puts".into()),
+            vec![(Type::Long, Value::Global("msg".into()))],
+            None,
+        ),
+    );
+    start.add_instr(Instr::Ret(Some(0.into())));
+    module.add_function(func);
+
+    assert_eq!(module.try_to_string().unwrap(), module.to_string());
+
+    let mut out = Vec::new();
+    module.write_to(&mut out).unwrap();
+    assert_eq!(out, module.to_string().into_bytes());
+}
+
+#[test]
+fn reports_location_instead_of_panicking() {
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    let start = func.add_block("start");
+    start.add_comment("skipped");
+    start.items.push(BlockItem::Statement(Statement::Assign(
+        Value::Global("g".into()),
+        Type::Word,
+        Instr::Copy(1.into()),
+    )));
+    let err = emit_error(func);
+    assert_eq!(
+        err.location,
+        Location::Function {
+            name: "f".into(),
+            block: Some("start".into()),
+            item: Some(1),
+        }
+    );
+    assert_eq!(
+        err.kind,
+        ValidationErrorKind::InvalidAssignTarget(Value::Global("g".into()))
+    );
+
+    let pair = Type::Aggregate(Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    }));
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("start")
+        .add_instr(Instr::Store(pair.clone(), temp("p"), temp("v")));
+    assert_eq!(
+        emit_error(func).kind,
+        ValidationErrorKind::InvalidStoreType(pair)
+    );
+
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("start")
+        .add_instr(Instr::Call(0.into(), vec![], None));
+    assert_eq!(
+        emit_error(func).kind,
+        ValidationErrorKind::InvalidCallTarget(0.into())
+    );
+
+    let func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Word, temp("a")), (Type::Env, temp("e"))],
+        None,
+    );
+    let err = emit_error(func);
+    assert_eq!(err.kind, ValidationErrorKind::MisplacedEnv);
+    assert_eq!(
+        err.to_string(),
+        "function $f: env must be the first and only environment parameter or argument"
+    );
+
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    let start = func.add_block("start");
+    start.add_instr(Instr::Call(
+        Value::Global("g".into()),
+        vec![(Type::Word, 1.into())],
+        Some(3),
+    ));
+    start.add_instr(Instr::Ret(None));
+    let err = emit_error(func);
+    assert_eq!(
+        err.location,
+        Location::Function {
+            name: "f".into(),
+            block: Some("start".into()),
+            item: Some(0),
+        }
+    );
+    assert_eq!(
+        err.kind,
+        ValidationErrorKind::InvalidVariadicIndex { index: 3, args: 1 }
+    );
+
+    let mut module = Module::new();
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "d",
+        None,
+        vec![(
+            Type::Word,
+            DataItem::List(vec![1.into(), DataItem::Zero(4)]),
+        )],
+    ));
+    match module.try_to_string() {
+        Err(EmitError::Invalid(err)) => {
+            assert_eq!(err.location, Location::Data("d".into()));
+            assert_eq!(
+                err.kind,
+                ValidationErrorKind::InvalidListItem(DataItem::Zero(4))
+            );
+        }
+        other => panic!("expected an invalid item, got {other:?}"),
+    }
+}
+
+#[test]
+fn write_errors_are_returned() {
+    struct Broken;
+
+    impl io::Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut module = Module::new();
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "x",
+        None,
+        vec![(Type::Word, 1.into())],
+    ));
+    let err = module.write_to(&mut Broken).unwrap_err();
+    assert!(matches!(err, EmitError::Io(_)));
+    assert_eq!(err.to_string(), "failed to write IL: disk full");
+}
@@ -69,8 +69,28 @@
 use std::fmt;
 use std::sync::Arc;
 
+mod builder;
+mod calls;
+mod cst;
+mod emit;
+mod parse;
+mod ssa;
 #[cfg(test)]
 mod tests;
+mod typecheck;
+mod types;
+mod validate;
+mod version;
+
+pub use builder::{BlockRef, CallTable, FunctionBuilder, Loop, Signedness, TypedValue, Var};
+pub use calls::{CallError, CallErrorKind};
+pub use cst::{NodeKind, SyntaxElement, SyntaxNode, SyntaxToken};
+pub use emit::EmitError;
+pub use parse::{ParseError, ParseErrors, Span, TokenKind};
+pub use ssa::{SsaError, SsaErrorKind};
+pub use typecheck::{TypeError, TypeErrorKind};
+pub use validate::{ValidationError, ValidationErrorKind};
+pub use version::{QbeVersion, VersionError};
 
 /// QBE comparison operations used in conditional instructions.
 ///
@@ -123,6 +143,14 @@ pub enum Cmp {
     Ugt,
     /// Returns 1 if first value is greater than or equal to second, unsigned comparison
     Uge,
+    /// Returns 1 if first value is less than second, floating-point comparison
+    Lt,
+    /// Returns 1 if first value is less than or equal to second, floating-point comparison
+    Le,
+    /// Returns 1 if first value is greater than second, floating-point comparison
+    Gt,
+    /// Returns 1 if first value is greater than or equal to second, floating-point comparison
+    Ge,
 }
 
 /// QBE instructions representing operations in the intermediate language.
@@ -151,7 +179,10 @@ pub enum Cmp {
 /// use qbe::{Instr, Type, Value};
 ///
 /// // Allocate 8 bytes on the stack with 8-byte alignment
-/// let alloc = Instr::Alloc8(8);
+/// let alloc = Instr::Alloc8(Value::Const(8));
+///
+/// // Allocate a runtime-sized buffer: %buf = alloc16 %len
+/// let vla = Instr::Alloc16(Value::Temporary("len".to_string()));
 ///
 /// // Store a word to memory: store %value, %ptr
 /// let store = Instr::Store(
@@ -211,14 +242,30 @@ pub enum Instr {
     Jnz(Value, String, String),
     /// Unconditionally jumps to a label
     Jmp(String),
-    /// Calls a function
-    Call(String, Vec<(Type, Value)>, Option<u64>),
-    /// Allocates a 4-byte aligned area on the stack
-    Alloc4(u32),
-    /// Allocates a 8-byte aligned area on the stack
-    Alloc8(u64),
-    /// Allocates a 16-byte aligned area on the stack
-    Alloc16(u128),
+    /// Calls a function.
+    /// `(callee, arguments, variadic index)`
+    ///
+    /// The callee is either a [`Value::Global`] for a direct call or a
+    /// [`Value::Temporary`] holding a function pointer for an indirect one.
+    /// An environment argument is passed with [`Type::Env`] as the first
+    /// argument.
+    ///
+    /// # Panics
+    ///
+    /// Formatting panics if the callee is a constant, if an environment
+    /// argument is not the first one, if the variadic index is greater than
+    /// the number of arguments, or if an argument type doesn't satisfy
+    /// [`Type::is_abi`]. [`Module::write_to`] returns an error instead.
+    Call(Value, Vec<(Type, Value)>, Option<u64>),
+    /// Allocates a 4-byte aligned area on the stack. The size may be a
+    /// constant or a temporary computed at runtime.
+    Alloc4(Value),
+    /// Allocates a 8-byte aligned area on the stack. The size may be a
+    /// constant or a temporary computed at runtime.
+    Alloc8(Value),
+    /// Allocates a 16-byte aligned area on the stack. The size may be a
+    /// constant or a temporary computed at runtime.
+    Alloc16(Value),
     /// Stores a value into memory pointed to by destination.
     /// `(type, destination, value)`
     ///
@@ -236,7 +283,7 @@ pub enum Instr {
     /// Panics if called with [`Type::Byte`] or [`Type::Halfword`], because QBE requires
     /// explicit sign/zero extension for sub-word loads. Use [`Type::SignedByte`] /
     /// [`Type::UnsignedByte`] or [`Type::SignedHalfword`] / [`Type::UnsignedHalfword`]
-    /// instead.
+    /// instead. [`Module::write_to`] returns an error instead of panicking.
     ///
     /// See the [QBE IL reference](https://c9x.me/compile/doc/il.html#Memory).
     Load(Type, Value),
@@ -247,7 +294,8 @@ pub enum Instr {
     /// n must be a constant value.
     ///
     /// ## Minimum supported QBE version
-    /// `1.1`
+    /// `1.1`. [`Module::lower_for`] rewrites it into loads and stores for
+    /// older releases.
     Blit(Value, Value, u64),
 
     /// Debug file.
@@ -326,6 +374,227 @@ pub enum Instr {
     Hlt,
 }
 
+impl Instr {
+    /// Returns an alloc instruction reserving a stack slot for a value of
+    /// `ty`, picking the smallest alloc alignment that satisfies it.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{Instr, Type, Value};
+    ///
+    /// assert_eq!(Instr::alloc_for(&Type::Word), Instr::Alloc4(Value::Const(4)));
+    /// assert_eq!(Instr::alloc_for(&Type::Double), Instr::Alloc8(Value::Const(8)));
+    /// ```
+    pub fn alloc_for(ty: &Type) -> Instr {
+        let size = Value::Const(ty.size());
+        match ty.align() {
+            0..=4 => Instr::Alloc4(size),
+            5..=8 => Instr::Alloc8(size),
+            _ => Instr::Alloc16(size),
+        }
+    }
+
+    /// Returns the alignment of the area reserved by an alloc instruction,
+    /// or `None` for any other instruction
+    pub fn alloc_align(&self) -> Option<u64> {
+        match self {
+            Self::Alloc4(_) => Some(4),
+            Self::Alloc8(_) => Some(8),
+            Self::Alloc16(_) => Some(16),
+            _ => None,
+        }
+    }
+
+    /// Returns the size of the area reserved by an alloc instruction when it
+    /// is a constant. Returns `None` for dynamically sized allocs and for any
+    /// other instruction.
+    pub fn alloc_size(&self) -> Option<u64> {
+        match self {
+            Self::Alloc4(size) | Self::Alloc8(size) | Self::Alloc16(size) => match size {
+                Value::Const(size) => Some(*size),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Returns the values read by the instruction, in operand order
+    pub(crate) fn operands(&self) -> Vec<&Value> {
+        match self {
+            Self::Add(lhs, rhs)
+            | Self::Sub(lhs, rhs)
+            | Self::Mul(lhs, rhs)
+            | Self::Div(lhs, rhs)
+            | Self::Rem(lhs, rhs)
+            | Self::Cmp(_, _, lhs, rhs)
+            | Self::And(lhs, rhs)
+            | Self::Or(lhs, rhs)
+            | Self::Xor(lhs, rhs)
+            | Self::Udiv(lhs, rhs)
+            | Self::Urem(lhs, rhs)
+            | Self::Sar(lhs, rhs)
+            | Self::Shr(lhs, rhs)
+            | Self::Shl(lhs, rhs)
+            | Self::Store(_, lhs, rhs)
+            | Self::Blit(lhs, rhs, _) => vec![lhs, rhs],
+            Self::Neg(val)
+            | Self::Copy(val)
+            | Self::Jnz(val, _, _)
+            | Self::Alloc4(val)
+            | Self::Alloc8(val)
+            | Self::Alloc16(val)
+            | Self::Load(_, val)
+            | Self::Cast(val)
+            | Self::Extsw(val)
+            | Self::Extuw(val)
+            | Self::Extsh(val)
+            | Self::Extuh(val)
+            | Self::Extsb(val)
+            | Self::Extub(val)
+            | Self::Exts(val)
+            | Self::Truncd(val)
+            | Self::Stosi(val)
+            | Self::Stoui(val)
+            | Self::Dtosi(val)
+            | Self::Dtoui(val)
+            | Self::Swtof(val)
+            | Self::Uwtof(val)
+            | Self::Sltof(val)
+            | Self::Ultof(val)
+            | Self::Vastart(val)
+            | Self::Vaarg(_, val) => vec![val],
+            Self::Ret(val) => val.iter().collect(),
+            Self::Call(callee, args, _) => std::iter::once(callee)
+                .chain(args.iter().map(|(_, val)| val))
+                .collect(),
+            Self::Phi(args) => args.iter().map(|(_, val)| val).collect(),
+            Self::Jmp(_) | Self::DbgFile(_) | Self::DbgLoc(..) | Self::Hlt => Vec::new(),
+        }
+    }
+
+    /// Mutable version of [`Instr::operands`]
+    pub(crate) fn operands_mut(&mut self) -> Vec<&mut Value> {
+        match self {
+            Self::Add(lhs, rhs)
+            | Self::Sub(lhs, rhs)
+            | Self::Mul(lhs, rhs)
+            | Self::Div(lhs, rhs)
+            | Self::Rem(lhs, rhs)
+            | Self::Cmp(_, _, lhs, rhs)
+            | Self::And(lhs, rhs)
+            | Self::Or(lhs, rhs)
+            | Self::Xor(lhs, rhs)
+            | Self::Udiv(lhs, rhs)
+            | Self::Urem(lhs, rhs)
+            | Self::Sar(lhs, rhs)
+            | Self::Shr(lhs, rhs)
+            | Self::Shl(lhs, rhs)
+            | Self::Store(_, lhs, rhs)
+            | Self::Blit(lhs, rhs, _) => vec![lhs, rhs],
+            Self::Neg(val)
+            | Self::Copy(val)
+            | Self::Jnz(val, _, _)
+            | Self::Alloc4(val)
+            | Self::Alloc8(val)
+            | Self::Alloc16(val)
+            | Self::Load(_, val)
+            | Self::Cast(val)
+            | Self::Extsw(val)
+            | Self::Extuw(val)
+            | Self::Extsh(val)
+            | Self::Extuh(val)
+            | Self::Extsb(val)
+            | Self::Extub(val)
+            | Self::Exts(val)
+            | Self::Truncd(val)
+            | Self::Stosi(val)
+            | Self::Stoui(val)
+            | Self::Dtosi(val)
+            | Self::Dtoui(val)
+            | Self::Swtof(val)
+            | Self::Uwtof(val)
+            | Self::Sltof(val)
+            | Self::Ultof(val)
+            | Self::Vastart(val)
+            | Self::Vaarg(_, val) => vec![val],
+            Self::Ret(val) => val.iter_mut().collect(),
+            Self::Call(callee, args, _) => std::iter::once(callee)
+                .chain(args.iter_mut().map(|(_, val)| val))
+                .collect(),
+            Self::Phi(args) => args.iter_mut().map(|(_, val)| val).collect(),
+            Self::Jmp(_) | Self::DbgFile(_) | Self::DbgLoc(..) | Self::Hlt => Vec::new(),
+        }
+    }
+
+    /// Returns the types the instruction refers to, not counting the type
+    /// its result is assigned with
+    pub(crate) fn types(&self) -> Vec<&Type> {
+        match self {
+            Self::Cmp(ty, ..) | Self::Store(ty, ..) | Self::Load(ty, _) | Self::Vaarg(ty, _) => {
+                vec![ty]
+            }
+            Self::Call(_, args, _) => args.iter().map(|(ty, _)| ty).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Mutable version of [`Instr::types`]
+    pub(crate) fn types_mut(&mut self) -> Vec<&mut Type> {
+        match self {
+            Self::Cmp(ty, ..) | Self::Store(ty, ..) | Self::Load(ty, _) | Self::Vaarg(ty, _) => {
+                vec![ty]
+            }
+            Self::Call(_, args, _) => args.iter_mut().map(|(ty, _)| ty).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the block labels the instruction refers to: jump targets and
+    /// the predecessors named by a `phi`
+    pub(crate) fn labels(&self) -> Vec<&str> {
+        match self {
+            Self::Jmp(label) => vec![label],
+            Self::Jnz(_, if_nonzero, if_zero) => vec![if_nonzero, if_zero],
+            Self::Phi(args) => args.iter().map(|(label, _)| label.as_str()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns true for instructions that end a block: jumps, returns and
+    /// `hlt`
+    pub(crate) fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Self::Jmp(_) | Self::Jnz(..) | Self::Ret(_) | Self::Hlt
+        )
+    }
+}
+
+/// Panics unless every entry of a parameter or argument list has a valid
+/// ABI type and the list holds at most one [`Type::Env`] entry, in first
+/// position.
+fn assert_abi_args(args: &[(Type, Value)]) {
+    for (i, (ty, val)) in args.iter().enumerate() {
+        if *ty == Type::Env {
+            assert!(
+                i == 0,
+                "env must be the first and only environment parameter or argument"
+            );
+        } else {
+            assert_abi_type(ty, val);
+        }
+    }
+}
+
+/// Panics if `ty` cannot appear in a function signature or as a call result
+fn assert_abi_type(ty: &Type, context: impl fmt::Display) {
+    assert!(
+        ty.is_abi(),
+        "invalid ABI type `{ty}` for {context}: use a base, signed/unsigned sub-word or aggregate type"
+    );
+}
+
 impl fmt::Display for Instr {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -356,6 +625,10 @@ impl fmt::Display for Instr {
                         Cmp::Ule => "ule",
                         Cmp::Ugt => "ugt",
                         Cmp::Uge => "uge",
+                        Cmp::Lt => "lt",
+                        Cmp::Le => "le",
+                        Cmp::Gt => "gt",
+                        Cmp::Ge => "ge",
                     },
                     ty,
                     lhs,
@@ -380,16 +653,31 @@ impl fmt::Display for Instr {
                 write!(f, "jnz {val}, @{if_nonzero}, @{if_zero}")
             }
             Self::Jmp(label) => write!(f, "jmp @{label}"),
-            Self::Call(name, args, opt_variadic_i) => {
+            Self::Call(callee, args, opt_variadic_i) => {
+                assert!(
+                    matches!(callee, Value::Global(_) | Value::Temporary(_)),
+                    "call target must be a global or a temporary, got {callee:?}"
+                );
+                assert_abi_args(args);
+                assert!(
+                    *opt_variadic_i != Some(0) || !matches!(args.first(), Some((Type::Env, _))),
+                    "env argument must come before the variadic marker"
+                );
+
                 let mut args_fmt = args
                     .iter()
                     .map(|(ty, temp)| format!("{ty} {temp}"))
                     .collect::<Vec<String>>();
                 if let Some(i) = *opt_variadic_i {
+                    assert!(
+                        i <= args.len() as u64,
+                        "variadic marker at index {i} is past the end of {} arguments",
+                        args.len()
+                    );
                     args_fmt.insert(i as usize, "...".to_string());
                 }
 
-                write!(f, "call ${}({})", name, args_fmt.join(", "),)
+                write!(f, "call {}({})", callee, args_fmt.join(", "),)
             }
             Self::Alloc4(size) => write!(f, "alloc4 {size}"),
             Self::Alloc8(size) => write!(f, "alloc8 {size}"),
@@ -515,6 +803,13 @@ pub enum Type {
 
     // Internal types
     Zero,
+    /// Marks the environment parameter of a function or the environment
+    /// argument of a call, printed as `env`.
+    ///
+    /// At most one may appear in a parameter or argument list, and it must
+    /// come first. The environment is passed in a dedicated register and is
+    /// typically used for closures.
+    Env,
 
     // Extended types
     Byte,
@@ -564,6 +859,40 @@ impl Type {
         Type::Aggregate(Arc::clone(td))
     }
 
+    /// Returns true for the base types `w`, `l`, `s` and `d`, the only types
+    /// temporaries can have
+    pub fn is_base(&self) -> bool {
+        matches!(self, Self::Word | Self::Long | Self::Single | Self::Double)
+    }
+
+    /// Returns true if the type may be used for function parameters, return
+    /// values, call arguments and call results.
+    ///
+    /// Since QBE 1.1 these accept base types, the signed and unsigned
+    /// sub-word types (`sb`, `ub`, `sh`, `uh`) and aggregates. The plain
+    /// `b` and `h` types are not accepted since they don't specify how to
+    /// extend the value.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::Type;
+    ///
+    /// assert!(Type::Word.is_abi());
+    /// assert!(Type::SignedByte.is_abi());
+    /// assert!(!Type::Byte.is_abi());
+    /// ```
+    pub fn is_abi(&self) -> bool {
+        match self {
+            Self::SignedByte
+            | Self::UnsignedByte
+            | Self::SignedHalfword
+            | Self::UnsignedHalfword
+            | Self::Aggregate(_) => true,
+            other => other.is_base(),
+        }
+    }
+
     /// Returns a C ABI type. Extended types are converted to closest base
     /// types
     pub fn into_abi(self) -> Self {
@@ -587,7 +916,7 @@ impl Type {
             | Self::Halfword
             | Self::SignedHalfword
             | Self::UnsignedHalfword => Self::Word,
-            Self::Aggregate(_) => Self::Long,
+            Self::Aggregate(_) | Self::Env => Self::Long,
             other => other,
         }
     }
@@ -598,7 +927,7 @@ impl Type {
             Self::Byte | Self::SignedByte | Self::UnsignedByte | Self::Zero => 1,
             Self::Halfword | Self::SignedHalfword | Self::UnsignedHalfword => 2,
             Self::Word | Self::Single => 4,
-            Self::Long | Self::Double => 8,
+            Self::Long | Self::Double | Self::Env => 8,
             Self::Aggregate(td) => {
                 fn size_of_items(s: &Type, items: &[(Type, usize)]) -> u64 {
                     let mut offset = 0;
@@ -685,20 +1014,98 @@ impl fmt::Display for Type {
             Self::Single => write!(f, "s"),
             Self::Double => write!(f, "d"),
             Self::Zero => write!(f, "z"),
+            Self::Env => write!(f, "env"),
             Self::Aggregate(td) => write!(f, ":{}", td.ident()),
         }
     }
 }
 
 /// QBE value that is accepted by instructions
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+///
+/// Floating-point constants are compared and hashed by their bit pattern, so
+/// `NaN` equals itself and `0.0` is distinct from `-0.0`.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::Value;
+///
+/// assert_eq!(Value::SingleConst(1.5).to_string(), "s_1.5");
+/// assert_eq!(Value::DoubleConst(2.75).to_string(), "d_2.75");
+/// assert_eq!(Value::from(f64::INFINITY).to_string(), "d_inf");
+/// ```
+#[derive(Debug, Clone)]
 pub enum Value {
     /// `%`-temporary
     Temporary(String),
     /// `$`-global
     Global(String),
+    /// `$`-global stored in thread-local storage, printed as `thread $name`.
+    ///
+    /// Use this to take the address of data defined with a thread-local
+    /// [`Linkage`].
+    ThreadLocal(String),
     /// Constant
     Const(u64),
+    /// Signed constant, printed as `-N` when negative
+    SignedConst(i64),
+    /// Single-precision floating-point constant (`s_`)
+    SingleConst(f32),
+    /// Double-precision floating-point constant (`d_`)
+    DoubleConst(f64),
+}
+
+/// Mirror of [`Value`] with floats replaced by their bit patterns, used to
+/// derive sound `Eq`, `Ord` and `Hash` implementations.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum ValueKey<'a> {
+    Temporary(&'a str),
+    Global(&'a str),
+    ThreadLocal(&'a str),
+    Const(u64),
+    SignedConst(i64),
+    SingleConst(u32),
+    DoubleConst(u64),
+}
+
+impl Value {
+    fn key(&self) -> ValueKey<'_> {
+        match self {
+            Self::Temporary(name) => ValueKey::Temporary(name),
+            Self::Global(name) => ValueKey::Global(name),
+            Self::ThreadLocal(name) => ValueKey::ThreadLocal(name),
+            Self::Const(value) => ValueKey::Const(*value),
+            Self::SignedConst(value) => ValueKey::SignedConst(*value),
+            Self::SingleConst(value) => ValueKey::SingleConst(value.to_bits()),
+            Self::DoubleConst(value) => ValueKey::DoubleConst(value.to_bits()),
+        }
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Value {}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl std::hash::Hash for Value {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
 }
 
 impl From<u64> for Value {
@@ -707,12 +1114,66 @@ impl From<u64> for Value {
     }
 }
 
+/// Non-negative values become [`Value::Const`], negative ones
+/// [`Value::SignedConst`].
+impl From<i64> for Value {
+    fn from(val: i64) -> Self {
+        match u64::try_from(val) {
+            Ok(val) => Value::Const(val),
+            Err(_) => Value::SignedConst(val),
+        }
+    }
+}
+
+/// Same as the `i64` conversion.
+impl From<i32> for Value {
+    fn from(val: i32) -> Self {
+        Value::from(i64::from(val))
+    }
+}
+
+impl From<f32> for Value {
+    fn from(val: f32) -> Self {
+        Value::SingleConst(val)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(val: f64) -> Self {
+        Value::DoubleConst(val)
+    }
+}
+
+/// Writes a floating-point literal in a form QBE reads back bit-exactly.
+///
+/// Finite values use Rust's shortest round-trip representation. Infinities
+/// are written as `inf`/`-inf` and NaNs as `nan`/`-nan`, which is what the
+/// `strtod` family accepts; NaN payloads are not preserved.
+fn fmt_float<T>(f: &mut fmt::Formatter, prefix: char, value: T) -> fmt::Result
+where
+    T: fmt::Debug + Into<f64> + Copy,
+{
+    let wide: f64 = value.into();
+    let sign = if wide.is_sign_negative() { "-" } else { "" };
+    if wide.is_nan() {
+        write!(f, "{prefix}_{sign}nan")
+    } else if wide.is_infinite() {
+        write!(f, "{prefix}_{sign}inf")
+    } else {
+        write!(f, "{prefix}_{value:?}")
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Temporary(name) => write!(f, "%{name}"),
             Self::Global(name) => write!(f, "${name}"),
+            Self::ThreadLocal(name) => write!(f, "thread ${name}"),
             Self::Const(value) => write!(f, "{value}"),
+            Self::SignedConst(value) => write!(f, "{value}"),
+            Self::SingleConst(value) => fmt_float(f, 's', *value),
+            Self::DoubleConst(value) => fmt_float(f, 'd', *value),
         }
     }
 }
@@ -762,18 +1223,182 @@ impl fmt::Display for DataDef {
 }
 
 /// Data definition item
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+///
+/// Floating-point constants are compared and hashed by their bit pattern,
+/// like in [`Value`].
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::DataItem;
+///
+/// // Strings and byte strings are escaped for the assembler
+/// assert_eq!(DataItem::Str("say \"hi\"\n".into()).to_string(), r#""say \"hi\"\n""#);
+/// assert_eq!(DataItem::from(&[0x00, 0xff][..]).to_string(), r#""\000\377""#);
+///
+/// // Several values sharing one type
+/// let table = DataItem::List(vec![1.into(), 2.into(), 3.into()]);
+/// assert_eq!(table.to_string(), "1 2 3");
+/// ```
+#[derive(Debug, Clone)]
 pub enum DataItem {
     /// Symbol and offset
     Symbol(String, Option<u64>),
-    /// String
+    /// Thread-local symbol and offset, printed as `thread $name`
+    ThreadLocalSymbol(String, Option<u64>),
+    /// String, escaped when printed
     Str(String),
+    /// Byte string, escaped when printed. Unlike [`DataItem::Str`], it may
+    /// hold arbitrary binary data.
+    Bytes(Vec<u8>),
     /// Constant
     Const(u64),
+    /// Signed constant, printed as `-N` when negative
+    SignedConst(i64),
+    /// Single-precision floating-point constant (`s_`)
+    SingleConst(f32),
+    /// Double-precision floating-point constant (`d_`)
+    DoubleConst(f64),
+    /// Several items sharing the same type, printed space-separated
+    /// (`b 1 2 3`). The items must not be [`DataItem::Zero`] or lists
+    /// themselves: formatting panics on them.
+    List(Vec<DataItem>),
     /// Zero-initialized data of specified size
     Zero(u64),
 }
 
+/// Mirror of [`DataItem`] with floats replaced by their bit patterns, used
+/// to derive sound `Eq`, `Ord` and `Hash` implementations.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum DataItemKey<'a> {
+    Symbol(&'a str, Option<u64>),
+    ThreadLocalSymbol(&'a str, Option<u64>),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+    Const(u64),
+    SignedConst(i64),
+    SingleConst(u32),
+    DoubleConst(u64),
+    List(&'a [DataItem]),
+    Zero(u64),
+}
+
+impl DataItem {
+    fn key(&self) -> DataItemKey<'_> {
+        match self {
+            Self::Symbol(name, offset) => DataItemKey::Symbol(name, *offset),
+            Self::ThreadLocalSymbol(name, offset) => DataItemKey::ThreadLocalSymbol(name, *offset),
+            Self::Str(string) => DataItemKey::Str(string),
+            Self::Bytes(bytes) => DataItemKey::Bytes(bytes),
+            Self::Const(val) => DataItemKey::Const(*val),
+            Self::SignedConst(val) => DataItemKey::SignedConst(*val),
+            Self::SingleConst(val) => DataItemKey::SingleConst(val.to_bits()),
+            Self::DoubleConst(val) => DataItemKey::DoubleConst(val.to_bits()),
+            Self::List(items) => DataItemKey::List(items),
+            Self::Zero(size) => DataItemKey::Zero(*size),
+        }
+    }
+}
+
+impl PartialEq for DataItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for DataItem {}
+
+impl PartialOrd for DataItem {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DataItem {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl std::hash::Hash for DataItem {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key().hash(state);
+    }
+}
+
+impl From<u64> for DataItem {
+    fn from(val: u64) -> Self {
+        DataItem::Const(val)
+    }
+}
+
+/// Non-negative values become [`DataItem::Const`], negative ones
+/// [`DataItem::SignedConst`].
+impl From<i64> for DataItem {
+    fn from(val: i64) -> Self {
+        match u64::try_from(val) {
+            Ok(val) => DataItem::Const(val),
+            Err(_) => DataItem::SignedConst(val),
+        }
+    }
+}
+
+/// Same as the `i64` conversion.
+impl From<i32> for DataItem {
+    fn from(val: i32) -> Self {
+        DataItem::from(i64::from(val))
+    }
+}
+
+impl From<f32> for DataItem {
+    fn from(val: f32) -> Self {
+        DataItem::SingleConst(val)
+    }
+}
+
+impl From<f64> for DataItem {
+    fn from(val: f64) -> Self {
+        DataItem::DoubleConst(val)
+    }
+}
+
+impl From<&str> for DataItem {
+    fn from(val: &str) -> Self {
+        DataItem::Str(val.to_string())
+    }
+}
+
+impl From<&[u8]> for DataItem {
+    fn from(val: &[u8]) -> Self {
+        DataItem::Bytes(val.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for DataItem {
+    fn from(val: Vec<u8>) -> Self {
+        DataItem::Bytes(val)
+    }
+}
+
+/// Writes `bytes` as a double-quoted string literal the assembler reads back
+/// verbatim. Printable ASCII is kept as is; quotes, backslashes and common
+/// control characters use C escapes and every other byte an octal escape.
+fn fmt_escaped(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    write!(f, "\"")?;
+    for &byte in bytes {
+        match byte {
+            b'"' => write!(f, "\\\"")?,
+            b'\\' => write!(f, "\\\\")?,
+            b'\n' => write!(f, "\\n")?,
+            b'\t' => write!(f, "\\t")?,
+            b'\r' => write!(f, "\\r")?,
+            b' '..=b'~' => write!(f, "{}", byte as char)?,
+            _ => write!(f, "\\{byte:03o}")?,
+        }
+    }
+    write!(f, "\"")
+}
+
 impl fmt::Display for DataItem {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -781,8 +1406,33 @@ impl fmt::Display for DataItem {
                 Some(off) => write!(f, "${name} +{off}"),
                 None => write!(f, "${name}"),
             },
-            Self::Str(string) => write!(f, "\"{string}\""),
+            Self::ThreadLocalSymbol(name, offset) => match offset {
+                Some(off) => write!(f, "thread ${name} +{off}"),
+                None => write!(f, "thread ${name}"),
+            },
+            Self::Str(string) => fmt_escaped(f, string.as_bytes()),
+            Self::Bytes(bytes) => fmt_escaped(f, bytes),
             Self::Const(val) => write!(f, "{val}"),
+            Self::SignedConst(val) => write!(f, "{val}"),
+            Self::SingleConst(val) => fmt_float(f, 's', *val),
+            Self::DoubleConst(val) => fmt_float(f, 'd', *val),
+            Self::List(items) => {
+                for item in items {
+                    assert!(
+                        !matches!(item, Self::Zero(_) | Self::List(_)),
+                        "list items must be symbols, strings or constants, got `{item}`"
+                    );
+                }
+                write!(
+                    f,
+                    "{}",
+                    items
+                        .iter()
+                        .map(|item| item.to_string())
+                        .collect::<Vec<String>>()
+                        .join(" ")
+                )
+            }
             Self::Zero(size) => write!(f, "z {size}"),
         }
     }
@@ -879,6 +1529,14 @@ impl fmt::Display for Statement {
                     matches!(temp, Value::Temporary(_)),
                     "assignment target must be a temporary, got {temp:?}"
                 );
+                if matches!(instr, Instr::Call(..)) {
+                    assert_abi_type(ty, format_args!("result of call assigned to {temp}"));
+                } else {
+                    assert!(
+                        ty.is_base(),
+                        "assignment to {temp} must use a base type, got `{ty}`"
+                    );
+                }
                 write!(f, "{temp} ={ty} {instr}")
             }
             Self::Volatile(instr) => write!(f, "{instr}"),
@@ -980,12 +1638,13 @@ impl Block {
         )));
     }
 
-    /// Returns true if the block's last instruction is a jump
+    /// Returns true if the block's last instruction ends it: a jump, `ret`
+    /// or `hlt`
     pub fn jumps(&self) -> bool {
         let last = self.items.last();
 
         if let Some(BlockItem::Statement(Statement::Volatile(instr))) = last {
-            matches!(instr, Instr::Ret(_) | Instr::Jmp(_) | Instr::Jnz(..))
+            instr.is_terminator()
         } else {
             false
         }
@@ -1063,9 +1722,16 @@ pub struct Function {
     pub name: String,
 
     /// Function arguments
+    ///
+    /// Parameter types must satisfy [`Type::is_abi`]. An environment
+    /// parameter is declared with [`Type::Env`] as the first entry.
     pub arguments: Vec<(Type, Value)>,
 
-    /// Return type
+    /// Whether the function accepts a variable number of arguments after
+    /// [`Function::arguments`], printed as a trailing `...`
+    pub variadic: bool,
+
+    /// Return type, which must satisfy [`Type::is_abi`]
     pub return_ty: Option<Type>,
 
     /// Labelled blocks
@@ -1084,6 +1750,7 @@ impl Function {
             linkage,
             name: name.into(),
             arguments,
+            variadic: false,
             return_ty,
             blocks: Vec::new(),
         }
@@ -1132,25 +1799,44 @@ impl Function {
             .expect("Last block must be present")
             .assign_instr(temp, ty, instr);
     }
+
+    /// Returns the signature of the function
+    pub fn decl(&self) -> FunctionDecl {
+        FunctionDecl {
+            name: self.name.clone(),
+            params: self.arguments.iter().map(|(ty, _)| ty.clone()).collect(),
+            variadic: self.variadic,
+            return_ty: self.return_ty.clone(),
+        }
+    }
 }
 
 impl fmt::Display for Function {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        assert_abi_args(&self.arguments);
+        if let Some(ty) = &self.return_ty {
+            assert_abi_type(ty, format_args!("return value of ${}", self.name));
+        }
+
         write!(f, "{}function", self.linkage)?;
         if let Some(ty) = &self.return_ty {
             write!(f, " {ty}")?;
         }
 
+        let mut args = self
+            .arguments
+            .iter()
+            .map(|(ty, temp)| format!("{ty} {temp}"))
+            .collect::<Vec<String>>();
+        if self.variadic {
+            args.push("...".to_string());
+        }
+
         writeln!(
             f,
             " ${name}({args}) {{",
             name = self.name,
-            args = self
-                .arguments
-                .iter()
-                .map(|(ty, temp)| format!("{ty} {temp}"))
-                .collect::<Vec<String>>()
-                .join(", "),
+            args = args.join(", "),
         )?;
 
         for blk in self.blocks.iter() {
@@ -1161,6 +1847,58 @@ impl fmt::Display for Function {
     }
 }
 
+/// Signature of a function, used to check calls to it.
+///
+/// Functions defined outside the module, such as `printf` from libc, are
+/// declared with [`Module::add_extern`]. QBE has no declarations, so they
+/// don't appear in the IL.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{FunctionDecl, Type};
+///
+/// // int printf(const char *fmt, ...)
+/// let printf = FunctionDecl::new("printf", vec![Type::Long], Some(Type::Word)).variadic();
+/// assert_eq!(printf.params.len(), 1);
+/// assert!(printf.variadic);
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct FunctionDecl {
+    /// Function name
+    pub name: String,
+
+    /// Parameter types. An environment parameter is declared with
+    /// [`Type::Env`] as the first entry.
+    pub params: Vec<Type>,
+
+    /// Whether the function accepts a variable number of arguments after
+    /// [`FunctionDecl::params`]. Calls to it must then put the variadic
+    /// marker of [`Instr::Call`] at index `params.len()`.
+    pub variadic: bool,
+
+    /// Return type
+    pub return_ty: Option<Type>,
+}
+
+impl FunctionDecl {
+    /// Creates the signature of a function that isn't variadic
+    pub fn new(name: impl Into<String>, params: Vec<Type>, return_ty: Option<Type>) -> Self {
+        FunctionDecl {
+            name: name.into(),
+            params,
+            variadic: false,
+            return_ty,
+        }
+    }
+
+    /// Marks the function as variadic
+    pub fn variadic(mut self) -> Self {
+        self.variadic = true;
+        self
+    }
+}
+
 /// Linkage of a function or data defintion (e.g. section and
 /// private/public status)
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
@@ -1268,6 +2006,49 @@ impl fmt::Display for Linkage {
     }
 }
 
+/// Position of an item within a [`Module`], used in error reports
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Location {
+    /// A type definition, by identifier
+    Type(String),
+    /// A data definition, by name
+    Data(String),
+    /// A function, optionally narrowed down to a block label and the index of
+    /// an item within [`Block::items`]
+    Function {
+        /// Name of the function
+        name: String,
+        /// Label of the block, or `None` for the function as a whole, such
+        /// as its signature
+        block: Option<String>,
+        /// Index of the item within the block, or `None` for the block as a
+        /// whole, such as its label
+        item: Option<usize>,
+    },
+    /// An external function declaration in [`Module::externs`], by name
+    Extern(String),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Type(ident) => write!(f, "type :{ident}"),
+            Self::Data(name) => write!(f, "data ${name}"),
+            Self::Function { name, block, item } => {
+                write!(f, "function ${name}")?;
+                if let Some(block) = block {
+                    write!(f, ", block @{block}")?;
+                }
+                if let Some(item) = item {
+                    write!(f, ", item {item}")?;
+                }
+                Ok(())
+            }
+            Self::Extern(name) => write!(f, "extern ${name}"),
+        }
+    }
+}
+
 /// A complete QBE IL module.
 ///
 /// A module contains all the functions, data definitions, and type definitions
@@ -1309,7 +2090,7 @@ impl fmt::Display for Linkage {
 ///     Value::Temporary("r".to_string()),
 ///     Type::Word,
 ///     Instr::Call(
-///         "printf".to_string(),
+///         Value::Global("printf".to_string()),
 ///         vec![(Type::Long, Value::Global("hello".to_string()))],
 ///         None,
 ///     ),
@@ -1326,6 +2107,9 @@ pub struct Module {
     pub functions: Vec<Function>,
     pub types: Vec<Arc<TypeDef>>,
     pub data: Vec<DataDef>,
+    /// Signatures of functions defined outside the module, which are not
+    /// printed. See [`Module::check_calls`].
+    pub externs: Vec<FunctionDecl>,
 }
 
 impl Module {
@@ -1335,6 +2119,7 @@ impl Module {
             functions: Vec::new(),
             types: Vec::new(),
             data: Vec::new(),
+            externs: Vec::new(),
         }
     }
 
@@ -1345,7 +2130,12 @@ impl Module {
         self.functions.last_mut().unwrap()
     }
 
-    /// Adds a type definition to the module
+    /// Adds a type definition to the module.
+    ///
+    /// Types referenced by the module are emitted even if they were not
+    /// added, and are ordered so each type is defined before it is used.
+    /// Adding a type fixes its position relative to unrelated types and
+    /// emits it even if nothing refers to it.
     pub fn add_type(&mut self, def: Arc<TypeDef>) {
         self.types.push(def);
     }
@@ -1355,11 +2145,26 @@ impl Module {
         self.data.push(data);
         self.data.last_mut().unwrap()
     }
+
+    /// Declares a function defined outside the module, so calls to it can
+    /// be checked
+    pub fn add_extern(&mut self, decl: FunctionDecl) {
+        self.externs.push(decl);
+    }
 }
 
+/// Types are written in the order given by [`Module::sorted_types`].
+///
+/// # Panics
+///
+/// Panics if [`Module::sorted_types`] fails. [`Module::write_to`] returns an
+/// error instead.
 impl fmt::Display for Module {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for ty in self.types.iter() {
+        let types = self
+            .sorted_types()
+            .unwrap_or_else(|errors| panic!("{}", errors[0]));
+        for ty in types.iter() {
             writeln!(f, "{ty}")?;
         }
         for func in self.functions.iter() {
@@ -0,0 +1,1265 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Parsing QBE IL text back into a [`Module`].
+
+use crate::{
+    Block, BlockItem, Cmp, DataDef, DataItem, Function, Instr, Linkage, Module, Statement, Type,
+    TypeDef, Value,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests;
+
+/// Kind of a lexical token in QBE IL, see [`SyntaxToken`](crate::SyntaxToken)
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum TokenKind {
+    /// Spaces, tabs and carriage returns
+    Whitespace,
+    Newline,
+    /// `#` up to the end of the line
+    Comment,
+    /// Keywords, instruction mnemonics and type names
+    Ident,
+    /// `%name`
+    Temporary,
+    /// `$name`
+    Global,
+    /// `@name`
+    Label,
+    /// `:name`
+    TypeName,
+    Integer,
+    /// `s_1.5` or `d_1.5`
+    Float,
+    /// A double-quoted string, with the quotes
+    Str,
+    Equals,
+    Comma,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Plus,
+    Ellipsis,
+    /// A string missing its closing quote
+    UnterminatedStr,
+    /// A character that doesn't start any token
+    Unknown,
+    /// End of input. Never part of a syntax tree.
+    Eof,
+}
+
+/// A lexical token, borrowing its text from the source
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset of the token in the source
+    pub offset: usize,
+}
+
+impl Token<'_> {
+    /// Returns the name of a sigil-prefixed token such as `%name`
+    fn name(&self) -> &str {
+        &self.text[1..]
+    }
+
+    pub(crate) fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Ident && self.text == keyword
+    }
+
+    /// Describes the token for error messages
+    fn describe(&self) -> String {
+        match self.kind {
+            TokenKind::Newline => "end of line".into(),
+            TokenKind::Eof => "end of input".into(),
+            _ => format!("`{}`", self.text),
+        }
+    }
+}
+
+/// Returns true if `c` can start an identifier after its sigil
+pub(crate) fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_' || c == b'.'
+}
+
+/// Returns true if `c` can continue an identifier
+pub(crate) fn is_ident_char(c: u8) -> bool {
+    is_ident_start(c) || c.is_ascii_digit() || c == b'$'
+}
+
+/// Splits QBE IL into tokens, including whitespace, newlines and comments.
+///
+/// The tokens cover the whole source, and the last one is always
+/// [`TokenKind::Eof`].
+pub(crate) fn lex(src: &str) -> Vec<Token<'_>> {
+    let bytes = src.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let start = i;
+        let kind = match bytes[i] {
+            b'\n' => {
+                i += 1;
+                TokenKind::Newline
+            }
+            b' ' | b'\t' | b'\r' => {
+                while i < bytes.len() && matches!(bytes[i], b' ' | b'\t' | b'\r') {
+                    i += 1;
+                }
+                TokenKind::Whitespace
+            }
+            b'#' => {
+                while i < bytes.len() && bytes[i] != b'\n' {
+                    i += 1;
+                }
+                TokenKind::Comment
+            }
+            b'"' => {
+                i += 1;
+                let mut escaped = false;
+                loop {
+                    match bytes.get(i) {
+                        None | Some(b'\n') => break TokenKind::UnterminatedStr,
+                        Some(b'"') if !escaped => {
+                            i += 1;
+                            break TokenKind::Str;
+                        }
+                        Some(&c) => {
+                            escaped = c == b'\\' && !escaped;
+                            i += 1;
+                        }
+                    }
+                }
+            }
+            sigil @ (b'%' | b'$' | b'@' | b':') => {
+                i += 1;
+                if i < bytes.len() && is_ident_start(bytes[i]) {
+                    while i < bytes.len() && is_ident_char(bytes[i]) {
+                        i += 1;
+                    }
+                }
+                match sigil {
+                    _ if i == start + 1 => TokenKind::Unknown,
+                    b'%' => TokenKind::Temporary,
+                    b'$' => TokenKind::Global,
+                    b'@' => TokenKind::Label,
+                    _ => TokenKind::TypeName,
+                }
+            }
+            b'0'..=b'9' | b'-' => {
+                i += 1;
+                while i < bytes.len() && bytes[i].is_ascii_digit() {
+                    i += 1;
+                }
+                if &src[start..i] == "-" {
+                    TokenKind::Unknown
+                } else {
+                    TokenKind::Integer
+                }
+            }
+            b'.' if src[i..].starts_with("...") => {
+                i += 3;
+                TokenKind::Ellipsis
+            }
+            b's' | b'd' if bytes.get(i + 1) == Some(&b'_') => {
+                i += 2;
+                while i < bytes.len()
+                    && (is_ident_char(bytes[i]) || matches!(bytes[i], b'+' | b'-'))
+                {
+                    i += 1;
+                }
+                TokenKind::Float
+            }
+            c if is_ident_start(c) => {
+                while i < bytes.len() && is_ident_char(bytes[i]) {
+                    i += 1;
+                }
+                TokenKind::Ident
+            }
+            c @ (b'=' | b',' | b'(' | b')' | b'{' | b'}' | b'+') => {
+                i += 1;
+                match c {
+                    b'=' => TokenKind::Equals,
+                    b',' => TokenKind::Comma,
+                    b'(' => TokenKind::LParen,
+                    b')' => TokenKind::RParen,
+                    b'{' => TokenKind::LBrace,
+                    b'}' => TokenKind::RBrace,
+                    _ => TokenKind::Plus,
+                }
+            }
+            _ => {
+                i += src[i..].chars().next().map_or(1, char::len_utf8);
+                TokenKind::Unknown
+            }
+        };
+        tokens.push(Token {
+            kind,
+            text: &src[start..i],
+            offset: start,
+        });
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        text: "",
+        offset: src.len(),
+    });
+    tokens
+}
+
+/// Resolves the escape sequences of a string literal, without its quotes
+pub(crate) fn unescape(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 == bytes.len() {
+            out.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        i += 1;
+        match bytes[i] {
+            b'n' => out.push(b'\n'),
+            b't' => out.push(b'\t'),
+            b'r' => out.push(b'\r'),
+            b'0'..=b'7' => {
+                let mut val: u32 = 0;
+                let end = (i + 3).min(bytes.len());
+                while i < end && (b'0'..=b'7').contains(&bytes[i]) {
+                    val = val * 8 + u32::from(bytes[i] - b'0');
+                    i += 1;
+                }
+                out.push(val as u8);
+                continue;
+            }
+            other => out.push(other),
+        }
+        i += 1;
+    }
+
+    out
+}
+
+/// A byte range in parsed source text
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Span {
+    /// Byte offset of the first character
+    pub start: usize,
+    /// Byte offset one past the last character
+    pub end: usize,
+}
+
+/// An error encountered while parsing QBE IL
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ParseError {
+    /// Name of the parsed file, if one was given to [`Module::parse_named`]
+    pub file: Option<String>,
+    /// Source range of the offending token
+    pub span: Span,
+    /// 1-based line of the offending token
+    pub line: usize,
+    /// 1-based column of the offending token, in characters
+    pub column: usize,
+    /// Descriptions of the tokens that would have been accepted, e.g. ``"`,`"``
+    /// or `"value"`. Empty if the token was well-formed but meaningless, such
+    /// as an unknown instruction.
+    pub expected: Vec<String>,
+    /// Description of the offending token, e.g. ``"`}`"`` or `"end of line"`
+    pub found: String,
+    /// What went wrong
+    pub message: String,
+}
+
+impl ParseError {
+    /// Renders the error as a snippet of `src` with carets under the
+    /// offending token.
+    ///
+    /// `src` must be the text that was parsed.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::Module;
+    ///
+    /// let src = "function $f() {\n@start\n    ret %x,\n}\n";
+    /// let errors = Module::parse_named("f.ssa", src).unwrap_err();
+    /// assert_eq!(
+    ///     errors.errors[0].render(src),
+    ///     "\
+    /// error: expected end of line, found `,`
+    ///  --> f.ssa:3:11
+    ///   |
+    /// 3 |     ret %x,
+    ///   |           ^"
+    /// );
+    /// ```
+    pub fn render(&self, src: &str) -> String {
+        let line_start = src[..self.span.start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = src[line_start..]
+            .find('\n')
+            .map_or(src.len(), |i| line_start + i);
+        let text = &src[line_start..line_end];
+
+        // Keep tabs so the carets line up with the source line
+        let indent: String = src[line_start..self.span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = src[self.span.start..self.span.end.clamp(self.span.start, line_end)]
+            .chars()
+            .count()
+            .max(1);
+
+        let line = self.line.to_string();
+        let gutter = " ".repeat(line.len());
+        let file = self
+            .file
+            .as_deref()
+            .map_or(String::new(), |f| format!("{f}:"));
+        format!(
+            "error: {message}\n{gutter}--> {file}{line}:{column}\n{gutter} |\n{line} | {text}\n{gutter} | {indent}{carets}",
+            message = self.message,
+            column = self.column,
+            carets = "^".repeat(width),
+        )
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}:")?;
+        }
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Every error found in a source text, in source order
+///
+/// Its [`Display`](fmt::Display) implementation renders each error as a
+/// snippet, see [`ParseError::render`].
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ParseErrors {
+    pub errors: Vec<ParseError>,
+    source: String,
+}
+
+impl ParseErrors {
+    /// Returns the text that failed to parse
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+}
+
+impl fmt::Display for ParseErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rendered = self
+            .errors
+            .iter()
+            .map(|err| err.render(&self.source))
+            .collect::<Vec<String>>();
+        write!(f, "{}", rendered.join("\n\n"))
+    }
+}
+
+impl std::error::Error for ParseErrors {}
+
+/// Parses QBE IL into a [`Module`].
+///
+/// Everything the [`Display`](fmt::Display) implementations print is accepted,
+/// so `module.to_string().parse::<Module>()` gives back an equal module,
+/// except for [`Module::types`]: the output defines every aggregate type
+/// the module references, in dependency order, so the parsed module's
+/// `types` is the original's [`Module::sorted_types`]. Where two values
+/// print the same, the parser picks one: non-negative integers become
+/// [`Value::Const`], `storeb`/`storeh` use [`Type::Byte`] and
+/// [`Type::Halfword`], and strings that aren't valid UTF-8 become
+/// [`DataItem::Bytes`].
+///
+/// Comments inside blocks become [`BlockItem::Comment`]; other comments are
+/// skipped. Aggregate types must be defined before they are referenced, as
+/// QBE requires.
+///
+/// After an error, parsing resumes at the next block or definition, so all
+/// problems are reported at once.
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Instr, Module, Value};
+///
+/// let module: Module = "
+/// export function w $main() {
+/// @start
+///     ret 0
+/// }
+/// "
+/// .parse()
+/// .unwrap();
+///
+/// let main = &module.functions[0];
+/// assert_eq!(main.name, "main");
+/// assert_eq!(main.blocks[0].label, "start");
+/// assert_eq!(module.to_string().parse::<Module>().unwrap(), module);
+/// ```
+impl FromStr for Module {
+    type Err = ParseErrors;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        Parser::new(src, None).parse_module()
+    }
+}
+
+impl Module {
+    /// Parses QBE IL like [`Module::from_str`], naming `file` as the source
+    /// in every error.
+    pub fn parse_named(file: impl Into<String>, src: &str) -> Result<Module, ParseErrors> {
+        Parser::new(src, Some(file.into())).parse_module()
+    }
+}
+
+/// Returns true for keywords that start a type, data or function definition
+pub(crate) fn is_item_start(tok: Token) -> bool {
+    tok.kind == TokenKind::Ident
+        && matches!(
+            tok.text,
+            "type" | "data" | "function" | "export" | "thread" | "section"
+        )
+}
+
+/// Parse errors are boxed while propagating to keep results small
+type PResult<T> = Result<T, Box<ParseError>>;
+
+struct Parser<'a> {
+    src: &'a str,
+    file: Option<String>,
+    /// Tokens without whitespace
+    tokens: Vec<Token<'a>>,
+    pos: usize,
+    /// Aggregate types defined so far
+    types: HashMap<String, Arc<TypeDef>>,
+    /// Errors recovered from so far
+    errors: Vec<ParseError>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str, file: Option<String>) -> Self {
+        let tokens = lex(src)
+            .into_iter()
+            .filter(|tok| tok.kind != TokenKind::Whitespace)
+            .collect();
+        Self {
+            src,
+            file,
+            tokens,
+            pos: 0,
+            types: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    fn peek(&self) -> Token<'a> {
+        self.tokens[self.pos]
+    }
+
+    fn peek_nth(&self, n: usize) -> Token<'a> {
+        self.tokens[(self.pos + n).min(self.tokens.len() - 1)]
+    }
+
+    fn next(&mut self) -> Token<'a> {
+        let tok = self.peek();
+        if tok.kind != TokenKind::Eof {
+            self.pos += 1;
+        }
+        tok
+    }
+
+    /// Returns true if the next token is the first on its line
+    fn at_line_start(&self) -> bool {
+        self.pos == 0 || self.tokens[self.pos - 1].kind == TokenKind::Newline
+    }
+
+    /// Consumes the next token if it has the given kind
+    fn eat(&mut self, kind: TokenKind) -> bool {
+        if self.peek().kind == kind {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consumes the next token if it is the given keyword
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_keyword(keyword) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind, what: &str) -> PResult<Token<'a>> {
+        let tok = self.peek();
+        if tok.kind == kind {
+            Ok(self.next())
+        } else {
+            Err(self.unexpected(tok, &[what]))
+        }
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> PResult<()> {
+        if self.eat_keyword(keyword) {
+            Ok(())
+        } else {
+            Err(self.unexpected(self.peek(), &[&format!("`{keyword}`")]))
+        }
+    }
+
+    /// Skips newlines and comments
+    fn skip_newlines(&mut self) {
+        while matches!(self.peek().kind, TokenKind::Newline | TokenKind::Comment) {
+            self.next();
+        }
+    }
+
+    /// Skips to the next line that starts a definition
+    fn recover_item(&mut self, start: usize) {
+        if self.pos == start {
+            self.next();
+        }
+        while self.peek().kind != TokenKind::Eof
+            && !(self.at_line_start() && is_item_start(self.peek()))
+        {
+            self.next();
+        }
+    }
+
+    /// Skips to the next block label or the end of the function
+    fn recover_block(&mut self) {
+        loop {
+            let tok = self.peek();
+            let boundary = match tok.kind {
+                TokenKind::Eof | TokenKind::RBrace => true,
+                TokenKind::Label => self.at_line_start(),
+                _ => self.at_line_start() && is_item_start(tok),
+            };
+            if boundary {
+                return;
+            }
+            self.next();
+        }
+    }
+
+    fn error(&self, tok: Token, message: impl Into<String>) -> Box<ParseError> {
+        let before = &self.src[..tok.offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Box::new(ParseError {
+            file: self.file.clone(),
+            span: Span {
+                start: tok.offset,
+                end: tok.offset + tok.text.len(),
+            },
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            expected: Vec::new(),
+            found: tok.describe(),
+            message: message.into(),
+        })
+    }
+
+    fn unexpected(&self, tok: Token, expected: &[&str]) -> Box<ParseError> {
+        let message = match tok.kind {
+            TokenKind::UnterminatedStr => "unterminated string".to_string(),
+            _ => format!(
+                "expected {}, found {}",
+                alternatives(expected),
+                tok.describe()
+            ),
+        };
+        Box::new(ParseError {
+            expected: expected.iter().map(|s| s.to_string()).collect(),
+            ..*self.error(tok, message)
+        })
+    }
+
+    fn parse_module(mut self) -> Result<Module, ParseErrors> {
+        let mut module = Module::new();
+
+        loop {
+            self.skip_newlines();
+            if self.peek().kind == TokenKind::Eof {
+                break;
+            }
+
+            let start = self.pos;
+            if let Err(err) = self.parse_item(&mut module) {
+                self.errors.push(*err);
+                self.recover_item(start);
+            }
+        }
+
+        if self.errors.is_empty() {
+            Ok(module)
+        } else {
+            Err(ParseErrors {
+                errors: self.errors,
+                source: self.src.to_string(),
+            })
+        }
+    }
+
+    fn parse_item(&mut self, module: &mut Module) -> PResult<()> {
+        if self.peek().is_keyword("type") {
+            let def = Arc::new(self.parse_typedef()?);
+            self.types.insert(def.ident().to_string(), Arc::clone(&def));
+            module.add_type(def);
+            return Ok(());
+        }
+
+        let linkage = self.parse_linkage()?;
+        if self.eat_keyword("function") {
+            let func = self.parse_function(linkage)?;
+            module.add_function(func);
+        } else if self.eat_keyword("data") {
+            let data = self.parse_data(linkage)?;
+            module.add_data(data);
+        } else {
+            return Err(self.unexpected(self.peek(), &["`function`", "`data`", "`type`"]));
+        }
+        Ok(())
+    }
+
+    fn parse_linkage(&mut self) -> PResult<Linkage> {
+        let mut linkage = Linkage::private();
+        loop {
+            if self.eat_keyword("export") {
+                linkage.exported = true;
+            } else if self.eat_keyword("thread") {
+                linkage.thread_local = true;
+            } else if self.eat_keyword("section") {
+                let section = self.expect(TokenKind::Str, "section name")?;
+                linkage.section = Some(raw_string(section));
+                if self.peek().kind == TokenKind::Str {
+                    linkage.secflags = Some(raw_string(self.next()));
+                }
+            } else {
+                return Ok(linkage);
+            }
+        }
+    }
+
+    fn parse_type(&mut self) -> PResult<Type> {
+        let tok = self.peek();
+        let ty = match tok.kind {
+            TokenKind::TypeName => match self.types.get(tok.name()) {
+                Some(def) => Type::Aggregate(Arc::clone(def)),
+                None => return Err(self.error(tok, format!("undefined type `{}`", tok.text))),
+            },
+            TokenKind::Ident => match type_from_name(tok.text) {
+                Some(ty) => ty,
+                None => return Err(self.unexpected(tok, &["type"])),
+            },
+            _ => return Err(self.unexpected(tok, &["type"])),
+        };
+        self.next();
+        Ok(ty)
+    }
+
+    fn parse_integer<T: FromStr>(&mut self) -> PResult<T> {
+        let tok = self.expect(TokenKind::Integer, "integer")?;
+        tok.text
+            .parse()
+            .map_err(|_| self.error(tok, format!("integer `{}` out of range", tok.text)))
+    }
+
+    fn parse_align(&mut self) -> PResult<Option<u64>> {
+        if self.eat_keyword("align") {
+            Ok(Some(self.parse_integer()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_typedef(&mut self) -> PResult<TypeDef> {
+        self.expect_keyword("type")?;
+        let ident = self
+            .expect(TokenKind::TypeName, "type name")?
+            .name()
+            .to_string();
+        self.expect(TokenKind::Equals, "`=`")?;
+        let align = self.parse_align()?;
+        self.expect(TokenKind::LBrace, "`{`")?;
+        self.skip_newlines();
+
+        let def = match self.peek().kind {
+            TokenKind::Integer => {
+                let tok = self.peek();
+                let size = self.parse_integer()?;
+                let align =
+                    align.ok_or_else(|| self.error(tok, "opaque type requires an alignment"))?;
+                TypeDef::Opaque { ident, align, size }
+            }
+            TokenKind::LBrace => {
+                let mut variations = Vec::new();
+                while self.eat(TokenKind::LBrace) {
+                    variations.push(self.parse_typedef_items()?);
+                    self.skip_newlines();
+                }
+                TypeDef::Union {
+                    ident,
+                    align,
+                    variations,
+                }
+            }
+            _ => {
+                let items = self.parse_typedef_items()?;
+                return Ok(TypeDef::Regular {
+                    ident,
+                    align,
+                    items,
+                });
+            }
+        };
+
+        self.skip_newlines();
+        self.expect(TokenKind::RBrace, "`}`")?;
+        Ok(def)
+    }
+
+    /// Parses `type [count], ...` up to and including the closing brace
+    fn parse_typedef_items(&mut self) -> PResult<Vec<(Type, usize)>> {
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.eat(TokenKind::RBrace) {
+                return Ok(items);
+            }
+            let ty = self.parse_type()?;
+            let count = if self.peek().kind == TokenKind::Integer {
+                self.parse_integer()?
+            } else {
+                1
+            };
+            items.push((ty, count));
+
+            self.skip_newlines();
+            if !self.eat(TokenKind::Comma) && self.peek().kind != TokenKind::RBrace {
+                return Err(self.unexpected(self.peek(), &["`,`", "`}`"]));
+            }
+        }
+    }
+
+    fn parse_data(&mut self, linkage: Linkage) -> PResult<DataDef> {
+        let name = self
+            .expect(TokenKind::Global, "data name")?
+            .name()
+            .to_string();
+        self.expect(TokenKind::Equals, "`=`")?;
+        let align = self.parse_align()?;
+        self.expect(TokenKind::LBrace, "`{`")?;
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.eat(TokenKind::RBrace) {
+                break;
+            }
+            items.push(self.parse_data_group()?);
+
+            self.skip_newlines();
+            if !self.eat(TokenKind::Comma) && self.peek().kind != TokenKind::RBrace {
+                return Err(self.unexpected(self.peek(), &["`,`", "`}`"]));
+            }
+        }
+
+        Ok(DataDef {
+            linkage,
+            name,
+            align,
+            items,
+        })
+    }
+
+    /// Parses a type followed by one or more data items
+    fn parse_data_group(&mut self) -> PResult<(Type, DataItem)> {
+        let ty = self.parse_type()?;
+        if ty == Type::Zero {
+            return Ok((ty, DataItem::Const(self.parse_integer()?)));
+        }
+        if self.eat_keyword("z") {
+            return Ok((ty, DataItem::Zero(self.parse_integer()?)));
+        }
+
+        let mut items = vec![self.parse_data_item()?];
+        while !matches!(
+            self.peek().kind,
+            TokenKind::Comma | TokenKind::RBrace | TokenKind::Newline | TokenKind::Comment
+        ) {
+            items.push(self.parse_data_item()?);
+        }
+
+        let item = if items.len() == 1 {
+            items.remove(0)
+        } else {
+            DataItem::List(items)
+        };
+        Ok((ty, item))
+    }
+
+    fn parse_data_item(&mut self) -> PResult<DataItem> {
+        let tok = self.peek();
+        let thread_local = tok.is_keyword("thread");
+        if thread_local || tok.kind == TokenKind::Global {
+            if thread_local {
+                self.next();
+            }
+            let name = self.expect(TokenKind::Global, "symbol")?.name().to_string();
+            let offset = if self.eat(TokenKind::Plus) {
+                Some(self.parse_integer()?)
+            } else {
+                None
+            };
+            return Ok(if thread_local {
+                DataItem::ThreadLocalSymbol(name, offset)
+            } else {
+                DataItem::Symbol(name, offset)
+            });
+        }
+
+        match tok.kind {
+            TokenKind::Str => {
+                self.next();
+                let bytes = unescape(&raw_string(tok));
+                Ok(match String::from_utf8(bytes) {
+                    Ok(string) => DataItem::Str(string),
+                    Err(err) => DataItem::Bytes(err.into_bytes()),
+                })
+            }
+            TokenKind::Integer | TokenKind::Float => match self.parse_value()? {
+                Value::Const(val) => Ok(DataItem::Const(val)),
+                Value::SignedConst(val) => Ok(DataItem::SignedConst(val)),
+                Value::SingleConst(val) => Ok(DataItem::SingleConst(val)),
+                Value::DoubleConst(val) => Ok(DataItem::DoubleConst(val)),
+                _ => unreachable!("constant tokens parse to constants"),
+            },
+            _ => Err(self.unexpected(tok, &["data item"])),
+        }
+    }
+
+    fn parse_function(&mut self, linkage: Linkage) -> PResult<Function> {
+        let return_ty = if self.peek().kind == TokenKind::Global {
+            None
+        } else {
+            Some(self.parse_type()?)
+        };
+        let name = self
+            .expect(TokenKind::Global, "function name")?
+            .name()
+            .to_string();
+
+        self.expect(TokenKind::LParen, "`(`")?;
+        let mut arguments = Vec::new();
+        let mut variadic = false;
+        if !self.eat(TokenKind::RParen) {
+            loop {
+                if self.eat(TokenKind::Ellipsis) {
+                    variadic = true;
+                    self.expect(TokenKind::RParen, "`)`")?;
+                    break;
+                }
+                let ty = self.parse_type()?;
+                let temp = self.expect(TokenKind::Temporary, "parameter")?;
+                arguments.push((ty, Value::Temporary(temp.name().to_string())));
+                if self.eat(TokenKind::RParen) {
+                    break;
+                }
+                if !self.eat(TokenKind::Comma) {
+                    return Err(self.unexpected(self.peek(), &["`,`", "`)`"]));
+                }
+            }
+        }
+        self.expect(TokenKind::LBrace, "`{`")?;
+
+        let mut blocks: Vec<Block> = Vec::new();
+        loop {
+            let tok = self.peek();
+            match tok.kind {
+                TokenKind::RBrace => {
+                    self.next();
+                    break;
+                }
+                TokenKind::Newline => {
+                    self.next();
+                }
+                TokenKind::Comment => {
+                    self.next();
+                    if let Some(block) = blocks.last_mut() {
+                        block.add_comment(comment_text(tok));
+                    }
+                }
+                TokenKind::Label => {
+                    self.next();
+                    blocks.push(Block {
+                        label: tok.name().to_string(),
+                        items: Vec::new(),
+                    });
+                }
+                TokenKind::Eof => return Err(self.unexpected(tok, &["`}`"])),
+                // The closing brace is missing
+                _ if is_item_start(tok) => return Err(self.unexpected(tok, &["`}`"])),
+                _ => {
+                    let Some(block) = blocks.last_mut() else {
+                        self.errors.push(*self.unexpected(tok, &["block label"]));
+                        self.recover_block();
+                        continue;
+                    };
+                    match self.parse_statement_line() {
+                        Ok(statement) => block.items.push(BlockItem::Statement(statement)),
+                        Err(err) => {
+                            self.errors.push(*err);
+                            self.recover_block();
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(Function {
+            linkage,
+            name,
+            arguments,
+            variadic,
+            return_ty,
+            blocks,
+        })
+    }
+
+    /// Parses a statement that must be followed by the end of its line
+    fn parse_statement_line(&mut self) -> PResult<Statement> {
+        let statement = self.parse_statement()?;
+        match self.peek().kind {
+            TokenKind::Newline | TokenKind::Comment | TokenKind::RBrace => Ok(statement),
+            _ => Err(self.unexpected(self.peek(), &["end of line"])),
+        }
+    }
+
+    fn parse_statement(&mut self) -> PResult<Statement> {
+        if self.peek().kind == TokenKind::Temporary && self.peek_nth(1).kind == TokenKind::Equals {
+            let temp = Value::Temporary(self.next().name().to_string());
+            self.next();
+            let ty = self.parse_type()?;
+            let instr = self.parse_instr()?;
+            Ok(Statement::Assign(temp, ty, instr))
+        } else {
+            Ok(Statement::Volatile(self.parse_instr()?))
+        }
+    }
+
+    fn parse_value(&mut self) -> PResult<Value> {
+        let tok = self.peek();
+        match tok.kind {
+            TokenKind::Temporary => {
+                self.next();
+                Ok(Value::Temporary(tok.name().to_string()))
+            }
+            TokenKind::Global => {
+                self.next();
+                Ok(Value::Global(tok.name().to_string()))
+            }
+            TokenKind::Ident if tok.text == "thread" => {
+                self.next();
+                let name = self.expect(TokenKind::Global, "thread-local symbol")?;
+                Ok(Value::ThreadLocal(name.name().to_string()))
+            }
+            TokenKind::Integer if tok.text.starts_with('-') => {
+                Ok(Value::from(self.parse_integer::<i64>()?))
+            }
+            TokenKind::Integer => Ok(Value::Const(self.parse_integer()?)),
+            TokenKind::Float => {
+                self.next();
+                let literal = &tok.text[2..];
+                let invalid = || self.error(tok, format!("invalid float `{}`", tok.text));
+                if tok.text.starts_with('s') {
+                    Ok(Value::SingleConst(literal.parse().map_err(|_| invalid())?))
+                } else {
+                    Ok(Value::DoubleConst(literal.parse().map_err(|_| invalid())?))
+                }
+            }
+            _ => Err(self.unexpected(tok, &["value"])),
+        }
+    }
+
+    fn parse_label(&mut self) -> PResult<String> {
+        Ok(self.expect(TokenKind::Label, "label")?.name().to_string())
+    }
+
+    /// Parses `lhs, rhs`
+    fn parse_pair(&mut self) -> PResult<(Value, Value)> {
+        let lhs = self.parse_value()?;
+        self.expect(TokenKind::Comma, "`,`")?;
+        let rhs = self.parse_value()?;
+        Ok((lhs, rhs))
+    }
+
+    fn parse_instr(&mut self) -> PResult<Instr> {
+        let tok = self.expect(TokenKind::Ident, "instruction")?;
+        let binary: Option<fn(Value, Value) -> Instr> = match tok.text {
+            "add" => Some(Instr::Add),
+            "sub" => Some(Instr::Sub),
+            "mul" => Some(Instr::Mul),
+            "div" => Some(Instr::Div),
+            "rem" => Some(Instr::Rem),
+            "and" => Some(Instr::And),
+            "or" => Some(Instr::Or),
+            "xor" => Some(Instr::Xor),
+            "udiv" => Some(Instr::Udiv),
+            "urem" => Some(Instr::Urem),
+            "sar" => Some(Instr::Sar),
+            "shr" => Some(Instr::Shr),
+            "shl" => Some(Instr::Shl),
+            _ => None,
+        };
+        if let Some(instr) = binary {
+            let (lhs, rhs) = self.parse_pair()?;
+            return Ok(instr(lhs, rhs));
+        }
+
+        let unary: Option<fn(Value) -> Instr> = match tok.text {
+            "neg" => Some(Instr::Neg),
+            "copy" => Some(Instr::Copy),
+            "alloc4" => Some(Instr::Alloc4),
+            "alloc8" => Some(Instr::Alloc8),
+            "alloc16" => Some(Instr::Alloc16),
+            "cast" => Some(Instr::Cast),
+            "extsw" => Some(Instr::Extsw),
+            "extuw" => Some(Instr::Extuw),
+            "extsh" => Some(Instr::Extsh),
+            "extuh" => Some(Instr::Extuh),
+            "extsb" => Some(Instr::Extsb),
+            "extub" => Some(Instr::Extub),
+            "exts" => Some(Instr::Exts),
+            "truncd" => Some(Instr::Truncd),
+            "stosi" => Some(Instr::Stosi),
+            "stoui" => Some(Instr::Stoui),
+            "dtosi" => Some(Instr::Dtosi),
+            "dtoui" => Some(Instr::Dtoui),
+            "swtof" => Some(Instr::Swtof),
+            "uwtof" => Some(Instr::Uwtof),
+            "sltof" => Some(Instr::Sltof),
+            "ultof" => Some(Instr::Ultof),
+            "vastart" => Some(Instr::Vastart),
+            _ => None,
+        };
+        if let Some(instr) = unary {
+            return Ok(instr(self.parse_value()?));
+        }
+
+        match tok.text {
+            "ret" => {
+                if matches!(
+                    self.peek().kind,
+                    TokenKind::Newline | TokenKind::Comment | TokenKind::RBrace | TokenKind::Eof
+                ) {
+                    Ok(Instr::Ret(None))
+                } else {
+                    Ok(Instr::Ret(Some(self.parse_value()?)))
+                }
+            }
+            "jnz" => {
+                let cond = self.parse_value()?;
+                self.expect(TokenKind::Comma, "`,`")?;
+                let if_nonzero = self.parse_label()?;
+                self.expect(TokenKind::Comma, "`,`")?;
+                let if_zero = self.parse_label()?;
+                Ok(Instr::Jnz(cond, if_nonzero, if_zero))
+            }
+            "jmp" => Ok(Instr::Jmp(self.parse_label()?)),
+            "call" => self.parse_call(),
+            "blit" => {
+                let (src, dst) = self.parse_pair()?;
+                self.expect(TokenKind::Comma, "`,`")?;
+                Ok(Instr::Blit(src, dst, self.parse_integer()?))
+            }
+            "dbgfile" => {
+                let name = self.expect(TokenKind::Str, "file name")?;
+                Ok(Instr::DbgFile(raw_string(name)))
+            }
+            "dbgloc" => {
+                let line = self.parse_integer()?;
+                let column = if self.eat(TokenKind::Comma) {
+                    Some(self.parse_integer()?)
+                } else {
+                    None
+                };
+                Ok(Instr::DbgLoc(line, column))
+            }
+            "phi" => {
+                let mut args = Vec::new();
+                loop {
+                    let label = self.parse_label()?;
+                    args.push((label, self.parse_value()?));
+                    if !self.eat(TokenKind::Comma) {
+                        return Ok(Instr::Phi(args));
+                    }
+                }
+            }
+            "hlt" => Ok(Instr::Hlt),
+            text => {
+                if let Some(ty) = text.strip_prefix("store").and_then(store_type) {
+                    let (value, dest) = self.parse_pair()?;
+                    return Ok(Instr::Store(ty, dest, value));
+                }
+                if let Some(ty) = text.strip_prefix("load").and_then(load_type) {
+                    return Ok(Instr::Load(ty, self.parse_value()?));
+                }
+                if let Some(ty) = text.strip_prefix("vaarg").and_then(base_type) {
+                    return Ok(Instr::Vaarg(ty, self.parse_value()?));
+                }
+                if let Some((cmp, ty)) = text.strip_prefix('c').and_then(comparison) {
+                    let (lhs, rhs) = self.parse_pair()?;
+                    return Ok(Instr::Cmp(ty, cmp, lhs, rhs));
+                }
+                Err(self.error(tok, format!("unknown instruction `{text}`")))
+            }
+        }
+    }
+
+    fn parse_call(&mut self) -> PResult<Instr> {
+        let callee = self.parse_value()?;
+        self.expect(TokenKind::LParen, "`(`")?;
+
+        let mut args = Vec::new();
+        let mut variadic = None;
+        if !self.eat(TokenKind::RParen) {
+            loop {
+                if self.eat(TokenKind::Ellipsis) {
+                    variadic = Some(args.len() as u64);
+                } else {
+                    let ty = self.parse_type()?;
+                    args.push((ty, self.parse_value()?));
+                }
+                if self.eat(TokenKind::RParen) {
+                    break;
+                }
+                if !self.eat(TokenKind::Comma) {
+                    return Err(self.unexpected(self.peek(), &["`,`", "`)`"]));
+                }
+            }
+        }
+
+        Ok(Instr::Call(callee, args, variadic))
+    }
+}
+
+/// Joins token descriptions into "a, b or c"
+fn alternatives(expected: &[&str]) -> String {
+    match expected {
+        [] => "nothing".to_string(),
+        [one] => one.to_string(),
+        [init @ .., last] => format!("{} or {last}", init.join(", ")),
+    }
+}
+
+/// Returns the contents of a string token without quotes or unescaping
+fn raw_string(tok: Token) -> String {
+    tok.text[1..tok.text.len() - 1].to_string()
+}
+
+/// Returns the text of a comment token without `#` and one following space
+fn comment_text(tok: Token) -> String {
+    let text = &tok.text[1..];
+    text.strip_prefix(' ').unwrap_or(text).to_string()
+}
+
+fn type_from_name(name: &str) -> Option<Type> {
+    Some(match name {
+        "w" => Type::Word,
+        "l" => Type::Long,
+        "s" => Type::Single,
+        "d" => Type::Double,
+        "z" => Type::Zero,
+        "env" => Type::Env,
+        "b" => Type::Byte,
+        "sb" => Type::SignedByte,
+        "ub" => Type::UnsignedByte,
+        "h" => Type::Halfword,
+        "sh" => Type::SignedHalfword,
+        "uh" => Type::UnsignedHalfword,
+        _ => return None,
+    })
+}
+
+fn base_type(name: &str) -> Option<Type> {
+    type_from_name(name).filter(Type::is_base)
+}
+
+fn store_type(suffix: &str) -> Option<Type> {
+    match suffix {
+        "b" => Some(Type::Byte),
+        "h" => Some(Type::Halfword),
+        _ => base_type(suffix),
+    }
+}
+
+fn load_type(suffix: &str) -> Option<Type> {
+    match suffix {
+        "sb" => Some(Type::SignedByte),
+        "ub" => Some(Type::UnsignedByte),
+        "sh" => Some(Type::SignedHalfword),
+        "uh" => Some(Type::UnsignedHalfword),
+        _ => base_type(suffix),
+    }
+}
+
+/// Splits a comparison mnemonic without its leading `c`, e.g. `sltw`. The
+/// floating-point orderings `lt`, `le`, `gt` and `ge` only exist for `s`
+/// and `d`.
+fn comparison(text: &str) -> Option<(Cmp, Type)> {
+    const CMPS: [(&str, Cmp); 16] = [
+        ("slt", Cmp::Slt),
+        ("sle", Cmp::Sle),
+        ("sgt", Cmp::Sgt),
+        ("sge", Cmp::Sge),
+        ("ult", Cmp::Ult),
+        ("ule", Cmp::Ule),
+        ("ugt", Cmp::Ugt),
+        ("uge", Cmp::Uge),
+        ("eq", Cmp::Eq),
+        ("ne", Cmp::Ne),
+        ("uo", Cmp::Uo),
+        ("o", Cmp::O),
+        ("lt", Cmp::Lt),
+        ("le", Cmp::Le),
+        ("gt", Cmp::Gt),
+        ("ge", Cmp::Ge),
+    ];
+
+    CMPS.iter().find_map(|(name, cmp)| {
+        let ty = base_type(text.strip_prefix(name)?)?;
+        match cmp {
+            Cmp::Lt | Cmp::Le | Cmp::Gt | Cmp::Ge if !matches!(ty, Type::Single | Type::Double) => {
+                None
+            }
+            _ => Some((*cmp, ty)),
+        }
+    })
+}
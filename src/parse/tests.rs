@@ -0,0 +1,538 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::tests::temp;
+use crate::*;
+use std::sync::Arc;
+
+fn assert_round_trip(module: &Module) {
+    let text = module.to_string();
+    let parsed: Module = text.parse().unwrap_or_else(|err| panic!("{err}\n{text}"));
+    assert_eq!(&parsed, module, "{text}");
+}
+
+#[test]
+fn every_instruction_round_trips() {
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::public(),
+        "all",
+        vec![(Type::Long, temp("p")), (Type::Word, temp("w"))],
+        Some(Type::Word),
+    );
+
+    let block = func.add_block("start");
+    let binary: [fn(Value, Value) -> Instr; 13] = [
+        Instr::Add,
+        Instr::Sub,
+        Instr::Mul,
+        Instr::Div,
+        Instr::Rem,
+        Instr::And,
+        Instr::Or,
+        Instr::Xor,
+        Instr::Udiv,
+        Instr::Urem,
+        Instr::Sar,
+        Instr::Shr,
+        Instr::Shl,
+    ];
+    for (i, instr) in binary.iter().enumerate() {
+        block.assign_instr(
+            temp(&format!("b{i}")),
+            Type::Word,
+            instr(temp("w"), 1.into()),
+        );
+    }
+
+    let unary: [fn(Value) -> Instr; 22] = [
+        Instr::Neg,
+        Instr::Copy,
+        Instr::Cast,
+        Instr::Extsw,
+        Instr::Extuw,
+        Instr::Extsh,
+        Instr::Extuh,
+        Instr::Extsb,
+        Instr::Extub,
+        Instr::Exts,
+        Instr::Truncd,
+        Instr::Stosi,
+        Instr::Stoui,
+        Instr::Dtosi,
+        Instr::Dtoui,
+        Instr::Swtof,
+        Instr::Uwtof,
+        Instr::Sltof,
+        Instr::Ultof,
+        Instr::Alloc4,
+        Instr::Alloc8,
+        Instr::Alloc16,
+    ];
+    for (i, instr) in unary.iter().enumerate() {
+        block.assign_instr(temp(&format!("u{i}")), Type::Long, instr(temp("p")));
+    }
+
+    let cmps = [
+        Cmp::Slt,
+        Cmp::Sle,
+        Cmp::Sgt,
+        Cmp::Sge,
+        Cmp::Eq,
+        Cmp::Ne,
+        Cmp::O,
+        Cmp::Uo,
+        Cmp::Ult,
+        Cmp::Ule,
+        Cmp::Ugt,
+        Cmp::Uge,
+        Cmp::Lt,
+        Cmp::Le,
+        Cmp::Gt,
+        Cmp::Ge,
+    ];
+    for (i, cmp) in cmps.into_iter().enumerate() {
+        block.assign_instr(
+            temp(&format!("c{i}")),
+            Type::Word,
+            Instr::Cmp(Type::Double, cmp, 2.5f64.into(), Value::DoubleConst(-0.0)),
+        );
+    }
+
+    for (i, ty) in [
+        Type::Word,
+        Type::Long,
+        Type::Single,
+        Type::Double,
+        Type::SignedByte,
+        Type::UnsignedByte,
+        Type::SignedHalfword,
+        Type::UnsignedHalfword,
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        block.assign_instr(
+            temp(&format!("l{i}")),
+            Type::Long,
+            Instr::Load(ty, temp("p")),
+        );
+    }
+    for ty in [
+        Type::Byte,
+        Type::Halfword,
+        Type::Word,
+        Type::Long,
+        Type::Single,
+        Type::Double,
+    ] {
+        block.add_instr(Instr::Store(ty, temp("p"), Value::SignedConst(-1)));
+    }
+
+    block.add_instr(Instr::Blit(temp("p"), Value::Global("dst".into()), 16));
+    block.add_instr(Instr::Vastart(temp("p")));
+    block.assign_instr(
+        temp("va"),
+        Type::Single,
+        Instr::Vaarg(Type::Single, temp("p")),
+    );
+    block.add_instr(Instr::DbgFile("main.c".into()));
+    block.add_instr(Instr::DbgLoc(3, None));
+    block.add_instr(Instr::DbgLoc(4, Some(7)));
+    block.assign_instr(
+        temp("r"),
+        Type::Word,
+        Instr::Call(
+            Value::Global("printf".into()),
+            vec![(Type::Long, temp("p")), (Type::Single, 0.5f32.into())],
+            Some(1),
+        ),
+    );
+    block.add_instr(Instr::Call(
+        temp("fp"),
+        vec![
+            (Type::Env, temp("env")),
+            (Type::Long, Value::ThreadLocal("tls".into())),
+        ],
+        None,
+    ));
+    block.add_instr(Instr::Call(Value::Global("noargs".into()), vec![], None));
+    block.add_instr(Instr::Jnz(temp("w"), "yes".into(), "no".into()));
+
+    let yes = func.add_block("yes");
+    yes.add_instr(Instr::Jmp("join".into()));
+    let no = func.add_block("no");
+    no.add_instr(Instr::Hlt);
+    let join = func.add_block("join");
+    join.assign_instr(
+        temp("phi"),
+        Type::Word,
+        Instr::Phi(vec![("yes".into(), 1.into()), ("no".into(), temp("w"))]),
+    );
+    join.add_instr(Instr::Ret(Some(temp("phi"))));
+
+    module.add_function(func);
+    assert_round_trip(&module);
+}
+
+#[test]
+fn types_and_data_round_trip() {
+    let mut module = Module::new();
+    let pair = Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Long, 1), (Type::Word, 2), (Type::Byte, 1)],
+    });
+    let either = Arc::new(TypeDef::Union {
+        ident: "either".into(),
+        align: Some(8),
+        variations: vec![
+            vec![(Type::Aggregate(Arc::clone(&pair)), 1)],
+            vec![(Type::Single, 4)],
+        ],
+    });
+    let blob = Arc::new(TypeDef::Opaque {
+        ident: "blob".into(),
+        align: 16,
+        size: 32,
+    });
+    let empty = Arc::new(TypeDef::Regular {
+        ident: "empty".into(),
+        align: Some(4),
+        items: vec![],
+    });
+    module.add_type(Arc::clone(&pair));
+    module.add_type(Arc::clone(&either));
+    module.add_type(blob);
+    module.add_type(empty);
+
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "str",
+        None,
+        vec![
+            (Type::Byte, "say \"hi\"\n\t\\".into()),
+            (Type::Byte, DataItem::from(&[0x00, 0xff, b'a'][..])),
+            (Type::Byte, 0.into()),
+        ],
+    ));
+    module.add_data(DataDef::new(
+        Linkage::public_with_section("data"),
+        "table",
+        Some(8),
+        vec![
+            (Type::Long, DataItem::Symbol("str".into(), None)),
+            (Type::Long, DataItem::Symbol("str".into(), Some(3))),
+            (
+                Type::Long,
+                DataItem::ThreadLocalSymbol("tls".into(), Some(8)),
+            ),
+            (
+                Type::Word,
+                DataItem::List(vec![1.into(), (-2).into(), 3.into()]),
+            ),
+            (Type::Single, 1.5f32.into()),
+            (Type::Double, DataItem::DoubleConst(f64::INFINITY)),
+            (Type::Zero, DataItem::Const(12)),
+            (Type::Halfword, DataItem::Zero(4)),
+        ],
+    ));
+    module.add_data(DataDef::new(
+        Linkage {
+            exported: true,
+            thread_local: true,
+            section: Some(".tdata".into()),
+            secflags: Some("awT".into()),
+        },
+        "tls",
+        None,
+        vec![(Type::Word, 7.into())],
+    ));
+
+    let mut func = Function::new(
+        Linkage::private(),
+        "agg",
+        vec![
+            (Type::Env, temp("env")),
+            (Type::Aggregate(Arc::clone(&pair)), temp("arg")),
+        ],
+        Some(Type::Aggregate(either)),
+    );
+    func.variadic = true;
+    let block = func.add_block("start");
+    block.add_comment("a comment");
+    block.add_comment("");
+    block.assign_instr(
+        temp("res"),
+        Type::Aggregate(pair),
+        Instr::Call(Value::Global("make".into()), vec![], None),
+    );
+    block.add_instr(Instr::Ret(Some(temp("res"))));
+    module.add_function(func);
+
+    let mut void = Function::new(
+        Linkage::thread_local_with_section("text"),
+        "void",
+        vec![],
+        None,
+    );
+    void.add_block("empty");
+    void.add_block("end").add_instr(Instr::Ret(None));
+    module.add_function(void);
+
+    assert_round_trip(&module);
+}
+
+#[test]
+fn referenced_types_are_parsed_into_types() {
+    let point = Arc::new(TypeDef::Regular {
+        ident: "point".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    });
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Aggregate(Arc::clone(&point)), temp("p"))],
+        None,
+    );
+    func.add_block("start").add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    // `point` is emitted although it was never added
+    let parsed: Module = module.to_string().parse().unwrap();
+    assert!(module.types.is_empty());
+    assert_eq!(parsed.types, vec![point]);
+    assert_ne!(parsed, module);
+
+    module.types = module.sorted_types().unwrap();
+    assert_eq!(parsed, module);
+}
+
+#[test]
+fn float_constants_round_trip() {
+    let mut module = Module::new();
+    let mut func = Function::new(Linkage::private(), "floats", vec![], None);
+    let block = func.add_block("start");
+    for (i, val) in [
+        Value::SingleConst(f32::MIN_POSITIVE),
+        Value::SingleConst(f32::NEG_INFINITY),
+        Value::SingleConst(f32::NAN),
+        Value::DoubleConst(1e-300),
+        Value::DoubleConst(-f64::NAN),
+        Value::DoubleConst(f64::MAX),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        block.assign_instr(temp(&format!("f{i}")), Type::Double, Instr::Copy(val));
+    }
+    block.add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    assert_round_trip(&module);
+}
+
+#[test]
+fn parse_handwritten_il() {
+    let module: Module = "
+# runtime support
+type :point = { w, w }
+
+export function w $add(w %a, w %b) {   # trailing comment
+@start
+    %sum =w add %a, %b
+    # checked
+    ret %sum
+}
+
+data $greeting = { b \"hi\\012\", b 0 }
+"
+    .parse()
+    .unwrap();
+
+    assert_eq!(module.types.len(), 1);
+    assert_eq!(module.types[0].ident(), "point");
+    assert_eq!(
+        module.data[0].items[0],
+        (Type::Byte, DataItem::Str("hi\n".into()))
+    );
+
+    let add = &module.functions[0];
+    assert!(add.linkage.exported);
+    assert_eq!(add.return_ty, Some(Type::Word));
+    assert_eq!(
+        add.blocks[0].items,
+        vec![
+            BlockItem::Statement(Statement::Assign(
+                temp("sum"),
+                Type::Word,
+                Instr::Add(temp("a"), temp("b")),
+            )),
+            BlockItem::Comment("checked".into()),
+            BlockItem::Statement(Statement::Volatile(Instr::Ret(Some(temp("sum"))))),
+        ]
+    );
+}
+
+#[test]
+fn parse_float_comparisons() {
+    let module: Module = "
+function w $less(d %a, d %b) {
+@start
+    %c =w cltd %a, %b
+    %d =w cges %a, %b
+    ret %c
+}
+"
+    .parse()
+    .unwrap();
+
+    let items = &module.functions[0].blocks[0].items;
+    assert_eq!(
+        items[0],
+        BlockItem::Statement(Statement::Assign(
+            temp("c"),
+            Type::Word,
+            Instr::Cmp(Type::Double, Cmp::Lt, temp("a"), temp("b")),
+        ))
+    );
+    assert_eq!(
+        items[1],
+        BlockItem::Statement(Statement::Assign(
+            temp("d"),
+            Type::Word,
+            Instr::Cmp(Type::Single, Cmp::Ge, temp("a"), temp("b")),
+        ))
+    );
+
+    let err = single_error("function $f() {\n@start\n    %x =w cltw 1, 2\n}");
+    assert_eq!(err.to_string(), "3:11: unknown instruction `cltw`");
+}
+
+#[test]
+fn identifiers_start_with_a_letter() {
+    let module: Module = "function w $f$1(w %a$b) {\n@.L$0\n    ret %a$b\n}"
+        .parse()
+        .unwrap();
+    let func = &module.functions[0];
+    assert_eq!(func.name, "f$1");
+    assert_eq!(func.arguments, vec![(Type::Word, temp("a$b"))]);
+    assert_eq!(func.blocks[0].label, ".L$0");
+
+    let err = single_error("function $f() {\n@start\n    ret %1\n}");
+    assert_eq!(err.to_string(), "3:9: expected value, found `%`");
+}
+
+/// Parses `src`, expecting exactly one error
+fn single_error(src: &str) -> ParseError {
+    let mut errors = src.parse::<Module>().unwrap_err().errors;
+    assert_eq!(errors.len(), 1, "{errors:?}");
+    errors.remove(0)
+}
+
+#[test]
+fn parse_errors_report_position() {
+    let err = single_error("function $f() {\n@start\n    %x =w frob 1\n}");
+    assert_eq!(err.line, 3);
+    assert_eq!(err.column, 11);
+    assert_eq!(err.span, Span { start: 33, end: 37 });
+    assert_eq!(err.to_string(), "3:11: unknown instruction `frob`");
+
+    let err = single_error("function $f(:missing %x) {\n}");
+    assert_eq!(err.to_string(), "1:13: undefined type `:missing`");
+
+    let err = single_error("data $s = { b \"open }");
+    assert_eq!(err.to_string(), "1:15: unterminated string");
+
+    let err = single_error("function $f() {\n@start\n    ret 1 2\n}");
+    assert_eq!(err.to_string(), "3:11: expected end of line, found `2`");
+    assert_eq!(err.expected, vec!["end of line"]);
+    assert_eq!(err.found, "`2`");
+
+    let err = single_error("type :t = { 8 }");
+    assert_eq!(err.to_string(), "1:13: opaque type requires an alignment");
+
+    let err = single_error("function $f(w %a w %b) {\n}");
+    assert_eq!(err.expected, vec!["`,`", "`)`"]);
+    assert_eq!(err.to_string(), "1:18: expected `,` or `)`, found `w`");
+}
+
+#[test]
+fn parse_recovers_at_blocks_and_definitions() {
+    let src = "\
+type :bad = { q }
+type :good = { w }
+
+function w $f(:good %p) {
+@start
+    %x =w add 1,
+    %y =w copy %x
+@next
+    jmp
+@end
+    ret %y
+}
+
+data $d = { w 1 2 ]
+function $g() {
+@start
+    frob
+    ret
+";
+    let errors = Module::parse_named("input.ssa", src).unwrap_err();
+    let messages: Vec<String> = errors.errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "input.ssa:1:15: expected type, found `q`",
+            "input.ssa:6:17: expected value, found end of line",
+            "input.ssa:9:8: expected label, found end of line",
+            "input.ssa:14:19: expected data item, found `]`",
+            "input.ssa:17:5: unknown instruction `frob`",
+            "input.ssa:19:1: expected `}`, found end of input",
+        ]
+    );
+    assert_eq!(errors.source(), src);
+}
+
+#[test]
+fn parse_errors_render_snippets() {
+    let src = "data $s = { w 1 }\nfunction $f() {\n@start\n\tcall $g(w 1,, w 2)\n}\n";
+    let errors = Module::parse_named("call.ssa", src).unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        "\
+error: expected type, found `,`
+ --> call.ssa:4:14
+  |
+4 | \tcall $g(w 1,, w 2)
+  | \t            ^"
+    );
+
+    let errors = "data $s = { b \"abc }\ndata $t = { w 1 ]\n"
+        .parse::<Module>()
+        .unwrap_err();
+    assert_eq!(
+        errors.to_string(),
+        "\
+error: unterminated string
+ --> 1:15
+  |
+1 | data $s = { b \"abc }
+  |               ^^^^^^
+
+error: expected data item, found `]`
+ --> 2:17
+  |
+2 | data $t = { w 1 ]
+  |                 ^"
+    );
+}
@@ -0,0 +1,426 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Control flow graph, dominators and SSA form verification.
+
+use crate::typecheck::{fits, temp_type};
+use crate::{Block, BlockItem, Function, Instr, Location, Statement, Type, Value};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// A violation of SSA form found by [`Function::verify_ssa`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct SsaError {
+    /// Where the problem was found
+    pub location: Location,
+    /// What is wrong
+    pub kind: SsaErrorKind,
+}
+
+impl fmt::Display for SsaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for SsaError {}
+
+/// Kind of an [`SsaError`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum SsaErrorKind {
+    /// A temporary assigned more than once, or assigned although it is a
+    /// parameter
+    Redefinition(String),
+    /// A temporary that is used but never assigned
+    Undefined(String),
+    /// A use of a temporary that its assignment doesn't dominate, so the
+    /// temporary may be read before it is assigned
+    NotDominated(String),
+    /// A predecessor of the block that a `phi` has no value for
+    PhiMissingPredecessor(String),
+    /// A label named by a `phi` that isn't a predecessor of its block
+    PhiNotPredecessor(String),
+    /// A label named more than once by a `phi`
+    PhiDuplicateLabel(String),
+    /// A `phi` value for the given predecessor whose type differs from the
+    /// `phi`'s
+    PhiType {
+        label: String,
+        expected: Type,
+        found: Type,
+    },
+}
+
+impl fmt::Display for SsaErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Redefinition(temp) => write!(f, "%{temp} is assigned more than once"),
+            Self::Undefined(temp) => write!(f, "%{temp} is used but never assigned"),
+            Self::NotDominated(temp) => {
+                write!(f, "%{temp} is used where its assignment doesn't dominate")
+            }
+            Self::PhiMissingPredecessor(label) => {
+                write!(f, "`phi` has no value for predecessor @{label}")
+            }
+            Self::PhiNotPredecessor(label) => {
+                write!(f, "`phi` names @{label}, which is not a predecessor")
+            }
+            Self::PhiDuplicateLabel(label) => write!(f, "`phi` names @{label} more than once"),
+            Self::PhiType {
+                label,
+                expected,
+                found,
+            } => write!(
+                f,
+                "`phi` value from @{label} is `{found}`, expected `{expected}`"
+            ),
+        }
+    }
+}
+
+/// Control flow graph of a function, with blocks identified by their index
+/// in [`Function::blocks`]
+pub(crate) struct Cfg {
+    pub(crate) succs: Vec<Vec<usize>>,
+    pub(crate) preds: Vec<Vec<usize>>,
+    /// Immediate dominator of each block; `None` for the entry block and
+    /// for unreachable blocks
+    pub(crate) idom: Vec<Option<usize>>,
+    /// Reverse postorder index of each block; `None` if it is unreachable
+    rpo_index: Vec<Option<usize>>,
+}
+
+impl Cfg {
+    /// Builds the graph of `func`. Jumps to unknown labels are ignored and
+    /// a block without a terminator falls through to the next one.
+    pub(crate) fn new(func: &Function) -> Self {
+        let index: HashMap<&str, usize> = func
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.label.as_str(), i))
+            .rev()
+            .collect();
+
+        let n = func.blocks.len();
+        let mut succs = vec![Vec::new(); n];
+        let mut preds = vec![Vec::new(); n];
+        for (i, block) in func.blocks.iter().enumerate() {
+            let targets = match terminator(block) {
+                Some(instr) => instr
+                    .labels()
+                    .into_iter()
+                    .filter_map(|label| index.get(label).copied())
+                    .collect(),
+                None if i + 1 < n => vec![i + 1],
+                None => Vec::new(),
+            };
+            for target in targets {
+                if !succs[i].contains(&target) {
+                    succs[i].push(target);
+                    preds[target].push(i);
+                }
+            }
+        }
+
+        let mut cfg = Cfg {
+            succs,
+            preds,
+            idom: vec![None; n],
+            rpo_index: vec![None; n],
+        };
+        if n > 0 {
+            cfg.compute_dominators();
+        }
+        cfg
+    }
+
+    /// Computes dominators with the algorithm from "A Simple, Fast
+    /// Dominance Algorithm" by Cooper, Harvey and Kennedy
+    fn compute_dominators(&mut self) {
+        let mut postorder = Vec::new();
+        let mut visited = vec![false; self.succs.len()];
+        let mut stack = vec![(0, 0)];
+        visited[0] = true;
+        while let Some((block, next)) = stack.pop() {
+            if let Some(&succ) = self.succs[block].get(next) {
+                stack.push((block, next + 1));
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            } else {
+                postorder.push(block);
+            }
+        }
+        let rpo: Vec<usize> = postorder.into_iter().rev().collect();
+        for (i, &block) in rpo.iter().enumerate() {
+            self.rpo_index[block] = Some(i);
+        }
+
+        // The entry block temporarily dominates itself to seed the fixpoint
+        self.idom[0] = Some(0);
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in &rpo[1..] {
+                let mut new_idom = None;
+                for &pred in &self.preds[block] {
+                    if self.idom[pred].is_none() {
+                        continue;
+                    }
+                    new_idom = Some(match new_idom {
+                        None => pred,
+                        Some(other) => self.intersect(pred, other),
+                    });
+                }
+                if new_idom.is_some() && self.idom[block] != new_idom {
+                    self.idom[block] = new_idom;
+                    changed = true;
+                }
+            }
+        }
+        self.idom[0] = None;
+    }
+
+    fn intersect(&self, mut a: usize, mut b: usize) -> usize {
+        let order = |block: usize| self.rpo_index[block].unwrap();
+        while a != b {
+            while order(a) > order(b) {
+                a = self.idom[a].unwrap();
+            }
+            while order(b) > order(a) {
+                b = self.idom[b].unwrap();
+            }
+        }
+        a
+    }
+
+    /// Returns true if `block` can be reached from the entry block
+    pub(crate) fn is_reachable(&self, block: usize) -> bool {
+        self.rpo_index[block].is_some()
+    }
+
+    /// Returns true if every path from the entry block to `block` passes
+    /// through `dom`. Every block dominates itself.
+    pub(crate) fn dominates(&self, dom: usize, mut block: usize) -> bool {
+        loop {
+            if block == dom {
+                return true;
+            }
+            match self.idom[block] {
+                Some(idom) => block = idom,
+                None => return false,
+            }
+        }
+    }
+}
+
+/// Returns the first jump, `ret` or `hlt` of `block`
+fn terminator(block: &Block) -> Option<&Instr> {
+    block.items.iter().find_map(|item| match item {
+        BlockItem::Statement(Statement::Volatile(instr)) if instr.is_terminator() => Some(instr),
+        _ => None,
+    })
+}
+
+/// Where a temporary is assigned
+#[derive(Clone, Copy)]
+enum Def {
+    Param,
+    Item { block: usize, item: usize },
+}
+
+impl Function {
+    /// Checks that the function is in SSA form.
+    ///
+    /// Every temporary must be assigned exactly once, either as a parameter
+    /// or by a statement, and every use must be dominated by that
+    /// assignment. Each `phi` must name every predecessor of its block
+    /// exactly once, with a value of the `phi`'s type that is available at
+    /// the end of that predecessor. Unreachable blocks are exempt from the
+    /// dominance checks.
+    ///
+    /// QBE itself accepts temporaries assigned more than once and rebuilds
+    /// SSA form, but code emitting `phi` must be in SSA form to be correct.
+    ///
+    /// All problems are reported, in block order. Labels that don't exist
+    /// are left to [`Module::validate`](crate::Module::validate).
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{Function, Instr, Linkage, Location, SsaErrorKind, Type, Value};
+    ///
+    /// let mut func = Function::new(Linkage::private(), "f", vec![], Some(Type::Word));
+    /// func.add_block("start")
+    ///     .add_instr(Instr::Jnz(1.into(), "then".into(), "end".into()));
+    /// func.add_block("then").assign_instr(
+    ///     Value::Temporary("x".into()),
+    ///     Type::Word,
+    ///     Instr::Copy(1.into()),
+    /// );
+    /// func.add_block("end")
+    ///     .add_instr(Instr::Ret(Some(Value::Temporary("x".into()))));
+    ///
+    /// let errors = func.verify_ssa().unwrap_err();
+    /// assert_eq!(errors[0].kind, SsaErrorKind::NotDominated("x".into()));
+    /// assert_eq!(
+    ///     errors[0].location,
+    ///     Location::Function {
+    ///         name: "f".into(),
+    ///         block: Some("end".into()),
+    ///         item: Some(0),
+    ///     }
+    /// );
+    /// ```
+    pub fn verify_ssa(&self) -> Result<(), Vec<SsaError>> {
+        let cfg = Cfg::new(self);
+        let index: HashMap<&str, usize> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| (block.label.as_str(), i))
+            .rev()
+            .collect();
+        let mut errors = Vec::new();
+        let location = |block: &Block, item: usize| Location::Function {
+            name: self.name.clone(),
+            block: Some(block.label.clone()),
+            item: Some(item),
+        };
+
+        let mut defs: HashMap<&str, (Def, Type)> = HashMap::new();
+        for (ty, val) in &self.arguments {
+            if let Value::Temporary(name) = val {
+                defs.insert(name, (Def::Param, temp_type(ty)));
+            }
+        }
+        for (b, block) in self.blocks.iter().enumerate() {
+            for (i, item) in block.items.iter().enumerate() {
+                let BlockItem::Statement(Statement::Assign(Value::Temporary(name), ty, _)) = item
+                else {
+                    continue;
+                };
+                if defs.contains_key(name.as_str()) {
+                    errors.push(SsaError {
+                        location: location(block, i),
+                        kind: SsaErrorKind::Redefinition(name.clone()),
+                    });
+                } else {
+                    defs.insert(name, (Def::Item { block: b, item: i }, temp_type(ty)));
+                }
+            }
+        }
+
+        for (b, block) in self.blocks.iter().enumerate() {
+            let reachable = cfg.is_reachable(b);
+            for (i, item) in block.items.iter().enumerate() {
+                let BlockItem::Statement(statement) = item else {
+                    continue;
+                };
+                let (result, instr) = match statement {
+                    Statement::Assign(_, ty, instr) => (Some(temp_type(ty)), instr),
+                    Statement::Volatile(instr) => (None, instr),
+                };
+                let mut report = |kind| {
+                    errors.push(SsaError {
+                        location: location(block, i),
+                        kind,
+                    })
+                };
+
+                let Instr::Phi(args) = instr else {
+                    for val in instr.operands() {
+                        let Value::Temporary(name) = val else {
+                            continue;
+                        };
+                        match defs.get(name.as_str()) {
+                            None => report(SsaErrorKind::Undefined(name.clone())),
+                            Some((Def::Param, _)) => {}
+                            Some((
+                                Def::Item {
+                                    block: db,
+                                    item: di,
+                                },
+                                _,
+                            )) => {
+                                let dominated = if *db == b {
+                                    *di < i
+                                } else {
+                                    cfg.dominates(*db, b)
+                                };
+                                if reachable && !dominated {
+                                    report(SsaErrorKind::NotDominated(name.clone()));
+                                }
+                            }
+                        }
+                    }
+                    continue;
+                };
+
+                let mut seen = HashSet::new();
+                for (label, val) in args {
+                    if !seen.insert(label.as_str()) {
+                        report(SsaErrorKind::PhiDuplicateLabel(label.clone()));
+                        continue;
+                    }
+                    let Some(&pred) = index.get(label.as_str()) else {
+                        continue;
+                    };
+                    if !cfg.preds[b].contains(&pred) {
+                        report(SsaErrorKind::PhiNotPredecessor(label.clone()));
+                        continue;
+                    }
+                    let Value::Temporary(name) = val else {
+                        continue;
+                    };
+                    match defs.get(name.as_str()) {
+                        None => report(SsaErrorKind::Undefined(name.clone())),
+                        Some((def, ty)) => {
+                            // The value must be available when leaving the
+                            // predecessor
+                            let available = match def {
+                                Def::Param => true,
+                                Def::Item { block: db, .. } => cfg.dominates(*db, pred),
+                            };
+                            if reachable && cfg.is_reachable(pred) && !available {
+                                report(SsaErrorKind::NotDominated(name.clone()));
+                            }
+                            if let Some(expected) = &result {
+                                if !fits(ty, expected) {
+                                    report(SsaErrorKind::PhiType {
+                                        label: label.clone(),
+                                        expected: expected.clone(),
+                                        found: ty.clone(),
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+                for &pred in &cfg.preds[b] {
+                    let label = &self.blocks[pred].label;
+                    if cfg.is_reachable(pred) && !seen.contains(label.as_str()) {
+                        report(SsaErrorKind::PhiMissingPredecessor(label.clone()));
+                    }
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use super::Cfg;
+use crate::tests::temp;
+use crate::*;
+
+fn at(block: &str, item: usize) -> Location {
+    Location::Function {
+        name: "f".into(),
+        block: Some(block.into()),
+        item: Some(item),
+    }
+}
+
+fn errors_of(func: &Function) -> Vec<(Location, SsaErrorKind)> {
+    match func.verify_ssa() {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .into_iter()
+            .map(|err| (err.location, err.kind))
+            .collect(),
+    }
+}
+
+/// A counting loop: `start -> loop <-> loop -> end`
+fn counting_loop() -> Function {
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Word, temp("n"))],
+        Some(Type::Word),
+    );
+    func.add_block("start").add_instr(Instr::Jmp("loop".into()));
+    let head = func.add_block("loop");
+    head.assign_instr(
+        temp("i"),
+        Type::Word,
+        Instr::Phi(vec![
+            ("start".into(), 0.into()),
+            ("loop".into(), temp("next")),
+        ]),
+    );
+    head.assign_instr(temp("next"), Type::Word, Instr::Add(temp("i"), 1.into()));
+    head.assign_instr(
+        temp("done"),
+        Type::Word,
+        Instr::Cmp(Type::Word, Cmp::Eq, temp("next"), temp("n")),
+    );
+    head.add_instr(Instr::Jnz(temp("done"), "end".into(), "loop".into()));
+    func.add_block("end")
+        .add_instr(Instr::Ret(Some(temp("next"))));
+    func
+}
+
+#[test]
+fn loop_passes() {
+    assert_eq!(errors_of(&counting_loop()), vec![]);
+}
+
+#[test]
+fn dominators() {
+    // start -> (a | b) -> join, with @dead unreachable
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("start")
+        .add_instr(Instr::Jnz(1.into(), "a".into(), "b".into()));
+    func.add_block("a").add_instr(Instr::Jmp("join".into()));
+    func.add_block("dead").add_instr(Instr::Jmp("join".into()));
+    // Falls through to @join
+    func.add_block("b").add_comment("empty");
+    func.add_block("join").add_instr(Instr::Ret(None));
+
+    let cfg = Cfg::new(&func);
+    assert_eq!(
+        cfg.succs,
+        vec![vec![1, 3], vec![4], vec![4], vec![4], vec![]]
+    );
+    assert_eq!(cfg.preds[4], vec![1, 2, 3]);
+    assert_eq!(cfg.idom, vec![None, Some(0), None, Some(0), Some(0)]);
+    assert!(!cfg.is_reachable(2));
+    assert!(cfg.dominates(0, 4));
+    assert!(cfg.dominates(4, 4));
+    assert!(!cfg.dominates(1, 4));
+}
+
+#[test]
+fn single_assignment() {
+    let mut func = Function::new(Linkage::private(), "f", vec![(Type::Word, temp("a"))], None);
+    let start = func.add_block("start");
+    start.assign_instr(temp("a"), Type::Word, Instr::Copy(1.into()));
+    start.assign_instr(temp("b"), Type::Word, Instr::Copy(1.into()));
+    start.assign_instr(temp("b"), Type::Word, Instr::Copy(2.into()));
+    start.add_instr(Instr::Ret(None));
+
+    assert_eq!(
+        errors_of(&func),
+        vec![
+            (at("start", 0), SsaErrorKind::Redefinition("a".into())),
+            (at("start", 2), SsaErrorKind::Redefinition("b".into())),
+        ]
+    );
+}
+
+#[test]
+fn uses_must_be_dominated() {
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    let start = func.add_block("start");
+    start.assign_instr(temp("a"), Type::Word, Instr::Copy(temp("b")));
+    start.assign_instr(temp("b"), Type::Word, Instr::Copy(temp("nowhere")));
+    start.add_instr(Instr::Jnz(temp("a"), "left".into(), "right".into()));
+    // Falls through to @right
+    func.add_block("left")
+        .assign_instr(temp("c"), Type::Word, Instr::Copy(1.into()));
+    let right = func.add_block("right");
+    right.add_instr(Instr::Call(temp("c"), vec![], None));
+    right.add_instr(Instr::Ret(None));
+    // Unreachable code isn't checked for dominance
+    let dead = func.add_block("dead");
+    dead.add_instr(Instr::Call(temp("c"), vec![], None));
+    dead.add_instr(Instr::Ret(None));
+
+    assert_eq!(
+        errors_of(&func),
+        vec![
+            (at("start", 0), SsaErrorKind::NotDominated("b".into())),
+            (at("start", 1), SsaErrorKind::Undefined("nowhere".into())),
+            (at("right", 0), SsaErrorKind::NotDominated("c".into())),
+        ]
+    );
+}
+
+#[test]
+fn phi_predecessors() {
+    let mut func = counting_loop();
+    func.blocks[1].items[0] = BlockItem::Statement(Statement::Assign(
+        temp("i"),
+        Type::Word,
+        Instr::Phi(vec![
+            ("start".into(), 0.into()),
+            ("start".into(), 1.into()),
+            ("end".into(), 2.into()),
+        ]),
+    ));
+
+    assert_eq!(
+        errors_of(&func),
+        vec![
+            (
+                at("loop", 0),
+                SsaErrorKind::PhiDuplicateLabel("start".into())
+            ),
+            (at("loop", 0), SsaErrorKind::PhiNotPredecessor("end".into())),
+            (
+                at("loop", 0),
+                SsaErrorKind::PhiMissingPredecessor("loop".into())
+            ),
+        ]
+    );
+}
+
+#[test]
+fn phi_values() {
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Double, temp("d"))],
+        None,
+    );
+    func.add_block("start")
+        .add_instr(Instr::Jnz(1.into(), "a".into(), "b".into()));
+    func.add_block("a")
+        .assign_instr(temp("x"), Type::Word, Instr::Copy(1.into()));
+    func.add_instr(Instr::Jmp("join".into()));
+    func.add_block("b").add_instr(Instr::Jmp("join".into()));
+    let join = func.add_block("join");
+    join.assign_instr(
+        temp("p"),
+        Type::Word,
+        Instr::Phi(vec![("a".into(), temp("d")), ("b".into(), temp("x"))]),
+    );
+    join.add_instr(Instr::Ret(None));
+
+    let errors: Vec<String> = func
+        .verify_ssa()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "function $f, block @join, item 0: `phi` value from @a is `d`, expected `w`",
+            "function $f, block @join, item 0: %x is used where its assignment doesn't dominate",
+        ]
+    );
+}
@@ -10,6 +10,11 @@
 use crate::*;
 use std::sync::Arc;
 
+/// Shorthand for a [`Value::Temporary`], shared by the test modules
+pub(crate) fn temp(name: &str) -> Value {
+    Value::Temporary(name.into())
+}
+
 #[test]
 fn qbe_value() {
     let val = Value::Temporary("temp42".into());
@@ -81,6 +86,7 @@ fn function() {
         return_ty: None,
         name: "main".into(),
         arguments: Vec::new(),
+        variadic: false,
         blocks: vec![Block {
             label: "start".into(),
             items: vec![BlockItem::Statement(Statement::Volatile(Instr::Ret(None)))],
@@ -102,6 +108,7 @@ fn function_new_equivalence() {
         return_ty: None,
         name: "main".into(),
         arguments: Vec::new(),
+        variadic: false,
         blocks: Vec::new(),
     };
 
@@ -379,6 +386,7 @@ fn add_function_to_module() {
         linkage: Linkage::public(),
         name: "foo".into(),
         arguments: Vec::new(),
+        variadic: false,
         blocks: Vec::new(),
         return_ty: None,
     };
@@ -391,7 +399,7 @@ fn add_function_to_module() {
 #[test]
 fn variadic_call() {
     let instr = Instr::Call(
-        "printf".into(),
+        Value::Global("printf".into()),
         vec![
             (Type::Long, Value::Global("fmt".into())),
             (Type::Word, Value::Const(0)),
@@ -749,6 +757,25 @@ fn halt_instruction() {
     assert_eq!(format!("{hlt}"), "hlt");
 }
 
+#[test]
+fn block_jumps() {
+    let mut block = Block::default();
+    assert!(!block.jumps());
+    block.add_instr(Instr::Add(1.into(), 2.into()));
+    assert!(!block.jumps());
+
+    for end in [
+        Instr::Ret(None),
+        Instr::Jmp("a".into()),
+        Instr::Jnz(1.into(), "a".into(), "b".into()),
+        Instr::Hlt,
+    ] {
+        let mut block = Block::default();
+        block.add_instr(end);
+        assert!(block.jumps());
+    }
+}
+
 #[test]
 fn thread_local_linkage() {
     let thread_local = Linkage::thread_local();
@@ -876,13 +903,13 @@ fn assign_instr_aggregate_type_coercion() {
     block.assign_instr(
         Value::Temporary("human".into()),
         Type::aggregate(&typedef),
-        Instr::Alloc8(Type::aggregate(&typedef).size()),
+        Instr::Alloc8(Type::aggregate(&typedef).size().into()),
     );
 
     block.assign_instr(
         Value::Temporary("result".into()),
         Type::aggregate(&typedef),
-        Instr::Call("new_person".into(), vec![], None),
+        Instr::Call(Value::Global("new_person".into()), vec![], None),
     );
 
     let formatted = format!("{block}");
@@ -1004,3 +1031,464 @@ fn store_valid_types() {
         "stored %val, %addr"
     );
 }
+
+#[test]
+fn float_constants() {
+    assert_eq!(format!("{}", Value::SingleConst(1.5)), "s_1.5");
+    assert_eq!(format!("{}", Value::DoubleConst(2.75)), "d_2.75");
+    assert_eq!(format!("{}", Value::DoubleConst(-0.0)), "d_-0.0");
+    assert_eq!(format!("{}", Value::DoubleConst(1e300)), "d_1e300");
+    assert_eq!(format!("{}", Value::SingleConst(0.1)), "s_0.1");
+    assert_eq!(format!("{}", Value::SingleConst(f32::INFINITY)), "s_inf");
+    assert_eq!(
+        format!("{}", Value::DoubleConst(f64::NEG_INFINITY)),
+        "d_-inf"
+    );
+    assert_eq!(format!("{}", Value::DoubleConst(f64::NAN)), "d_nan");
+    assert_eq!(Value::from(2.5f32), Value::SingleConst(2.5));
+    assert_eq!(Value::from(2.5f64), Value::DoubleConst(2.5));
+
+    // Shortest representation still parses back to the same bits
+    let text = format!("{}", Value::DoubleConst(0.1 + 0.2));
+    let parsed: f64 = text.strip_prefix("d_").unwrap().parse().unwrap();
+    assert_eq!(parsed.to_bits(), (0.1f64 + 0.2).to_bits());
+}
+
+#[test]
+fn float_constants_compare_by_bits() {
+    use std::collections::HashSet;
+
+    assert_eq!(Value::DoubleConst(f64::NAN), Value::DoubleConst(f64::NAN));
+    assert_ne!(Value::DoubleConst(0.0), Value::DoubleConst(-0.0));
+    assert_ne!(Value::SingleConst(1.0), Value::DoubleConst(1.0));
+
+    let set: HashSet<Value> = [
+        Value::SingleConst(f32::NAN),
+        Value::SingleConst(f32::NAN),
+        Value::DoubleConst(1.0),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(set.len(), 2);
+
+    let mut values = vec![
+        Value::DoubleConst(2.0),
+        Value::Const(1),
+        Value::DoubleConst(1.0),
+    ];
+    values.sort();
+    assert_eq!(
+        values,
+        vec![
+            Value::Const(1),
+            Value::DoubleConst(1.0),
+            Value::DoubleConst(2.0)
+        ]
+    );
+}
+
+#[test]
+fn float_constants_as_operands() {
+    let ret = Instr::Ret(Some(Value::DoubleConst(2.75)));
+    assert_eq!(format!("{ret}"), "ret d_2.75");
+
+    let call = Instr::Call(
+        Value::Global("sqrtf".into()),
+        vec![(Type::Single, Value::SingleConst(2.0))],
+        None,
+    );
+    assert_eq!(format!("{call}"), "call $sqrtf(s s_2.0)");
+
+    let store = Instr::Store(
+        Type::Double,
+        Value::Temporary("addr".into()),
+        Value::DoubleConst(0.5),
+    );
+    assert_eq!(format!("{store}"), "stored d_0.5, %addr");
+
+    let phi = Instr::Phi(vec![
+        ("a".into(), Value::SingleConst(1.0)),
+        ("b".into(), Value::Temporary("x".into())),
+    ]);
+    assert_eq!(format!("{phi}"), "phi @a s_1.0, @b %x");
+}
+
+#[test]
+fn signed_constants() {
+    assert_eq!(format!("{}", Value::SignedConst(-1)), "-1");
+    assert_eq!(
+        format!("{}", Value::SignedConst(i64::MIN)),
+        "-9223372036854775808"
+    );
+    assert_eq!(Value::from(-1i32), Value::SignedConst(-1));
+    assert_eq!(Value::from(-42i64), Value::SignedConst(-42));
+    // Non-negative values normalize to the unsigned form
+    assert_eq!(Value::from(42i32), Value::Const(42));
+    assert_eq!(Value::from(0i64), Value::Const(0));
+
+    let sub = Statement::Assign(
+        Value::Temporary("r".into()),
+        Type::Word,
+        Instr::Add(Value::Temporary("x".into()), (-1).into()),
+    );
+    assert_eq!(format!("{sub}"), "%r =w add %x, -1");
+
+    let data = DataDef::new(
+        Linkage::private(),
+        "table",
+        None,
+        vec![
+            (Type::Word, DataItem::from(-5)),
+            (Type::Word, DataItem::from(7)),
+            (Type::Long, DataItem::SignedConst(-1)),
+        ],
+    );
+    assert_eq!(format!("{data}"), "data $table = { w -5, w 7, l -1 }");
+    assert_eq!(DataItem::from(7u64), DataItem::Const(7));
+}
+
+#[test]
+fn indirect_call() {
+    let call = Statement::Assign(
+        Value::Temporary("r".into()),
+        Type::Word,
+        Instr::Call(
+            Value::Temporary("fp".into()),
+            vec![(Type::Long, Value::Temporary("self".into()))],
+            None,
+        ),
+    );
+    assert_eq!(format!("{call}"), "%r =w call %fp(l %self)");
+}
+
+#[test]
+#[should_panic(expected = "variadic marker at index 2 is past the end of 1 arguments")]
+fn call_variadic_index_past_arguments_panics() {
+    let call = Instr::Call(
+        Value::Global("f".into()),
+        vec![(Type::Word, 1.into())],
+        Some(2),
+    );
+    let _ = call.to_string();
+}
+
+#[test]
+#[should_panic(expected = "call target must be a global or a temporary")]
+fn call_constant_panics() {
+    let _ = format!("{}", Instr::Call(Value::Const(0), vec![], None));
+}
+
+#[test]
+fn env_parameter_and_argument() {
+    let mut func = Function::new(
+        Linkage::private(),
+        "closure",
+        vec![
+            (Type::Env, Value::Temporary("env".into())),
+            (Type::Word, Value::Temporary("x".into())),
+        ],
+        Some(Type::Word),
+    );
+    func.add_block("start");
+    func.add_instr(Instr::Ret(Some(Value::Temporary("x".into()))));
+    assert_eq!(
+        format!("{func}").lines().next().unwrap(),
+        "function w $closure(env %env, w %x) {"
+    );
+
+    let call = Instr::Call(
+        Value::Temporary("fn".into()),
+        vec![
+            (Type::Env, Value::Temporary("captures".into())),
+            (Type::Word, Value::Const(1)),
+        ],
+        None,
+    );
+    assert_eq!(format!("{call}"), "call %fn(env %captures, w 1)");
+
+    assert_eq!(Type::Env.size(), 8);
+    assert_eq!(Type::Env.into_base(), Type::Long);
+}
+
+#[test]
+#[should_panic(expected = "env must be the first and only environment")]
+fn env_argument_not_first_panics() {
+    let call = Instr::Call(
+        Value::Global("f".into()),
+        vec![
+            (Type::Word, Value::Const(1)),
+            (Type::Env, Value::Temporary("e".into())),
+        ],
+        None,
+    );
+    let _ = format!("{call}");
+}
+
+#[test]
+#[should_panic(expected = "env must be the first and only environment")]
+fn duplicate_env_parameter_panics() {
+    let func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![
+            (Type::Env, Value::Temporary("a".into())),
+            (Type::Env, Value::Temporary("b".into())),
+        ],
+        None,
+    );
+    let _ = format!("{func}");
+}
+
+#[test]
+fn variadic_function() {
+    let mut func = Function::new(
+        Linkage::public(),
+        "log",
+        vec![(Type::Long, Value::Temporary("fmt".into()))],
+        None,
+    );
+    func.variadic = true;
+    func.add_block("start");
+    func.assign_instr(
+        Value::Temporary("ap".into()),
+        Type::Long,
+        Instr::Alloc8(32.into()),
+    );
+    func.add_instr(Instr::Vastart(Value::Temporary("ap".into())));
+    func.add_instr(Instr::Ret(None));
+
+    let formatted = format!("{func}");
+    let mut lines = formatted.lines();
+    assert_eq!(lines.next().unwrap(), "export function $log(l %fmt, ...) {");
+    assert_eq!(lines.nth(2).unwrap(), "\tvastart %ap");
+
+    let no_fixed = Function {
+        variadic: true,
+        ..Function::new(Linkage::private(), "any", Vec::new(), None)
+    };
+    assert_eq!(
+        format!("{no_fixed}").lines().next().unwrap(),
+        "function $any(...) {"
+    );
+}
+
+#[test]
+fn dynamic_alloc() {
+    let alloc = Statement::Assign(
+        Value::Temporary("buf".into()),
+        Type::Long,
+        Instr::Alloc16(Value::Temporary("len".into())),
+    );
+    assert_eq!(format!("{alloc}"), "%buf =l alloc16 %len");
+    assert_eq!(format!("{}", Instr::Alloc4(4.into())), "alloc4 4");
+
+    assert_eq!(Instr::Alloc8(24.into()).alloc_size(), Some(24));
+    assert_eq!(Instr::Alloc8(24.into()).alloc_align(), Some(8));
+    assert_eq!(
+        Instr::Alloc16(Value::Temporary("len".into())).alloc_size(),
+        None
+    );
+    assert_eq!(
+        Instr::Alloc16(Value::Temporary("len".into())).alloc_align(),
+        Some(16)
+    );
+    assert_eq!(Instr::Hlt.alloc_align(), None);
+}
+
+#[test]
+fn alloc_for_type() {
+    assert_eq!(Instr::alloc_for(&Type::Byte), Instr::Alloc4(1.into()));
+    assert_eq!(Instr::alloc_for(&Type::Long), Instr::Alloc8(8.into()));
+
+    let typedef = Arc::new(TypeDef::Opaque {
+        ident: "vec4".into(),
+        align: 16,
+        size: 64,
+    });
+    assert_eq!(
+        Instr::alloc_for(&Type::aggregate(&typedef)),
+        Instr::Alloc16(64.into())
+    );
+}
+
+#[test]
+fn thread_local_operands() {
+    let val = Value::ThreadLocal("counter".into());
+    assert_eq!(format!("{val}"), "thread $counter");
+
+    let load = Statement::Assign(
+        Value::Temporary("n".into()),
+        Type::Word,
+        Instr::Load(Type::Word, Value::ThreadLocal("counter".into())),
+    );
+    assert_eq!(format!("{load}"), "%n =w loadw thread $counter");
+
+    let addr = Statement::Assign(
+        Value::Temporary("p".into()),
+        Type::Long,
+        Instr::Copy(Value::ThreadLocal("counter".into())),
+    );
+    assert_eq!(format!("{addr}"), "%p =l copy thread $counter");
+
+    assert_ne!(
+        Value::ThreadLocal("counter".into()),
+        Value::Global("counter".into())
+    );
+
+    let data = DataDef::new(
+        Linkage::thread_local(),
+        "self_ref",
+        None,
+        vec![
+            (
+                Type::Long,
+                DataItem::ThreadLocalSymbol("counter".into(), None),
+            ),
+            (
+                Type::Long,
+                DataItem::ThreadLocalSymbol("counter".into(), Some(4)),
+            ),
+        ],
+    );
+    assert_eq!(
+        format!("{data}"),
+        "thread data $self_ref = { l thread $counter, l thread $counter +4 }"
+    );
+}
+
+#[test]
+fn data_string_escaping() {
+    let item = DataItem::Str("quote \" backslash \\ newline \n tab \t".into());
+    assert_eq!(
+        format!("{item}"),
+        r#""quote \" backslash \\ newline \n tab \t""#
+    );
+
+    // Non-ASCII text is written byte by byte
+    assert_eq!(format!("{}", DataItem::Str("é".into())), r#""\303\251""#);
+
+    let blob: &[u8] = &[b'a', 0, 0x7f, 0xff, b'"'];
+    assert_eq!(format!("{}", DataItem::from(blob)), r#""a\000\177\377\"""#);
+}
+
+#[test]
+fn data_float_items() {
+    let data = DataDef::new(
+        Linkage::private(),
+        "consts",
+        None,
+        vec![
+            (Type::Single, DataItem::SingleConst(1.5)),
+            (Type::Double, DataItem::from(-0.25f64)),
+        ],
+    );
+    assert_eq!(format!("{data}"), "data $consts = { s s_1.5, d d_-0.25 }");
+    assert_eq!(
+        DataItem::DoubleConst(f64::NAN),
+        DataItem::DoubleConst(f64::NAN)
+    );
+}
+
+#[test]
+fn data_item_lists() {
+    let data = DataDef::new(
+        Linkage::private(),
+        "table",
+        Some(8),
+        vec![
+            (
+                Type::Byte,
+                DataItem::List(vec![1.into(), 2.into(), (-3).into()]),
+            ),
+            (Type::Byte, DataItem::List(vec!["abc".into(), 0.into()])),
+            (
+                Type::Long,
+                DataItem::List(vec![
+                    DataItem::Symbol("f".into(), None),
+                    DataItem::Symbol("g".into(), Some(8)),
+                ]),
+            ),
+        ],
+    );
+    assert_eq!(
+        format!("{data}"),
+        r#"data $table = align 8 { b 1 2 -3, b "abc" 0, l $f $g +8 }"#
+    );
+}
+
+#[test]
+#[should_panic(expected = "list items must be symbols, strings or constants, got `z 4`")]
+fn data_item_list_of_zero_panics() {
+    let _ = DataItem::List(vec![DataItem::Zero(4), 1.into()]).to_string();
+}
+
+#[test]
+fn abi_types_in_signatures() {
+    let typedef = Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    });
+
+    let mut func = Function::new(
+        Linkage::public(),
+        "f",
+        vec![
+            (Type::SignedByte, Value::Temporary("c".into())),
+            (Type::UnsignedHalfword, Value::Temporary("s".into())),
+            (Type::aggregate(&typedef), Value::Temporary("p".into())),
+        ],
+        Some(Type::UnsignedByte),
+    );
+    func.add_block("start");
+    func.assign_instr(
+        Value::Temporary("r".into()),
+        Type::SignedHalfword,
+        Instr::Call(
+            Value::Global("g".into()),
+            vec![(Type::UnsignedByte, Value::Temporary("c".into()))],
+            None,
+        ),
+    );
+    func.add_instr(Instr::Ret(Some(Value::Temporary("c".into()))));
+
+    let formatted = format!("{func}");
+    let lines: Vec<&str> = formatted.lines().collect();
+    assert_eq!(lines[0], "export function ub $f(sb %c, uh %s, :pair %p) {");
+    assert_eq!(lines[2], "\t%r =sh call $g(ub %c)");
+
+    assert!(Type::aggregate(&typedef).is_abi());
+    assert!(!Type::Halfword.is_abi());
+    assert!(!Type::Zero.is_abi());
+    assert!(!Type::Env.is_abi());
+}
+
+#[test]
+#[should_panic(expected = "invalid ABI type `b` for %c")]
+fn ambiguous_subword_parameter_panics() {
+    let func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Byte, Value::Temporary("c".into()))],
+        None,
+    );
+    let _ = format!("{func}");
+}
+
+#[test]
+#[should_panic(expected = "invalid ABI type `h` for return value of $f")]
+fn ambiguous_subword_return_panics() {
+    let func = Function::new(Linkage::private(), "f", vec![], Some(Type::Halfword));
+    let _ = format!("{func}");
+}
+
+#[test]
+#[should_panic(expected = "assignment to %x must use a base type")]
+fn subword_assignment_panics() {
+    let stmt = Statement::Assign(
+        Value::Temporary("x".into()),
+        Type::SignedByte,
+        Instr::Copy(Value::Const(1)),
+    );
+    let _ = format!("{stmt}");
+}
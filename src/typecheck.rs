@@ -0,0 +1,511 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Operand type checking following QBE's typing rules.
+
+use crate::{BlockItem, Cmp, Function, Instr, Location, Module, Statement, Type, Value};
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+pub(crate) const INTEGER: &[Type] = &[Type::Word, Type::Long];
+pub(crate) const FLOAT: &[Type] = &[Type::Single, Type::Double];
+
+/// A type error found by [`Module::typecheck`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct TypeError {
+    /// Where the problem was found
+    pub location: Location,
+    /// What is wrong
+    pub kind: TypeErrorKind,
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for TypeError {}
+
+/// Kind of a [`TypeError`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum TypeErrorKind {
+    /// A temporary assigned with a type other than the one it was first
+    /// given
+    ConflictingTypes {
+        temp: String,
+        first: Type,
+        second: Type,
+    },
+    /// A temporary used where the instruction expects another type
+    OperandType {
+        operand: Value,
+        expected: Type,
+        found: Type,
+    },
+    /// A constant used where the instruction expects another kind of value:
+    /// an integer constant or symbol as a float, or a float constant as an
+    /// integer or as the other float type
+    ConstantType { operand: Value, expected: Type },
+    /// An instruction assigned with a type it can't produce, e.g. `extsw`
+    /// assigned to anything but `l`
+    ResultType { expected: Vec<Type>, found: Type },
+    /// An instruction that doesn't produce a value, like `store` or `jmp`,
+    /// assigned to a temporary
+    NoResult,
+    /// A comparison that isn't defined for its type: `o`/`uo` and float
+    /// orderings on integers, signed and unsigned orderings on floats, or any
+    /// comparison of a type that isn't a base type
+    InvalidComparison(Cmp, Type),
+}
+
+impl fmt::Display for TypeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::ConflictingTypes {
+                temp,
+                first,
+                second,
+            } => write!(
+                f,
+                "%{temp} is assigned as `{second}` but was first assigned as `{first}`"
+            ),
+            Self::OperandType {
+                operand,
+                expected,
+                found,
+            } => write!(f, "expected `{expected}` operand, `{operand}` is `{found}`"),
+            Self::ConstantType { operand, expected } => {
+                write!(f, "expected `{expected}` operand, got constant `{operand}`")
+            }
+            Self::ResultType { expected, found } => {
+                let expected = expected
+                    .iter()
+                    .map(|ty| format!("`{ty}`"))
+                    .collect::<Vec<String>>()
+                    .join(" or ");
+                write!(
+                    f,
+                    "instruction can't produce `{found}`: expected {expected}"
+                )
+            }
+            Self::NoResult => write!(f, "instruction doesn't produce a value to assign"),
+            Self::InvalidComparison(cmp, ty) => match cmp {
+                _ if !ty.is_base() => write!(f, "cannot compare `{ty}`: use a base type"),
+                Cmp::O | Cmp::Uo => write!(
+                    f,
+                    "`o` and `uo` comparisons need a floating-point type, got `{ty}`"
+                ),
+                Cmp::Lt | Cmp::Le | Cmp::Gt | Cmp::Ge => write!(
+                    f,
+                    "floating-point comparisons need a floating-point type, got `{ty}`: \
+                     use `slt`, `sle`, `sgt` or `sge` for integers"
+                ),
+                Cmp::Slt | Cmp::Sle | Cmp::Sgt | Cmp::Sge => write!(
+                    f,
+                    "signed comparisons need an integer type, got `{ty}`: \
+                     use `lt`, `le`, `gt` or `ge` for floats"
+                ),
+                _ => write!(f, "unsigned comparisons need an integer type, got `{ty}`"),
+            },
+        }
+    }
+}
+
+impl Module {
+    /// Checks the operand and result types of every instruction against
+    /// QBE's typing rules.
+    ///
+    /// The type of each temporary is taken from the function parameters and
+    /// from the assignments to it; temporaries that are never assigned are
+    /// not checked. Integer constants and symbols fit `w` and `l` operands,
+    /// and `s_`/`d_` constants fit `s` and `d` operands respectively. As in
+    /// QBE, an `l` temporary may be used where a `w` is expected.
+    ///
+    /// All problems are reported, in module order. Run [`Module::validate`]
+    /// as well to catch structural problems.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{Function, Instr, Linkage, Module, Type, TypeErrorKind, Value};
+    ///
+    /// let mut module = Module::new();
+    /// let mut func = Function::new(
+    ///     Linkage::private(),
+    ///     "f",
+    ///     vec![(Type::Single, Value::Temporary("x".into()))],
+    ///     Some(Type::Long),
+    /// );
+    /// let start = func.add_block("start");
+    /// start.assign_instr(
+    ///     Value::Temporary("y".into()),
+    ///     Type::Long,
+    ///     Instr::Extsw(Value::Temporary("x".into())),
+    /// );
+    /// start.add_instr(Instr::Ret(Some(Value::Temporary("y".into()))));
+    /// module.add_function(func);
+    ///
+    /// let errors = module.typecheck().unwrap_err();
+    /// assert_eq!(
+    ///     errors[0].kind,
+    ///     TypeErrorKind::OperandType {
+    ///         operand: Value::Temporary("x".into()),
+    ///         expected: Type::Word,
+    ///         found: Type::Single,
+    ///     }
+    /// );
+    /// assert_eq!(
+    ///     errors[0].to_string(),
+    ///     "function $f, block @start, item 0: expected `w` operand, `%x` is `s`"
+    /// );
+    /// ```
+    pub fn typecheck(&self) -> Result<(), Vec<TypeError>> {
+        let mut errors = Vec::new();
+        for func in &self.functions {
+            TypeChecker::new(func, &mut errors).run();
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Returns the type a temporary holding a value of `ty` has, e.g. `w` for
+/// sub-word parameters and `l` for pointers to aggregates
+pub(crate) fn temp_type(ty: &Type) -> Type {
+    ty.clone().into_base()
+}
+
+/// Returns true if a temporary of type `found` may be used where `expected`
+/// is required. Longs are silently truncated when used as words.
+pub(crate) fn fits(found: &Type, expected: &Type) -> bool {
+    found == expected || (*found == Type::Long && *expected == Type::Word)
+}
+
+/// Returns true if a value other than a temporary may be used where
+/// `expected` is required: integer constants and symbols as integers, and
+/// float constants as their own type
+fn constant_fits(val: &Value, expected: &Type) -> bool {
+    match val {
+        Value::Temporary(_) => true,
+        Value::SingleConst(_) => *expected == Type::Single,
+        Value::DoubleConst(_) => *expected == Type::Double,
+        Value::Global(_) | Value::ThreadLocal(_) | Value::Const(_) | Value::SignedConst(_) => {
+            INTEGER.contains(expected)
+        }
+    }
+}
+
+/// Returns true if values of type `ty` can be compared with `cmp`
+pub(crate) fn is_valid_comparison(cmp: Cmp, ty: &Type) -> bool {
+    match cmp {
+        Cmp::O | Cmp::Uo | Cmp::Lt | Cmp::Le | Cmp::Gt | Cmp::Ge => FLOAT.contains(ty),
+        Cmp::Slt | Cmp::Sle | Cmp::Sgt | Cmp::Sge => INTEGER.contains(ty),
+        Cmp::Ult | Cmp::Ule | Cmp::Ugt | Cmp::Uge => INTEGER.contains(ty),
+        Cmp::Eq | Cmp::Ne => ty.is_base(),
+    }
+}
+
+struct TypeChecker<'a> {
+    func: &'a Function,
+    temps: HashMap<&'a str, Type>,
+    errors: &'a mut Vec<TypeError>,
+    location: Location,
+}
+
+impl<'a> TypeChecker<'a> {
+    fn new(func: &'a Function, errors: &'a mut Vec<TypeError>) -> Self {
+        Self {
+            func,
+            temps: HashMap::new(),
+            errors,
+            location: Location::Function {
+                name: func.name.clone(),
+                block: None,
+                item: None,
+            },
+        }
+    }
+
+    fn report(&mut self, kind: TypeErrorKind) {
+        self.errors.push(TypeError {
+            location: self.location.clone(),
+            kind,
+        });
+    }
+
+    fn run(mut self) {
+        let func = self.func;
+
+        for (ty, val) in &func.arguments {
+            if let Value::Temporary(name) = val {
+                self.temps.insert(name, temp_type(ty));
+            }
+        }
+
+        // Types are flow-insensitive, so collect them all before checking
+        // any use
+        for block in &func.blocks {
+            for (i, item) in block.items.iter().enumerate() {
+                let BlockItem::Statement(Statement::Assign(Value::Temporary(name), ty, _)) = item
+                else {
+                    continue;
+                };
+                let ty = temp_type(ty);
+                match self.temps.get(name.as_str()) {
+                    Some(first) if *first != ty => {
+                        let first = first.clone();
+                        self.location = Location::Function {
+                            name: func.name.clone(),
+                            block: Some(block.label.clone()),
+                            item: Some(i),
+                        };
+                        self.report(TypeErrorKind::ConflictingTypes {
+                            temp: name.clone(),
+                            first,
+                            second: ty,
+                        });
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.temps.insert(name, ty);
+                    }
+                }
+            }
+        }
+
+        for block in &func.blocks {
+            for (i, item) in block.items.iter().enumerate() {
+                let BlockItem::Statement(statement) = item else {
+                    continue;
+                };
+                self.location = Location::Function {
+                    name: func.name.clone(),
+                    block: Some(block.label.clone()),
+                    item: Some(i),
+                };
+                match statement {
+                    Statement::Assign(_, ty, instr) => self.check_instr(instr, Some(ty)),
+                    Statement::Volatile(instr) => self.check_instr(instr, None),
+                }
+            }
+        }
+    }
+
+    /// Checks `instr`, whose result is assigned with type `result` if any
+    fn check_instr(&mut self, instr: &Instr, result: Option<&Type>) {
+        let base = result.map(temp_type);
+
+        match instr {
+            Instr::Add(lhs, rhs)
+            | Instr::Sub(lhs, rhs)
+            | Instr::Mul(lhs, rhs)
+            | Instr::Div(lhs, rhs) => self.expect_same(&base, &[lhs, rhs]),
+            Instr::Neg(val) | Instr::Copy(val) => self.expect_same(&base, &[val]),
+            Instr::Rem(lhs, rhs)
+            | Instr::Udiv(lhs, rhs)
+            | Instr::Urem(lhs, rhs)
+            | Instr::And(lhs, rhs)
+            | Instr::Or(lhs, rhs)
+            | Instr::Xor(lhs, rhs) => {
+                self.expect_result(result, INTEGER);
+                self.expect_same(&base, &[lhs, rhs]);
+            }
+            Instr::Sar(lhs, rhs) | Instr::Shr(lhs, rhs) | Instr::Shl(lhs, rhs) => {
+                self.expect_result(result, INTEGER);
+                self.expect_same(&base, &[lhs]);
+                self.expect(rhs, &Type::Word);
+            }
+            Instr::Cmp(ty, cmp, lhs, rhs) => {
+                self.expect_result(result, INTEGER);
+                if is_valid_comparison(*cmp, ty) {
+                    self.expect(lhs, ty);
+                    self.expect(rhs, ty);
+                } else {
+                    self.report(TypeErrorKind::InvalidComparison(*cmp, ty.clone()));
+                }
+            }
+            Instr::Phi(args) => {
+                let vals: Vec<&Value> = args.iter().map(|(_, val)| val).collect();
+                self.expect_same(&base, &vals);
+            }
+            Instr::Alloc4(size) | Instr::Alloc8(size) | Instr::Alloc16(size) => {
+                self.expect_result(result, &[Type::Long]);
+                self.expect(size, &Type::Long);
+            }
+            Instr::Load(ty, src) => {
+                let expected: &[Type] = match ty {
+                    Type::Long => &[Type::Long],
+                    Type::Single => &[Type::Single],
+                    Type::Double => &[Type::Double],
+                    _ => INTEGER,
+                };
+                self.expect_result(result, expected);
+                self.expect(src, &Type::Long);
+            }
+            Instr::Store(ty, dest, val) => {
+                self.expect_no_result(result);
+                self.expect(dest, &Type::Long);
+                if !matches!(ty, Type::Aggregate(_)) {
+                    self.expect(val, &temp_type(ty));
+                }
+            }
+            Instr::Blit(src, dst, _) => {
+                self.expect_no_result(result);
+                self.expect(src, &Type::Long);
+                self.expect(dst, &Type::Long);
+            }
+            Instr::Cast(val) => {
+                let from = match base {
+                    Some(Type::Word) => Type::Single,
+                    Some(Type::Long) => Type::Double,
+                    Some(Type::Single) => Type::Word,
+                    Some(Type::Double) => Type::Long,
+                    _ => return,
+                };
+                // `l` fitting `w` doesn't apply: the widths must match
+                if let Some(found) = self.type_of(val) {
+                    if found != from {
+                        self.report(TypeErrorKind::OperandType {
+                            operand: val.clone(),
+                            expected: from,
+                            found,
+                        });
+                    }
+                }
+            }
+            Instr::Extsw(val) | Instr::Extuw(val) => {
+                self.expect_result(result, &[Type::Long]);
+                self.expect(val, &Type::Word);
+            }
+            Instr::Extsh(val) | Instr::Extuh(val) | Instr::Extsb(val) | Instr::Extub(val) => {
+                self.expect_result(result, INTEGER);
+                self.expect(val, &Type::Word);
+            }
+            Instr::Exts(val) => {
+                self.expect_result(result, &[Type::Double]);
+                self.expect(val, &Type::Single);
+            }
+            Instr::Truncd(val) => {
+                self.expect_result(result, &[Type::Single]);
+                self.expect(val, &Type::Double);
+            }
+            Instr::Stosi(val) | Instr::Stoui(val) => {
+                self.expect_result(result, INTEGER);
+                self.expect(val, &Type::Single);
+            }
+            Instr::Dtosi(val) | Instr::Dtoui(val) => {
+                self.expect_result(result, INTEGER);
+                self.expect(val, &Type::Double);
+            }
+            Instr::Swtof(val) | Instr::Uwtof(val) => {
+                self.expect_result(result, FLOAT);
+                self.expect(val, &Type::Word);
+            }
+            Instr::Sltof(val) | Instr::Ultof(val) => {
+                self.expect_result(result, FLOAT);
+                self.expect(val, &Type::Long);
+            }
+            Instr::Vastart(val) => {
+                self.expect_no_result(result);
+                self.expect(val, &Type::Long);
+            }
+            Instr::Vaarg(ty, val) => {
+                if let Some(found) = &base {
+                    let ty = temp_type(ty);
+                    if *found != ty {
+                        self.report(TypeErrorKind::ResultType {
+                            expected: vec![ty],
+                            found: found.clone(),
+                        });
+                    }
+                }
+                self.expect(val, &Type::Long);
+            }
+            Instr::Call(callee, args, _) => {
+                self.expect(callee, &Type::Long);
+                for (ty, val) in args {
+                    self.expect(val, &temp_type(ty));
+                }
+            }
+            Instr::Jnz(cond, _, _) => {
+                self.expect_no_result(result);
+                self.expect(cond, &Type::Word);
+            }
+            Instr::Ret(val) => {
+                self.expect_no_result(result);
+                if let (Some(val), Some(ty)) = (val, &self.func.return_ty) {
+                    self.expect(val, &temp_type(ty));
+                }
+            }
+            Instr::Jmp(_) | Instr::DbgFile(_) | Instr::DbgLoc(..) | Instr::Hlt => {
+                self.expect_no_result(result)
+            }
+        }
+    }
+
+    fn type_of(&self, val: &Value) -> Option<Type> {
+        match val {
+            Value::Temporary(name) => self.temps.get(name.as_str()).cloned(),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, val: &Value, expected: &Type) {
+        if let Some(found) = self.type_of(val) {
+            if !fits(&found, expected) {
+                self.report(TypeErrorKind::OperandType {
+                    operand: val.clone(),
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+        } else if !constant_fits(val, expected) {
+            self.report(TypeErrorKind::ConstantType {
+                operand: val.clone(),
+                expected: expected.clone(),
+            });
+        }
+    }
+
+    /// Checks that every value has the instruction's result type, if it is
+    /// assigned
+    fn expect_same(&mut self, base: &Option<Type>, vals: &[&Value]) {
+        if let Some(ty) = base {
+            for val in vals {
+                self.expect(val, ty);
+            }
+        }
+    }
+
+    fn expect_result(&mut self, result: Option<&Type>, expected: &[Type]) {
+        if let Some(found) = result {
+            let found = temp_type(found);
+            if !expected.contains(&found) {
+                self.report(TypeErrorKind::ResultType {
+                    expected: expected.to_vec(),
+                    found,
+                });
+            }
+        }
+    }
+
+    fn expect_no_result(&mut self, result: Option<&Type>) {
+        if result.is_some() {
+            self.report(TypeErrorKind::NoResult);
+        }
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::tests::temp;
+use crate::*;
+use std::sync::Arc;
+
+fn at(item: usize) -> Location {
+    Location::Function {
+        name: "f".into(),
+        block: Some("start".into()),
+        item: Some(item),
+    }
+}
+
+/// Typechecks a function `$f` with the given parameters whose single block
+/// is built by `build`, returning the error kinds with their locations
+fn errors_of(
+    params: Vec<(Type, Value)>,
+    return_ty: Option<Type>,
+    build: impl FnOnce(&mut Block),
+) -> Vec<(Location, TypeErrorKind)> {
+    let mut module = Module::new();
+    let mut func = Function::new(Linkage::private(), "f", params, return_ty);
+    build(func.add_block("start"));
+    module.add_function(func);
+    match module.typecheck() {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .into_iter()
+            .map(|err| (err.location, err.kind))
+            .collect(),
+    }
+}
+
+fn operand(name: &str, expected: Type, found: Type) -> TypeErrorKind {
+    TypeErrorKind::OperandType {
+        operand: temp(name),
+        expected,
+        found,
+    }
+}
+
+fn constant(operand: Value, expected: Type) -> TypeErrorKind {
+    TypeErrorKind::ConstantType { operand, expected }
+}
+
+#[test]
+fn well_typed_function_passes() {
+    let pair = Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    });
+    let errors = errors_of(
+        vec![
+            (Type::Env, temp("env")),
+            (Type::Long, temp("n")),
+            (Type::SignedByte, temp("b")),
+            (Type::Aggregate(pair), temp("p")),
+            (Type::Double, temp("x")),
+        ],
+        Some(Type::Word),
+        |b| {
+            // A long used as a word is truncated
+            b.assign_instr(temp("w"), Type::Word, Instr::Add(temp("n"), temp("b")));
+            b.assign_instr(temp("l"), Type::Long, Instr::Extsw(temp("w")));
+            b.assign_instr(temp("sh"), Type::Long, Instr::Shl(temp("l"), temp("w")));
+            b.assign_instr(temp("i"), Type::Long, Instr::Cast(temp("x")));
+            b.assign_instr(temp("s"), Type::Single, Instr::Truncd(temp("x")));
+            b.assign_instr(
+                temp("c"),
+                Type::Word,
+                Instr::Cmp(Type::Double, Cmp::Uo, temp("x"), 1.5.into()),
+            );
+            b.assign_instr(
+                temp("ld"),
+                Type::Long,
+                Instr::Load(Type::SignedHalfword, temp("p")),
+            );
+            b.add_instr(Instr::Store(Type::UnsignedByte, temp("p"), temp("w")));
+            b.add_instr(Instr::Store(Type::Single, temp("env"), temp("s")));
+            b.assign_instr(
+                temp("r"),
+                Type::SignedByte,
+                Instr::Call(temp("env"), vec![(Type::UnsignedHalfword, temp("b"))], None),
+            );
+            b.add_instr(Instr::Ret(Some(temp("r"))));
+        },
+    );
+    assert_eq!(errors, vec![]);
+}
+
+#[test]
+fn integer_only_instructions() {
+    let errors = errors_of(vec![(Type::Single, temp("x"))], None, |b| {
+        b.assign_instr(temp("a"), Type::Single, Instr::Rem(temp("x"), temp("x")));
+        b.assign_instr(temp("b"), Type::Double, Instr::Udiv(1.into(), 2.into()));
+        b.assign_instr(temp("c"), Type::Word, Instr::Sar(1.into(), temp("x")));
+        b.add_instr(Instr::Ret(None));
+    });
+    assert_eq!(
+        errors,
+        vec![
+            (
+                at(0),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Word, Type::Long],
+                    found: Type::Single,
+                }
+            ),
+            (
+                at(1),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Word, Type::Long],
+                    found: Type::Double,
+                }
+            ),
+            (at(1), constant(1.into(), Type::Double)),
+            (at(1), constant(2.into(), Type::Double)),
+            (at(2), operand("x", Type::Word, Type::Single)),
+        ]
+    );
+}
+
+#[test]
+fn constants_match_operand_type() {
+    let errors = errors_of(vec![], None, |b| {
+        b.assign_instr(temp("a"), Type::Word, Instr::Add(1.into(), 1.0f32.into()));
+        b.assign_instr(
+            temp("b"),
+            Type::Double,
+            Instr::Add(2.5f64.into(), Value::Global("g".into())),
+        );
+        b.assign_instr(
+            temp("c"),
+            Type::Single,
+            Instr::Mul(1.0f32.into(), 2.0f64.into()),
+        );
+        b.assign_instr(temp("d"), Type::Long, Instr::Sub((-1).into(), 1.into()));
+        b.assign_instr(temp("e"), Type::Double, Instr::Neg(0.5f64.into()));
+        b.add_instr(Instr::Ret(None));
+    });
+    assert_eq!(
+        errors,
+        vec![
+            (at(0), constant(1.0f32.into(), Type::Word)),
+            (at(1), constant(Value::Global("g".into()), Type::Double)),
+            (at(2), constant(2.0f64.into(), Type::Single)),
+        ]
+    );
+    assert_eq!(
+        errors[0].1.to_string(),
+        "expected `w` operand, got constant `s_1.0`"
+    );
+}
+
+#[test]
+fn operands_match_result() {
+    let errors = errors_of(
+        vec![(Type::Word, temp("w")), (Type::Double, temp("d"))],
+        Some(Type::Long),
+        |b| {
+            b.assign_instr(temp("a"), Type::Long, Instr::Add(temp("w"), temp("d")));
+            b.assign_instr(
+                temp("b"),
+                Type::Long,
+                Instr::Copy(Value::Global("g".into())),
+            );
+            b.add_instr(Instr::Ret(Some(temp("w"))));
+        },
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (at(0), operand("w", Type::Long, Type::Word)),
+            (at(0), operand("d", Type::Long, Type::Double)),
+            (at(2), operand("w", Type::Long, Type::Word)),
+        ]
+    );
+}
+
+#[test]
+fn comparisons() {
+    let errors = errors_of(
+        vec![(Type::Word, temp("w")), (Type::Single, temp("s"))],
+        None,
+        |b| {
+            b.assign_instr(
+                temp("a"),
+                Type::Word,
+                Instr::Cmp(Type::Long, Cmp::Eq, temp("w"), temp("s")),
+            );
+            b.assign_instr(
+                temp("b"),
+                Type::Single,
+                Instr::Cmp(Type::Single, Cmp::Lt, temp("s"), temp("s")),
+            );
+            b.assign_instr(
+                temp("c"),
+                Type::Word,
+                Instr::Cmp(Type::Word, Cmp::O, temp("w"), temp("w")),
+            );
+            b.assign_instr(
+                temp("d"),
+                Type::Word,
+                Instr::Cmp(Type::Single, Cmp::Ult, temp("s"), temp("s")),
+            );
+            b.assign_instr(
+                temp("e"),
+                Type::Word,
+                Instr::Cmp(Type::Byte, Cmp::Eq, temp("w"), temp("w")),
+            );
+            b.assign_instr(
+                temp("f"),
+                Type::Word,
+                Instr::Cmp(Type::Single, Cmp::Slt, temp("s"), temp("s")),
+            );
+            b.assign_instr(
+                temp("g"),
+                Type::Word,
+                Instr::Cmp(Type::Word, Cmp::Ge, temp("w"), temp("w")),
+            );
+            b.add_instr(Instr::Ret(None));
+        },
+    );
+    let messages: Vec<String> = errors.iter().map(|(_, kind)| kind.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "expected `l` operand, `%w` is `w`",
+            "expected `l` operand, `%s` is `s`",
+            "instruction can't produce `s`: expected `w` or `l`",
+            "`o` and `uo` comparisons need a floating-point type, got `w`",
+            "unsigned comparisons need an integer type, got `s`",
+            "cannot compare `b`: use a base type",
+            "signed comparisons need an integer type, got `s`: \
+             use `lt`, `le`, `gt` or `ge` for floats",
+            "floating-point comparisons need a floating-point type, got `w`: \
+             use `slt`, `sle`, `sgt` or `sge` for integers",
+        ]
+    );
+}
+
+#[test]
+fn conversions() {
+    let errors = errors_of(
+        vec![
+            (Type::Word, temp("w")),
+            (Type::Long, temp("l")),
+            (Type::Single, temp("s")),
+        ],
+        None,
+        |b| {
+            b.assign_instr(temp("a"), Type::Word, Instr::Extsw(temp("w")));
+            b.assign_instr(temp("b"), Type::Long, Instr::Extuw(temp("s")));
+            b.assign_instr(temp("c"), Type::Single, Instr::Exts(temp("s")));
+            b.assign_instr(temp("d"), Type::Double, Instr::Cast(temp("w")));
+            b.assign_instr(temp("e"), Type::Word, Instr::Cast(temp("l")));
+            b.assign_instr(temp("f"), Type::Single, Instr::Sltof(temp("l")));
+            b.add_instr(Instr::Ret(None));
+        },
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (
+                at(0),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Long],
+                    found: Type::Word,
+                }
+            ),
+            (at(1), operand("s", Type::Word, Type::Single)),
+            (
+                at(2),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Double],
+                    found: Type::Single,
+                }
+            ),
+            (at(3), operand("w", Type::Long, Type::Word)),
+            // Cast needs the exact width, a long doesn't fit a single
+            (at(4), operand("l", Type::Single, Type::Long)),
+        ]
+    );
+}
+
+#[test]
+fn memory() {
+    let errors = errors_of(
+        vec![(Type::Word, temp("p")), (Type::Double, temp("d"))],
+        None,
+        |b| {
+            b.add_instr(Instr::Store(Type::Single, temp("p"), temp("d")));
+            b.add_instr(Instr::Store(
+                Type::Long,
+                Value::Global("g".into()),
+                temp("d"),
+            ));
+            b.assign_instr(temp("a"), Type::Word, Instr::Load(Type::Double, temp("p")));
+            b.assign_instr(temp("b"), Type::Word, Instr::Alloc8(8.into()));
+            b.assign_instr(
+                temp("c"),
+                Type::Word,
+                Instr::Store(Type::Word, temp("b"), 1.into()),
+            );
+            b.add_instr(Instr::Ret(None));
+        },
+    );
+    assert_eq!(
+        errors,
+        vec![
+            (at(0), operand("p", Type::Long, Type::Word)),
+            (at(0), operand("d", Type::Single, Type::Double)),
+            (at(1), operand("d", Type::Long, Type::Double)),
+            (
+                at(2),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Double],
+                    found: Type::Word,
+                }
+            ),
+            (at(2), operand("p", Type::Long, Type::Word)),
+            (
+                at(3),
+                TypeErrorKind::ResultType {
+                    expected: vec![Type::Long],
+                    found: Type::Word,
+                }
+            ),
+            (at(4), TypeErrorKind::NoResult),
+            (at(4), operand("b", Type::Long, Type::Word)),
+        ]
+    );
+}
+
+#[test]
+fn conflicting_assignments() {
+    let mut module = Module::new();
+    let mut func = Function::new(Linkage::private(), "f", vec![(Type::Word, temp("x"))], None);
+    func.add_block("start").add_instr(Instr::Jmp("next".into()));
+    let next = func.add_block("next");
+    next.assign_instr(temp("x"), Type::Long, Instr::Copy(1.into()));
+    next.assign_instr(
+        temp("y"),
+        Type::Word,
+        Instr::Phi(vec![("start".into(), temp("x"))]),
+    );
+    next.add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    let errors: Vec<String> = module
+        .typecheck()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "function $f, block @next, item 0: %x is assigned as `l` but was first assigned as `w`",
+        ]
+    );
+}
@@ -0,0 +1,344 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Collecting and ordering the aggregate types used by a module.
+
+use crate::{
+    BlockItem, Location, Module, Statement, Type, TypeDef, ValidationError, ValidationErrorKind,
+};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+#[cfg(test)]
+mod tests;
+
+impl TypeDef {
+    /// Returns the types of the members, in order, across all variations of
+    /// a union
+    pub(crate) fn member_types(&self) -> Vec<&Type> {
+        match self {
+            TypeDef::Regular { items, .. } => items.iter().map(|(ty, _)| ty).collect(),
+            TypeDef::Union { variations, .. } => variations
+                .iter()
+                .flat_map(|items| items.iter().map(|(ty, _)| ty))
+                .collect(),
+            TypeDef::Opaque { .. } => Vec::new(),
+        }
+    }
+}
+
+impl Module {
+    /// Returns the type definitions to emit: [`Module::types`] followed by
+    /// every other [`TypeDef`] referenced by the module's functions, data
+    /// and types, ordered so that each type comes after the types it
+    /// contains.
+    ///
+    /// Types are identified by their identifier. Definitions sharing an
+    /// identifier are merged if they are equal and reported as
+    /// [`ValidationErrorKind::DuplicateType`] otherwise. A type that contains
+    /// a type with its own identifier is reported as
+    /// [`ValidationErrorKind::RecursiveType`].
+    ///
+    /// Formatting uses this order, so types don't need to be added with
+    /// [`Module::add_type`], nor added in any particular order.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    /// use qbe::{DataDef, DataItem, Linkage, Module, Type, TypeDef};
+    ///
+    /// let point = Arc::new(TypeDef::Regular {
+    ///     ident: "point".into(),
+    ///     align: None,
+    ///     items: vec![(Type::Word, 2)],
+    /// });
+    /// let line = Arc::new(TypeDef::Regular {
+    ///     ident: "line".into(),
+    ///     align: None,
+    ///     items: vec![(Type::Aggregate(point), 2)],
+    /// });
+    ///
+    /// let mut module = Module::new();
+    /// module.add_type(Arc::clone(&line));
+    ///
+    /// let idents: Vec<String> = module
+    ///     .sorted_types()
+    ///     .unwrap()
+    ///     .iter()
+    ///     .map(|def| def.ident().to_string())
+    ///     .collect();
+    /// assert_eq!(idents, ["point", "line"]);
+    /// assert!(module.to_string().starts_with("type :point = { w 2 }\ntype :line"));
+    /// ```
+    pub fn sorted_types(&self) -> Result<Vec<Arc<TypeDef>>, Vec<ValidationError>> {
+        let mut sorter = TypeSorter::default();
+        for def in &self.types {
+            sorter.visit(def);
+        }
+        self.for_each_type(|ty| {
+            if let Type::Aggregate(def) = ty {
+                sorter.visit(def);
+            }
+        });
+
+        if sorter.errors.is_empty() {
+            Ok(sorter.sorted)
+        } else {
+            Err(sorter.errors)
+        }
+    }
+
+    /// Merges structurally identical type definitions and makes every
+    /// [`Type::Aggregate`] in the module refer to the merged definition.
+    ///
+    /// Two definitions are identical if they have the same alignment and
+    /// members, after merging the types of their members; identifiers don't
+    /// matter. The first definition found is kept, looking at
+    /// [`Module::types`] first and then at the functions and data in module
+    /// order. Afterwards [`Module::types`] holds the kept versions of its
+    /// definitions, without duplicates.
+    ///
+    /// Different definitions sharing an identifier are reported as
+    /// [`ValidationErrorKind::DuplicateType`], and the module is left
+    /// unchanged.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    /// use qbe::{DataDef, DataItem, Linkage, Module, Type, TypeDef};
+    ///
+    /// let pair = |ident: &str| {
+    ///     Type::Aggregate(Arc::new(TypeDef::Regular {
+    ///         ident: ident.into(),
+    ///         align: None,
+    ///         items: vec![(Type::Word, 2)],
+    ///     }))
+    /// };
+    ///
+    /// let mut module = Module::new();
+    /// for (name, ty) in [("a", pair("pair")), ("b", pair("point"))] {
+    ///     module.add_data(DataDef::new(
+    ///         Linkage::private(),
+    ///         name,
+    ///         None,
+    ///         vec![(ty, DataItem::Zero(8))],
+    ///     ));
+    /// }
+    ///
+    /// module.intern_types().unwrap();
+    /// assert_eq!(module.data[1].items[0].0, pair("pair"));
+    /// assert_eq!(module.sorted_types().unwrap().len(), 1);
+    /// ```
+    pub fn intern_types(&mut self) -> Result<(), Vec<ValidationError>> {
+        let mut interner = Interner::default();
+        let types: Vec<Arc<TypeDef>> = self.types.iter().map(|def| interner.intern(def)).collect();
+        self.for_each_type(|ty| {
+            if let Type::Aggregate(def) = ty {
+                interner.intern(def);
+            }
+        });
+        if !interner.errors.is_empty() {
+            return Err(interner.errors);
+        }
+
+        let mut seen = HashSet::new();
+        self.types = types
+            .into_iter()
+            .filter(|def| seen.insert(Arc::as_ptr(def)))
+            .collect();
+        self.for_each_type_mut(|ty| {
+            if let Type::Aggregate(def) = ty {
+                *def = interner.intern(def);
+            }
+        });
+        Ok(())
+    }
+
+    /// Calls `f` with every type used by functions and data, in module
+    /// order. Members of aggregate types are not visited.
+    pub(crate) fn for_each_type<'a>(&'a self, mut f: impl FnMut(&'a Type)) {
+        for func in &self.functions {
+            func.arguments.iter().for_each(|(ty, _)| f(ty));
+            func.return_ty.iter().for_each(&mut f);
+            for block in &func.blocks {
+                for item in &block.items {
+                    let instr = match item {
+                        BlockItem::Statement(Statement::Assign(_, ty, instr)) => {
+                            f(ty);
+                            instr
+                        }
+                        BlockItem::Statement(Statement::Volatile(instr)) => instr,
+                        BlockItem::Comment(_) => continue,
+                    };
+                    instr.types().into_iter().for_each(&mut f);
+                }
+            }
+        }
+        for data in &self.data {
+            data.items.iter().for_each(|(ty, _)| f(ty));
+        }
+    }
+
+    /// Mutable version of [`Module::for_each_type`]
+    fn for_each_type_mut(&mut self, mut f: impl FnMut(&mut Type)) {
+        for func in &mut self.functions {
+            func.arguments.iter_mut().for_each(|(ty, _)| f(ty));
+            func.return_ty.iter_mut().for_each(&mut f);
+            for block in &mut func.blocks {
+                for item in &mut block.items {
+                    let instr = match item {
+                        BlockItem::Statement(Statement::Assign(_, ty, instr)) => {
+                            f(ty);
+                            instr
+                        }
+                        BlockItem::Statement(Statement::Volatile(instr)) => instr,
+                        BlockItem::Comment(_) => continue,
+                    };
+                    instr.types_mut().into_iter().for_each(&mut f);
+                }
+            }
+        }
+        for data in &mut self.data {
+            data.items.iter_mut().for_each(|(ty, _)| f(ty));
+        }
+    }
+}
+
+enum Mark {
+    /// The type's members are being visited
+    Visiting,
+    /// The type has been added to the sorted list
+    Done,
+}
+
+#[derive(Default)]
+struct TypeSorter {
+    /// First definition seen for each identifier
+    seen: HashMap<String, (Arc<TypeDef>, Mark)>,
+    sorted: Vec<Arc<TypeDef>>,
+    errors: Vec<ValidationError>,
+}
+
+impl TypeSorter {
+    /// Adds `def` after the types it contains, unless a type with its
+    /// identifier was already added
+    fn visit(&mut self, def: &Arc<TypeDef>) {
+        let ident = def.ident();
+        if let Some((first, mark)) = self.seen.get(ident) {
+            let kind = match mark {
+                Mark::Visiting => ValidationErrorKind::RecursiveType(ident.to_string()),
+                Mark::Done if Arc::ptr_eq(first, def) || first == def => return,
+                Mark::Done => ValidationErrorKind::DuplicateType(ident.to_string()),
+            };
+            let error = ValidationError {
+                location: Location::Type(ident.to_string()),
+                kind,
+            };
+            if !self.errors.contains(&error) {
+                self.errors.push(error);
+            }
+            return;
+        }
+
+        self.seen
+            .insert(ident.to_string(), (Arc::clone(def), Mark::Visiting));
+        for ty in def.member_types() {
+            if let Type::Aggregate(member) = ty {
+                self.visit(member);
+            }
+        }
+        self.seen.get_mut(ident).unwrap().1 = Mark::Done;
+        self.sorted.push(Arc::clone(def));
+    }
+}
+
+/// Maps type definitions to the first structurally identical one
+#[derive(Default)]
+struct Interner {
+    /// Merged definition of every definition seen, by address
+    merged: HashMap<*const TypeDef, Arc<TypeDef>>,
+    /// Kept definitions, by shape
+    shapes: HashMap<TypeDef, Arc<TypeDef>>,
+    /// Shape of the first definition seen for each identifier
+    idents: HashMap<String, TypeDef>,
+    errors: Vec<ValidationError>,
+}
+
+impl Interner {
+    fn intern(&mut self, def: &Arc<TypeDef>) -> Arc<TypeDef> {
+        if let Some(merged) = self.merged.get(&Arc::as_ptr(def)) {
+            return Arc::clone(merged);
+        }
+
+        // Members first, so identical members compare equal
+        let mut rebuilt = TypeDef::clone(def);
+        let mut changed = false;
+        for ty in rebuilt.member_types_mut() {
+            if let Type::Aggregate(member) = ty {
+                let merged = self.intern(member);
+                changed |= !Arc::ptr_eq(member, &merged);
+                *member = merged;
+            }
+        }
+
+        let shape = rebuilt.shape();
+        let ident = def.ident();
+        match self.idents.get(ident) {
+            Some(first) if *first != shape => {
+                let error = ValidationError {
+                    location: Location::Type(ident.to_string()),
+                    kind: ValidationErrorKind::DuplicateType(ident.to_string()),
+                };
+                if !self.errors.contains(&error) {
+                    self.errors.push(error);
+                }
+            }
+            Some(_) => {}
+            None => {
+                self.idents.insert(ident.to_string(), shape.clone());
+            }
+        }
+
+        let rebuilt = if changed {
+            Arc::new(rebuilt)
+        } else {
+            Arc::clone(def)
+        };
+        let merged = Arc::clone(self.shapes.entry(shape).or_insert(rebuilt));
+        self.merged.insert(Arc::as_ptr(def), Arc::clone(&merged));
+        merged
+    }
+}
+
+impl TypeDef {
+    /// Mutable version of [`TypeDef::member_types`]
+    fn member_types_mut(&mut self) -> Vec<&mut Type> {
+        match self {
+            TypeDef::Regular { items, .. } => items.iter_mut().map(|(ty, _)| ty).collect(),
+            TypeDef::Union { variations, .. } => variations
+                .iter_mut()
+                .flat_map(|items| items.iter_mut().map(|(ty, _)| ty))
+                .collect(),
+            TypeDef::Opaque { .. } => Vec::new(),
+        }
+    }
+
+    /// Returns the definition without its identifier
+    fn shape(&self) -> TypeDef {
+        let mut shape = self.clone();
+        match &mut shape {
+            TypeDef::Regular { ident, .. }
+            | TypeDef::Union { ident, .. }
+            | TypeDef::Opaque { ident, .. } => ident.clear(),
+        }
+        shape
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::*;
+use std::sync::Arc;
+
+fn regular(ident: &str, items: Vec<(Type, usize)>) -> Arc<TypeDef> {
+    Arc::new(TypeDef::Regular {
+        ident: ident.into(),
+        align: None,
+        items,
+    })
+}
+
+fn idents(module: &Module) -> Vec<String> {
+    module
+        .sorted_types()
+        .unwrap()
+        .iter()
+        .map(|def| def.ident().to_string())
+        .collect()
+}
+
+#[test]
+fn members_come_first() {
+    let inner = regular("inner", vec![(Type::Word, 1)]);
+    let middle = Arc::new(TypeDef::Union {
+        ident: "middle".into(),
+        align: None,
+        variations: vec![
+            vec![(Type::Long, 1)],
+            vec![(Type::Aggregate(Arc::clone(&inner)), 2)],
+        ],
+    });
+    let outer = regular(
+        "outer",
+        vec![
+            (Type::Aggregate(Arc::clone(&middle)), 1),
+            (Type::Aggregate(Arc::clone(&inner)), 1),
+        ],
+    );
+
+    let mut module = Module::new();
+    module.add_type(Arc::clone(&outer));
+    module.add_type(Arc::clone(&middle));
+    module.add_type(inner);
+    assert_eq!(idents(&module), ["inner", "middle", "outer"]);
+
+    // A well-ordered module keeps its order
+    let mut module = Module::new();
+    module.add_type(regular("b", vec![(Type::Byte, 1)]));
+    module.add_type(regular("a", vec![(Type::Byte, 1)]));
+    assert_eq!(idents(&module), ["b", "a"]);
+}
+
+#[test]
+fn referenced_types_are_collected() {
+    let arg = regular("arg", vec![(Type::Word, 1)]);
+    let ret = regular("ret", vec![(Type::Aggregate(Arc::clone(&arg)), 1)]);
+    let call = regular("call", vec![(Type::Long, 1)]);
+    let data = regular("data", vec![(Type::Byte, 4)]);
+    let unused = regular("unused", vec![(Type::Byte, 1)]);
+
+    let mut module = Module::new();
+    module.add_type(unused);
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(
+            Type::Aggregate(Arc::clone(&arg)),
+            Value::Temporary("a".into()),
+        )],
+        Some(Type::Aggregate(ret)),
+    );
+    let start = func.add_block("start");
+    start.assign_instr(
+        Value::Temporary("r".into()),
+        Type::Word,
+        Instr::Call(
+            Value::Global("g".into()),
+            vec![(Type::Aggregate(call), Value::Temporary("a".into()))],
+            None,
+        ),
+    );
+    start.add_instr(Instr::Ret(Some(Value::Temporary("a".into()))));
+    module.add_function(func);
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "d",
+        None,
+        vec![(Type::Aggregate(data), DataItem::Zero(4))],
+    ));
+
+    assert_eq!(idents(&module), ["unused", "arg", "ret", "call", "data"]);
+    assert!(module.to_string().starts_with(
+        "type :unused = { b }\ntype :arg = { w }\ntype :ret = { :arg }\ntype :call = { l }\ntype :data = { b 4 }\n"
+    ));
+}
+
+#[test]
+fn equal_definitions_are_merged() {
+    let mut module = Module::new();
+    module.add_type(regular("pair", vec![(Type::Word, 2)]));
+    module.add_type(regular("pair", vec![(Type::Word, 2)]));
+    module.add_type(regular(
+        "user",
+        vec![(Type::Aggregate(regular("pair", vec![(Type::Word, 2)])), 1)],
+    ));
+    assert_eq!(idents(&module), ["pair", "user"]);
+    assert_eq!(module.to_string().matches("type :pair").count(), 1);
+}
+
+#[test]
+fn conflicts_are_rejected() {
+    let mut module = Module::new();
+    module.add_type(regular("pair", vec![(Type::Word, 2)]));
+    module.add_type(regular("pair", vec![(Type::Long, 2)]));
+    // `type :node = { l, :node }`, through a different definition
+    module.add_type(regular(
+        "node",
+        vec![
+            (Type::Long, 1),
+            (Type::Aggregate(regular("node", vec![(Type::Long, 1)])), 1),
+        ],
+    ));
+
+    let errors = module.sorted_types().unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "type :pair: conflicting definitions of type :pair",
+            "type :node: type :node contains itself",
+        ]
+    );
+    assert_eq!(module.validate().unwrap_err(), errors);
+    assert!(matches!(
+        module.try_to_string(),
+        Err(EmitError::Invalid(err)) if err == errors[0]
+    ));
+}
+
+#[test]
+#[should_panic(expected = "conflicting definitions of type :pair")]
+fn display_panics_on_conflicts() {
+    let mut module = Module::new();
+    module.add_type(regular("pair", vec![(Type::Word, 2)]));
+    module.add_type(regular("pair", vec![(Type::Long, 2)]));
+    let _ = module.to_string();
+}
+
+#[test]
+fn interning_merges_identical_structures() {
+    let pair = regular("pair", vec![(Type::Word, 2)]);
+    let point = regular("point", vec![(Type::Word, 2)]);
+    let line = regular("line", vec![(Type::Aggregate(Arc::clone(&point)), 2)]);
+
+    let mut module = Module::new();
+    module.add_type(Arc::clone(&pair));
+    module.add_type(Arc::clone(&line));
+    module.add_type(Arc::clone(&pair));
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(
+            Type::Aggregate(Arc::clone(&point)),
+            Value::Temporary("p".into()),
+        )],
+        None,
+    );
+    let start = func.add_block("start");
+    start.add_instr(Instr::Call(
+        Value::Global("g".into()),
+        vec![(
+            Type::Aggregate(regular("pair", vec![(Type::Word, 2)])),
+            Value::Temporary("p".into()),
+        )],
+        None,
+    ));
+    start.add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    module.intern_types().unwrap();
+
+    assert_eq!(idents(&module), ["pair", "line"]);
+    assert_eq!(module.types.len(), 2);
+    let TypeDef::Regular { items, .. } = &*module.types[1] else {
+        unreachable!()
+    };
+    assert_eq!(items[0].0, Type::Aggregate(Arc::clone(&pair)));
+
+    // Every reference points at the same definition
+    let mut refs = Vec::new();
+    module.for_each_type(|ty| {
+        if let Type::Aggregate(def) = ty {
+            refs.push(Arc::clone(def));
+        }
+    });
+    assert_eq!(refs.len(), 2);
+    assert!(refs.iter().all(|def| Arc::ptr_eq(def, &module.types[0])));
+    assert!(matches!(&items[0].0, Type::Aggregate(def) if Arc::ptr_eq(def, &module.types[0])));
+}
+
+#[test]
+fn interning_rejects_conflicts() {
+    let mut module = Module::new();
+    module.add_type(regular("pair", vec![(Type::Word, 2)]));
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "d",
+        None,
+        vec![(
+            Type::Aggregate(regular("pair", vec![(Type::Long, 2)])),
+            DataItem::Zero(16),
+        )],
+    ));
+    let before = module.clone();
+
+    let errors = module.intern_types().unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec!["type :pair: conflicting definitions of type :pair"]
+    );
+    assert_eq!(module, before);
+}
@@ -0,0 +1,501 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Structural checks on modules before they are emitted.
+
+use crate::parse::{is_ident_char, is_ident_start};
+use crate::{
+    Block, BlockItem, DataItem, Function, Instr, Location, Module, Statement, Type, Value,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// A problem found by [`Module::validate`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct ValidationError {
+    /// Where the problem was found
+    pub location: Location,
+    /// What is wrong
+    pub kind: ValidationErrorKind,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.location, self.kind)
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Kind of a [`ValidationError`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum ValidationErrorKind {
+    /// A name that QBE can't lex, with its sigil, e.g. `%my var`
+    InvalidIdentifier(String),
+    /// A label defined by more than one block
+    DuplicateLabel(String),
+    /// A jump or `phi` referring to a block that doesn't exist
+    UndefinedLabel(String),
+    /// A function without blocks
+    EmptyFunction,
+    /// The last block doesn't end with a jump, `ret` or `hlt`
+    FallsOffEnd,
+    /// An instruction following a jump, `ret` or `hlt` in the same block
+    InstructionAfterJump,
+    /// `ret` without a value in a function with a return type
+    MissingReturnValue,
+    /// `ret` with a value in a function without a return type
+    UnexpectedReturnValue,
+    /// A `phi` following other instructions in its block
+    PhiNotAtBlockHead,
+    /// A function parameter that isn't a temporary
+    InvalidParameter(Value),
+    /// An assignment to something other than a temporary
+    InvalidAssignTarget(Value),
+    /// A non-call assignment with a type other than `w`, `l`, `s` or `d`
+    InvalidAssignType(Type),
+    /// A parameter, argument, return or call result type that fails
+    /// [`Type::is_abi`]
+    InvalidAbiType(Type),
+    /// [`Type::Env`] anywhere but first in a parameter or argument list, or
+    /// after the variadic marker
+    MisplacedEnv,
+    /// A call to a constant
+    InvalidCallTarget(Value),
+    /// A call whose variadic marker comes after more arguments than it has
+    InvalidVariadicIndex {
+        /// Index of the variadic marker
+        index: u64,
+        /// Number of arguments of the call
+        args: usize,
+    },
+    /// A load of `b`, `h` or an aggregate, which doesn't say how to extend
+    /// the value
+    InvalidLoadType(Type),
+    /// A store of an aggregate
+    InvalidStoreType(Type),
+    /// A comparison of aggregates
+    InvalidCompareType(Type),
+    /// A `z` item or a nested list inside a [`DataItem::List`]
+    InvalidListItem(DataItem),
+    /// Different type definitions sharing an identifier
+    DuplicateType(String),
+    /// A type definition containing a type with its own identifier
+    RecursiveType(String),
+}
+
+impl fmt::Display for ValidationErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::InvalidIdentifier(name) => write!(f, "invalid identifier `{name}`"),
+            Self::DuplicateLabel(label) => write!(f, "duplicate block label @{label}"),
+            Self::UndefinedLabel(label) => write!(f, "undefined block label @{label}"),
+            Self::EmptyFunction => write!(f, "function has no blocks"),
+            Self::FallsOffEnd => write!(
+                f,
+                "last block falls off the end of the function: end it with a jump, `ret` or `hlt`"
+            ),
+            Self::InstructionAfterJump => {
+                write!(f, "instruction after a jump: start a new block first")
+            }
+            Self::MissingReturnValue => {
+                write!(f, "`ret` without a value in a function with a return type")
+            }
+            Self::UnexpectedReturnValue => {
+                write!(f, "`ret` with a value in a function without a return type")
+            }
+            Self::PhiNotAtBlockHead => {
+                write!(f, "`phi` must come before other instructions in its block")
+            }
+            Self::InvalidParameter(val) => {
+                write!(f, "parameter must be a temporary, got `{val}`")
+            }
+            Self::InvalidAssignTarget(val) => {
+                write!(f, "assignment target must be a temporary, got `{val}`")
+            }
+            Self::InvalidAssignType(ty) => {
+                write!(f, "assignment must use a base type, got `{ty}`")
+            }
+            Self::InvalidAbiType(ty) => write!(
+                f,
+                "invalid ABI type `{ty}`: use a base, signed/unsigned sub-word or aggregate type"
+            ),
+            Self::MisplacedEnv => write!(
+                f,
+                "env must be the first and only environment parameter or argument"
+            ),
+            Self::InvalidCallTarget(val) => {
+                write!(
+                    f,
+                    "call target must be a global or a temporary, got `{val}`"
+                )
+            }
+            Self::InvalidVariadicIndex { index, args } => write!(
+                f,
+                "variadic marker at index {index} is past the end of {args} arguments"
+            ),
+            Self::InvalidLoadType(ty) => write!(
+                f,
+                "cannot load `{ty}`: use a base type or a signed/unsigned sub-word type"
+            ),
+            Self::InvalidStoreType(ty) => write!(f, "cannot store aggregate type `{ty}`"),
+            Self::InvalidCompareType(ty) => write!(f, "cannot compare aggregate type `{ty}`"),
+            Self::InvalidListItem(item) => write!(
+                f,
+                "list items must be symbols, strings or constants, got `{item}`"
+            ),
+            Self::DuplicateType(ident) => {
+                write!(f, "conflicting definitions of type :{ident}")
+            }
+            Self::RecursiveType(ident) => write!(f, "type :{ident} contains itself"),
+        }
+    }
+}
+
+impl Module {
+    /// Checks the module for structural problems that QBE would reject or
+    /// that would make formatting panic.
+    ///
+    /// All problems are reported, in module order. Types of operands are
+    /// checked by [`Module::typecheck`].
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use qbe::{Function, Instr, Linkage, Module, Type, ValidationErrorKind};
+    ///
+    /// let mut module = Module::new();
+    /// let mut func = Function::new(Linkage::private(), "f", vec![], Some(Type::Word));
+    /// func.add_block("start").add_instr(Instr::Jmp("missing".into()));
+    /// module.add_function(func);
+    ///
+    /// let errors = module.validate().unwrap_err();
+    /// assert_eq!(
+    ///     errors[0].kind,
+    ///     ValidationErrorKind::UndefinedLabel("missing".into())
+    /// );
+    /// assert_eq!(
+    ///     errors[0].to_string(),
+    ///     "function $f, block @start, item 0: undefined block label @missing"
+    /// );
+    /// ```
+    pub fn validate(&self) -> Result<(), Vec<ValidationError>> {
+        let mut errors = Vec::new();
+
+        let types = self.sorted_types().unwrap_or_else(|type_errors| {
+            errors.extend(type_errors);
+            self.types.clone()
+        });
+        for def in &types {
+            let location = Location::Type(def.ident().to_string());
+            check_ident(':', def.ident(), &location, &mut errors);
+        }
+
+        for data in &self.data {
+            let location = Location::Data(data.name.clone());
+            check_ident('$', &data.name, &location, &mut errors);
+            for (_, item) in &data.items {
+                check_data_item(item, &location, &mut errors);
+                errors.extend(
+                    data_item_problems(item)
+                        .into_iter()
+                        .map(|kind| ValidationError {
+                            location: location.clone(),
+                            kind,
+                        }),
+                );
+            }
+        }
+
+        for func in &self.functions {
+            FunctionValidator::new(func, &mut errors).run();
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+}
+
+/// Returns true if QBE can lex `name` after a sigil
+fn is_valid_ident(name: &str) -> bool {
+    name.bytes().next().is_some_and(is_ident_start) && name.bytes().all(is_ident_char)
+}
+
+fn check_ident(sigil: char, name: &str, location: &Location, errors: &mut Vec<ValidationError>) {
+    if !is_valid_ident(name) {
+        errors.push(ValidationError {
+            location: location.clone(),
+            kind: ValidationErrorKind::InvalidIdentifier(format!("{sigil}{name}")),
+        });
+    }
+}
+
+fn check_value(val: &Value, location: &Location, errors: &mut Vec<ValidationError>) {
+    match val {
+        Value::Temporary(name) => check_ident('%', name, location, errors),
+        Value::Global(name) | Value::ThreadLocal(name) => check_ident('$', name, location, errors),
+        _ => {}
+    }
+}
+
+fn check_data_item(item: &DataItem, location: &Location, errors: &mut Vec<ValidationError>) {
+    match item {
+        DataItem::Symbol(name, _) | DataItem::ThreadLocalSymbol(name, _) => {
+            check_ident('$', name, location, errors)
+        }
+        DataItem::List(items) => {
+            for item in items {
+                check_data_item(item, location, errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+struct FunctionValidator<'a> {
+    func: &'a Function,
+    labels: HashSet<&'a str>,
+    errors: &'a mut Vec<ValidationError>,
+}
+
+impl<'a> FunctionValidator<'a> {
+    fn new(func: &'a Function, errors: &'a mut Vec<ValidationError>) -> Self {
+        Self {
+            func,
+            labels: func.blocks.iter().map(|b| b.label.as_str()).collect(),
+            errors,
+        }
+    }
+
+    fn location(&self, block: Option<&Block>, item: Option<usize>) -> Location {
+        Location::Function {
+            name: self.func.name.clone(),
+            block: block.map(|b| b.label.clone()),
+            item,
+        }
+    }
+
+    fn report(&mut self, location: Location, kind: ValidationErrorKind) {
+        self.errors.push(ValidationError { location, kind });
+    }
+
+    fn run(mut self) {
+        let func = self.func;
+        let location = self.location(None, None);
+
+        check_ident('$', &func.name, &location, self.errors);
+        for (_, val) in &func.arguments {
+            if matches!(val, Value::Temporary(_)) {
+                check_value(val, &location, self.errors);
+            } else {
+                self.report(
+                    location.clone(),
+                    ValidationErrorKind::InvalidParameter(val.clone()),
+                );
+            }
+        }
+        for kind in signature_problems(func) {
+            self.report(location.clone(), kind);
+        }
+
+        let Some(last) = func.blocks.last() else {
+            self.report(location, ValidationErrorKind::EmptyFunction);
+            return;
+        };
+
+        let mut seen = HashSet::new();
+        for block in &func.blocks {
+            let location = self.location(Some(block), None);
+            check_ident('@', &block.label, &location, self.errors);
+            if !seen.insert(block.label.as_str()) {
+                self.report(
+                    location,
+                    ValidationErrorKind::DuplicateLabel(block.label.clone()),
+                );
+            }
+            self.check_block(block);
+        }
+
+        let terminated = last.items.iter().rev().find_map(|item| match item {
+            BlockItem::Statement(Statement::Volatile(instr)) => Some(instr.is_terminator()),
+            BlockItem::Statement(_) => Some(false),
+            BlockItem::Comment(_) => None,
+        });
+        if terminated != Some(true) {
+            let location = self.location(Some(last), None);
+            self.report(location, ValidationErrorKind::FallsOffEnd);
+        }
+    }
+
+    fn check_block(&mut self, block: &Block) {
+        let mut seen_instr = false;
+        let mut jumped = false;
+
+        for (i, item) in block.items.iter().enumerate() {
+            let BlockItem::Statement(statement) = item else {
+                continue;
+            };
+            let location = self.location(Some(block), Some(i));
+
+            let instr = match statement {
+                Statement::Assign(temp, ty, instr) => {
+                    if matches!(temp, Value::Temporary(_)) {
+                        check_value(temp, &location, self.errors);
+                    }
+                    for kind in assign_problems(temp, ty, instr) {
+                        self.report(location.clone(), kind);
+                    }
+                    instr
+                }
+                Statement::Volatile(instr) => instr,
+            };
+
+            if jumped {
+                self.report(location.clone(), ValidationErrorKind::InstructionAfterJump);
+            }
+            jumped |= instr.is_terminator();
+
+            if matches!(instr, Instr::Phi(_)) {
+                if seen_instr {
+                    self.report(location.clone(), ValidationErrorKind::PhiNotAtBlockHead);
+                }
+            } else {
+                seen_instr = true;
+            }
+
+            self.check_instr(instr, &location);
+        }
+    }
+
+    fn check_instr(&mut self, instr: &Instr, location: &Location) {
+        let func = self.func;
+        for label in instr.labels() {
+            if !self.labels.contains(label) {
+                self.report(
+                    location.clone(),
+                    ValidationErrorKind::UndefinedLabel(label.to_string()),
+                );
+            }
+        }
+        for val in instr.operands() {
+            check_value(val, location, self.errors);
+        }
+
+        if let Instr::Ret(val) = instr {
+            match (val, &func.return_ty) {
+                (None, Some(_)) => {
+                    self.report(location.clone(), ValidationErrorKind::MissingReturnValue)
+                }
+                (Some(_), None) => {
+                    self.report(location.clone(), ValidationErrorKind::UnexpectedReturnValue)
+                }
+                _ => {}
+            }
+        }
+        for kind in instr_problems(instr) {
+            self.report(location.clone(), kind);
+        }
+    }
+}
+
+/// Returns the problems with a function's signature that make formatting
+/// it panic
+pub(crate) fn signature_problems(func: &Function) -> Vec<ValidationErrorKind> {
+    let mut problems = abi_args_problems(&func.arguments);
+    if let Some(ty) = &func.return_ty {
+        problems.extend(abi_type_problem(ty));
+    }
+    problems
+}
+
+/// Returns the problems with an assignment that make formatting it panic,
+/// not counting those of the instruction itself
+pub(crate) fn assign_problems(temp: &Value, ty: &Type, instr: &Instr) -> Vec<ValidationErrorKind> {
+    let mut problems = Vec::new();
+    if !matches!(temp, Value::Temporary(_)) {
+        problems.push(ValidationErrorKind::InvalidAssignTarget(temp.clone()));
+    }
+    if matches!(instr, Instr::Call(..)) {
+        problems.extend(abi_type_problem(ty));
+    } else if !ty.is_base() {
+        problems.push(ValidationErrorKind::InvalidAssignType(ty.clone()));
+    }
+    problems
+}
+
+/// Returns the problems with an instruction that make formatting it panic
+pub(crate) fn instr_problems(instr: &Instr) -> Vec<ValidationErrorKind> {
+    match instr {
+        Instr::Call(callee, args, variadic) => {
+            let mut problems = Vec::new();
+            if !matches!(callee, Value::Global(_) | Value::Temporary(_)) {
+                problems.push(ValidationErrorKind::InvalidCallTarget(callee.clone()));
+            }
+            problems.extend(abi_args_problems(args));
+            if *variadic == Some(0) && matches!(args.first(), Some((Type::Env, _))) {
+                problems.push(ValidationErrorKind::MisplacedEnv);
+            }
+            if let Some(index) = *variadic {
+                if index > args.len() as u64 {
+                    problems.push(ValidationErrorKind::InvalidVariadicIndex {
+                        index,
+                        args: args.len(),
+                    });
+                }
+            }
+            problems
+        }
+        Instr::Load(ty @ (Type::Byte | Type::Halfword | Type::Aggregate(_)), _) => {
+            vec![ValidationErrorKind::InvalidLoadType(ty.clone())]
+        }
+        Instr::Store(ty @ Type::Aggregate(_), _, _) => {
+            vec![ValidationErrorKind::InvalidStoreType(ty.clone())]
+        }
+        Instr::Cmp(ty @ Type::Aggregate(_), ..) => {
+            vec![ValidationErrorKind::InvalidCompareType(ty.clone())]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the problems with a data item that make formatting it panic
+pub(crate) fn data_item_problems(item: &DataItem) -> Vec<ValidationErrorKind> {
+    match item {
+        DataItem::List(items) => items
+            .iter()
+            .filter(|item| matches!(item, DataItem::Zero(_) | DataItem::List(_)))
+            .map(|item| ValidationErrorKind::InvalidListItem(item.clone()))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn abi_args_problems(args: &[(Type, Value)]) -> Vec<ValidationErrorKind> {
+    let mut problems = Vec::new();
+    for (i, (ty, _)) in args.iter().enumerate() {
+        if *ty == Type::Env {
+            if i != 0 {
+                problems.push(ValidationErrorKind::MisplacedEnv);
+            }
+        } else {
+            problems.extend(abi_type_problem(ty));
+        }
+    }
+    problems
+}
+
+fn abi_type_problem(ty: &Type) -> Option<ValidationErrorKind> {
+    (!ty.is_abi()).then(|| ValidationErrorKind::InvalidAbiType(ty.clone()))
+}
@@ -0,0 +1,383 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::tests::temp;
+use crate::*;
+use std::sync::Arc;
+
+fn at(block: &str, item: usize) -> Location {
+    Location::Function {
+        name: "f".into(),
+        block: Some(block.into()),
+        item: Some(item),
+    }
+}
+
+/// Validates a module holding only `func`, returning the error kinds with
+/// their locations
+fn errors_of(func: Function) -> Vec<(Location, ValidationErrorKind)> {
+    let mut module = Module::new();
+    module.add_function(func);
+    match module.validate() {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .into_iter()
+            .map(|err| (err.location, err.kind))
+            .collect(),
+    }
+}
+
+#[test]
+fn valid_module_passes() {
+    let mut module = Module::new();
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "table",
+        None,
+        vec![(Type::Long, DataItem::Symbol("table".into(), None))],
+    ));
+
+    let mut func = Function::new(
+        Linkage::public(),
+        "f",
+        vec![(Type::Word, temp("n"))],
+        Some(Type::Word),
+    );
+    let start = func.add_block("start");
+    start.add_comment("loop header follows");
+    start.add_instr(Instr::Jmp("loop".into()));
+    let head = func.add_block("loop");
+    head.assign_instr(
+        temp("i"),
+        Type::Word,
+        Instr::Phi(vec![
+            ("start".into(), 0.into()),
+            ("loop".into(), temp("next")),
+        ]),
+    );
+    head.assign_instr(temp("next"), Type::Word, Instr::Add(temp("i"), 1.into()));
+    head.assign_instr(
+        temp("done"),
+        Type::Word,
+        Instr::Cmp(Type::Word, Cmp::Eq, temp("next"), temp("n")),
+    );
+    head.add_instr(Instr::Jnz(temp("done"), "end".into(), "loop".into()));
+    let end = func.add_block("end");
+    end.add_instr(Instr::Ret(Some(temp("next"))));
+    end.add_comment("trailing comments are fine");
+    module.add_function(func);
+
+    assert_eq!(module.validate(), Ok(()));
+}
+
+#[test]
+fn labels() {
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("a")
+        .add_instr(Instr::Jnz(1.into(), "b".into(), "nowhere".into()));
+    func.add_block("b").add_instr(Instr::Jmp("a".into()));
+    func.add_block("a").add_instr(Instr::Ret(None));
+
+    assert_eq!(
+        errors_of(func),
+        vec![
+            (
+                at("a", 0),
+                ValidationErrorKind::UndefinedLabel("nowhere".into())
+            ),
+            (
+                Location::Function {
+                    name: "f".into(),
+                    block: Some("a".into()),
+                    item: None,
+                },
+                ValidationErrorKind::DuplicateLabel("a".into()),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn control_flow() {
+    let func = Function::new(Linkage::private(), "f", vec![], None);
+    assert_eq!(
+        errors_of(func),
+        vec![(
+            Location::Function {
+                name: "f".into(),
+                block: None,
+                item: None,
+            },
+            ValidationErrorKind::EmptyFunction,
+        )]
+    );
+
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    // Falling through to the next block is fine, off the end is not
+    func.add_block("a")
+        .assign_instr(temp("x"), Type::Word, Instr::Copy(1.into()));
+    let b = func.add_block("b");
+    b.add_instr(Instr::Hlt);
+    b.add_instr(Instr::Ret(None));
+    b.assign_instr(temp("y"), Type::Word, Instr::Copy(1.into()));
+    b.add_comment("not a terminator");
+
+    let errors = errors_of(func);
+    assert_eq!(
+        errors,
+        vec![
+            (at("b", 1), ValidationErrorKind::InstructionAfterJump),
+            (at("b", 2), ValidationErrorKind::InstructionAfterJump),
+            (
+                Location::Function {
+                    name: "f".into(),
+                    block: Some("b".into()),
+                    item: None,
+                },
+                ValidationErrorKind::FallsOffEnd,
+            ),
+        ]
+    );
+}
+
+#[test]
+fn return_values() {
+    let mut func = Function::new(Linkage::private(), "f", vec![], Some(Type::Long));
+    func.add_block("a")
+        .add_instr(Instr::Jnz(1.into(), "b".into(), "c".into()));
+    func.add_block("b").add_instr(Instr::Ret(None));
+    func.add_block("c").add_instr(Instr::Ret(Some(1.into())));
+    assert_eq!(
+        errors_of(func),
+        vec![(at("b", 0), ValidationErrorKind::MissingReturnValue)]
+    );
+
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("a").add_instr(Instr::Ret(Some(1.into())));
+    assert_eq!(
+        errors_of(func),
+        vec![(at("a", 0), ValidationErrorKind::UnexpectedReturnValue)]
+    );
+}
+
+#[test]
+fn phi_at_block_head() {
+    let mut func = Function::new(Linkage::private(), "f", vec![], None);
+    func.add_block("a").add_instr(Instr::Jmp("b".into()));
+    let b = func.add_block("b");
+    b.add_comment("comments don't count");
+    b.assign_instr(
+        temp("x"),
+        Type::Word,
+        Instr::Phi(vec![("a".into(), 1.into())]),
+    );
+    b.assign_instr(temp("y"), Type::Word, Instr::Copy(temp("x")));
+    b.assign_instr(
+        temp("z"),
+        Type::Word,
+        Instr::Phi(vec![("a".into(), 2.into())]),
+    );
+    b.add_instr(Instr::Ret(None));
+
+    assert_eq!(
+        errors_of(func),
+        vec![(at("b", 3), ValidationErrorKind::PhiNotAtBlockHead)]
+    );
+}
+
+#[test]
+fn identifiers() {
+    let mut module = Module::new();
+    module.add_type(Arc::new(TypeDef::Opaque {
+        ident: "my type".into(),
+        align: 8,
+        size: 8,
+    }));
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "",
+        None,
+        vec![(
+            Type::Long,
+            DataItem::List(vec![DataItem::Symbol("a-b".into(), None)]),
+        )],
+    ));
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Word, temp("ok")), (Type::Word, 1.into())],
+        None,
+    );
+    let block = func.add_block("a b");
+    block.assign_instr(
+        temp("x!"),
+        Type::Word,
+        Instr::Copy(Value::Global("g\n".into())),
+    );
+    block.add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    let errors: Vec<String> = module
+        .validate()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "type :my type: invalid identifier `:my type`",
+            "data $: invalid identifier `$`",
+            "data $: invalid identifier `$a-b`",
+            "function $f: parameter must be a temporary, got `1`",
+            "function $f, block @a b: invalid identifier `@a b`",
+            "function $f, block @a b, item 0: invalid identifier `%x!`",
+            "function $f, block @a b, item 0: invalid identifier `$g\n`",
+        ]
+    );
+}
+
+#[test]
+fn identifiers_start_with_a_letter() {
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Word, temp("1"))],
+        Some(Type::Word),
+    );
+    func.add_block("0").add_instr(Instr::Ret(Some(temp("1"))));
+    module.add_function(func);
+
+    let errors: Vec<String> = module
+        .validate()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "function $f: invalid identifier `%1`",
+            "function $f, block @0: invalid identifier `@0`",
+            "function $f, block @0, item 0: invalid identifier `%1`",
+        ]
+    );
+
+    // `$` may follow the first character
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::private(),
+        "f$1",
+        vec![(Type::Word, temp("_a$b"))],
+        Some(Type::Word),
+    );
+    func.add_block(".L$0")
+        .add_instr(Instr::Ret(Some(temp("_a$b"))));
+    module.add_function(func);
+    assert_eq!(module.validate(), Ok(()));
+}
+
+#[test]
+fn data_item_lists() {
+    let mut module = Module::new();
+    module.add_data(DataDef::new(
+        Linkage::private(),
+        "d",
+        None,
+        vec![
+            (Type::Byte, DataItem::List(vec!["ok".into(), 0.into()])),
+            (
+                Type::Byte,
+                DataItem::List(vec![
+                    DataItem::Zero(4),
+                    1.into(),
+                    DataItem::List(vec![2.into(), 3.into()]),
+                ]),
+            ),
+        ],
+    ));
+
+    let errors: Vec<String> = module
+        .validate()
+        .unwrap_err()
+        .iter()
+        .map(|err| err.to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "data $d: list items must be symbols, strings or constants, got `z 4`",
+            "data $d: list items must be symbols, strings or constants, got `2 3`",
+        ]
+    );
+}
+
+/// Every case where formatting panics is reported instead
+#[test]
+fn display_panics_are_reported() {
+    let mut func = Function::new(
+        Linkage::private(),
+        "f",
+        vec![(Type::Word, temp("a")), (Type::Env, temp("e"))],
+        Some(Type::Byte),
+    );
+    let pair = Type::Aggregate(Arc::new(TypeDef::Regular {
+        ident: "pair".into(),
+        align: None,
+        items: vec![(Type::Word, 2)],
+    }));
+    let block = func.add_block("a");
+    block.items.push(BlockItem::Statement(Statement::Assign(
+        Value::Global("g".into()),
+        Type::Byte,
+        Instr::Copy(1.into()),
+    )));
+    block.assign_instr(
+        temp("l"),
+        Type::Word,
+        Instr::Load(Type::Halfword, temp("a")),
+    );
+    block.add_instr(Instr::Store(pair.clone(), temp("a"), temp("a")));
+    block.assign_instr(
+        temp("c"),
+        Type::Word,
+        Instr::Cmp(pair.clone(), Cmp::Eq, temp("a"), temp("a")),
+    );
+    block.add_instr(Instr::Call(
+        1.into(),
+        vec![(Type::Env, temp("e")), (Type::Halfword, temp("a"))],
+        Some(0),
+    ));
+    block.add_instr(Instr::Call(Value::Global("g".into()), vec![], Some(3)));
+    block.add_instr(Instr::Hlt);
+
+    let kinds: Vec<ValidationErrorKind> =
+        errors_of(func).into_iter().map(|(_, kind)| kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            ValidationErrorKind::MisplacedEnv,
+            ValidationErrorKind::InvalidAbiType(Type::Byte),
+            ValidationErrorKind::InvalidAssignTarget(Value::Global("g".into())),
+            ValidationErrorKind::InvalidAssignType(Type::Byte),
+            ValidationErrorKind::InvalidLoadType(Type::Halfword),
+            ValidationErrorKind::InvalidStoreType(pair.clone()),
+            ValidationErrorKind::InvalidCompareType(pair),
+            ValidationErrorKind::InvalidCallTarget(1.into()),
+            ValidationErrorKind::InvalidAbiType(Type::Halfword),
+            ValidationErrorKind::MisplacedEnv,
+            ValidationErrorKind::InvalidVariadicIndex { index: 3, args: 0 },
+        ]
+    );
+    assert_eq!(
+        kinds.last().unwrap().to_string(),
+        "variadic marker at index 3 is past the end of 0 arguments"
+    );
+}
@@ -0,0 +1,330 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Emitting IL for a specific QBE release.
+
+use crate::{
+    BlockItem, DataDef, DataItem, Function, Instr, Location, Module, Statement, Type, Value,
+};
+use std::collections::HashSet;
+use std::fmt;
+
+#[cfg(test)]
+mod tests;
+
+/// A QBE release to emit IL for.
+///
+/// Newer releases accept more constructs. [`Module::lower_for`] rewrites or
+/// rejects the constructs an older release doesn't understand:
+///
+/// | Construct | Since | On older targets |
+/// |---|---|---|
+/// | `blit` | 1.1 | lowered into loads and stores |
+/// | `sb`, `ub`, `sh`, `uh` in signatures and call results | 1.1 | rejected |
+/// | thread-local linkage and `thread $name` references | 1.2 | rejected |
+/// | `hlt` | 1.2 | rejected |
+/// | `dbgfile`, `dbgloc` | 1.2 | removed |
+///
+/// # Examples
+///
+/// ```rust
+/// use qbe::{Function, Instr, Linkage, Module, QbeVersion, Value};
+///
+/// let mut module = Module::new();
+/// let mut func = Function::new(Linkage::public(), "copy", Vec::new(), None);
+/// func.add_block("start");
+/// func.add_instr(Instr::Blit(
+///     Value::Global("src".into()),
+///     Value::Global("dst".into()),
+///     4,
+/// ));
+/// func.add_instr(Instr::Ret(None));
+/// module.add_function(func);
+///
+/// let il = module.to_string_for(QbeVersion::V1_0).unwrap();
+/// assert!(il.contains("loadw $src"));
+/// assert!(il.contains("storew"));
+/// assert!(!il.contains("blit $src"));
+/// ```
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub enum QbeVersion {
+    /// QBE 1.0
+    V1_0,
+    /// QBE 1.1
+    V1_1,
+    /// QBE 1.2, the newest supported release
+    #[default]
+    V1_2,
+}
+
+impl fmt::Display for QbeVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::V1_0 => write!(f, "1.0"),
+            Self::V1_1 => write!(f, "1.1"),
+            Self::V1_2 => write!(f, "1.2"),
+        }
+    }
+}
+
+/// A construct that the targeted QBE release cannot handle
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct VersionError {
+    /// The offending construct as it appears in IL, e.g. `hlt`
+    pub construct: String,
+    /// The first release supporting the construct
+    pub required: QbeVersion,
+    /// The targeted release
+    pub target: QbeVersion,
+    /// Where the construct was found
+    pub location: Location,
+}
+
+impl fmt::Display for VersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: `{}` requires QBE {} or newer, but QBE {} is targeted",
+            self.location, self.construct, self.required, self.target
+        )
+    }
+}
+
+impl std::error::Error for VersionError {}
+
+impl Module {
+    /// Returns a copy of the module that the given QBE release accepts.
+    ///
+    /// Constructs newer than `version` are lowered where an equivalent
+    /// exists and rejected otherwise; see [`QbeVersion`] for the details.
+    pub fn lower_for(&self, version: QbeVersion) -> Result<Module, VersionError> {
+        let mut module = self.clone();
+        for data in &module.data {
+            check_data(data, version)?;
+        }
+        for func in &mut module.functions {
+            lower_function(func, version)?;
+        }
+        Ok(module)
+    }
+
+    /// Lowers the module for the given QBE release and formats it as IL.
+    ///
+    /// See [`Module::lower_for`].
+    pub fn to_string_for(&self, version: QbeVersion) -> Result<String, VersionError> {
+        Ok(self.lower_for(version)?.to_string())
+    }
+}
+
+/// Fails if `target` is older than `required`
+fn require(
+    construct: impl fmt::Display,
+    required: QbeVersion,
+    target: QbeVersion,
+    location: impl FnOnce() -> Location,
+) -> Result<(), VersionError> {
+    if target >= required {
+        return Ok(());
+    }
+    Err(VersionError {
+        construct: construct.to_string(),
+        required,
+        target,
+        location: location(),
+    })
+}
+
+fn is_subword(ty: &Type) -> bool {
+    matches!(
+        ty,
+        Type::SignedByte | Type::UnsignedByte | Type::SignedHalfword | Type::UnsignedHalfword
+    )
+}
+
+fn check_data(data: &DataDef, version: QbeVersion) -> Result<(), VersionError> {
+    let location = || Location::Data(data.name.clone());
+    if data.linkage.thread_local {
+        require("thread", QbeVersion::V1_2, version, location)?;
+    }
+
+    fn check_item(
+        item: &DataItem,
+        version: QbeVersion,
+        location: &dyn Fn() -> Location,
+    ) -> Result<(), VersionError> {
+        match item {
+            DataItem::ThreadLocalSymbol(..) => require(item, QbeVersion::V1_2, version, location),
+            DataItem::List(items) => items
+                .iter()
+                .try_for_each(|item| check_item(item, version, location)),
+            _ => Ok(()),
+        }
+    }
+
+    data.items
+        .iter()
+        .try_for_each(|(_, item)| check_item(item, version, &location))
+}
+
+fn lower_function(func: &mut Function, version: QbeVersion) -> Result<(), VersionError> {
+    let name = func.name.clone();
+    let location = |block: Option<&str>, item: Option<usize>| Location::Function {
+        name: name.clone(),
+        block: block.map(str::to_string),
+        item,
+    };
+
+    if func.linkage.thread_local {
+        require("thread", QbeVersion::V1_2, version, || location(None, None))?;
+    }
+    for ty in func
+        .arguments
+        .iter()
+        .map(|(ty, _)| ty)
+        .chain(&func.return_ty)
+        .filter(|ty| is_subword(ty))
+    {
+        require(ty, QbeVersion::V1_1, version, || location(None, None))?;
+    }
+
+    let mut names = TempNames::new(func);
+    for block in &mut func.blocks {
+        let mut items = Vec::with_capacity(block.items.len());
+        for (i, item) in block.items.drain(..).enumerate() {
+            let at = || location(Some(&block.label), Some(i));
+            let instr = match &item {
+                BlockItem::Statement(Statement::Assign(_, ty, instr)) => {
+                    if matches!(instr, Instr::Call(..)) && is_subword(ty) {
+                        require(ty, QbeVersion::V1_1, version, at)?;
+                    }
+                    instr
+                }
+                BlockItem::Statement(Statement::Volatile(instr)) => instr,
+                BlockItem::Comment(_) => {
+                    items.push(item);
+                    continue;
+                }
+            };
+
+            for val in instr.operands() {
+                if let Value::ThreadLocal(_) = val {
+                    require(val, QbeVersion::V1_2, version, at)?;
+                }
+            }
+            if let Instr::Call(_, args, _) = instr {
+                for (ty, _) in args.iter().filter(|(ty, _)| is_subword(ty)) {
+                    require(ty, QbeVersion::V1_1, version, at)?;
+                }
+            }
+
+            match instr {
+                Instr::Hlt => require("hlt", QbeVersion::V1_2, version, at)?,
+                Instr::DbgFile(_) | Instr::DbgLoc(..) if version < QbeVersion::V1_2 => continue,
+                Instr::Blit(src, dst, n) if version < QbeVersion::V1_1 => {
+                    items.extend(lower_blit(src, dst, *n, &mut names));
+                    continue;
+                }
+                _ => {}
+            }
+            items.push(item);
+        }
+        block.items = items;
+    }
+
+    Ok(())
+}
+
+/// Replaces `blit` by loads and stores of decreasing width
+fn lower_blit(src: &Value, dst: &Value, n: u64, names: &mut TempNames) -> Vec<BlockItem> {
+    let mut items = Vec::new();
+    let mut offset = 0;
+
+    for (width, load_ty, store_ty) in [
+        (8, Type::Long, Type::Long),
+        (4, Type::Word, Type::Word),
+        (2, Type::UnsignedHalfword, Type::Halfword),
+        (1, Type::UnsignedByte, Type::Byte),
+    ] {
+        while n - offset >= width {
+            let from = address(src, offset, &mut items, names);
+            let val = names.fresh();
+            items.push(BlockItem::Statement(Statement::Assign(
+                val.clone(),
+                load_ty.clone().into_base(),
+                Instr::Load(load_ty.clone(), from),
+            )));
+            let to = address(dst, offset, &mut items, names);
+            items.push(BlockItem::Statement(Statement::Volatile(Instr::Store(
+                store_ty.clone(),
+                to,
+                val,
+            ))));
+            offset += width;
+        }
+    }
+
+    items
+}
+
+/// Returns `base + offset`, emitting an addition when the offset is nonzero
+fn address(base: &Value, offset: u64, items: &mut Vec<BlockItem>, names: &mut TempNames) -> Value {
+    if offset == 0 {
+        return base.clone();
+    }
+    let addr = names.fresh();
+    items.push(BlockItem::Statement(Statement::Assign(
+        addr.clone(),
+        Type::Long,
+        Instr::Add(base.clone(), Value::Const(offset)),
+    )));
+    addr
+}
+
+/// Hands out temporaries that don't clash with any used in a function
+struct TempNames {
+    used: HashSet<String>,
+    next: usize,
+}
+
+impl TempNames {
+    fn new(func: &Function) -> Self {
+        let mut used = HashSet::new();
+        let mut add = |val: &Value| {
+            if let Value::Temporary(name) = val {
+                used.insert(name.clone());
+            }
+        };
+        for (_, val) in &func.arguments {
+            add(val);
+        }
+        for item in func.blocks.iter().flat_map(|blk| &blk.items) {
+            match item {
+                BlockItem::Statement(Statement::Assign(temp, _, instr)) => {
+                    add(temp);
+                    instr.operands().into_iter().for_each(&mut add);
+                }
+                BlockItem::Statement(Statement::Volatile(instr)) => {
+                    instr.operands().into_iter().for_each(&mut add);
+                }
+                BlockItem::Comment(_) => {}
+            }
+        }
+        Self { used, next: 0 }
+    }
+
+    fn fresh(&mut self) -> Value {
+        loop {
+            let name = format!("blit.{}", self.next);
+            self.next += 1;
+            if self.used.insert(name.clone()) {
+                return Value::Temporary(name);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Copyright 2022 Garrit Franke
+// Copyright 2021 Alexey Yerin
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use crate::*;
+
+fn module_with(instrs: Vec<Instr>) -> Module {
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::public(),
+        "f",
+        vec![
+            (Type::Long, Value::Temporary("src".into())),
+            (Type::Long, Value::Temporary("dst".into())),
+        ],
+        None,
+    );
+    func.add_block("start");
+    for instr in instrs {
+        func.add_instr(instr);
+    }
+    func.add_instr(Instr::Ret(None));
+    module.add_function(func);
+    module
+}
+
+#[test]
+fn version_order() {
+    assert!(QbeVersion::V1_0 < QbeVersion::V1_1);
+    assert!(QbeVersion::V1_1 < QbeVersion::V1_2);
+    assert_eq!(QbeVersion::default(), QbeVersion::V1_2);
+    assert_eq!(QbeVersion::V1_1.to_string(), "1.1");
+}
+
+#[test]
+fn blit_lowered_for_1_0() {
+    let module = module_with(vec![Instr::Blit(
+        Value::Temporary("src".into()),
+        Value::Temporary("dst".into()),
+        15,
+    )]);
+
+    let lowered = module.lower_for(QbeVersion::V1_0).unwrap();
+    let formatted = format!("{}", lowered.functions[0]);
+    let lines: Vec<&str> = formatted.lines().collect();
+    assert_eq!(
+        lines[1..lines.len() - 1],
+        [
+            "@start",
+            "\t%blit.0 =l loadl %src",
+            "\tstorel %blit.0, %dst",
+            "\t%blit.1 =l add %src, 8",
+            "\t%blit.2 =w loadw %blit.1",
+            "\t%blit.3 =l add %dst, 8",
+            "\tstorew %blit.2, %blit.3",
+            "\t%blit.4 =l add %src, 12",
+            "\t%blit.5 =w loaduh %blit.4",
+            "\t%blit.6 =l add %dst, 12",
+            "\tstoreh %blit.5, %blit.6",
+            "\t%blit.7 =l add %src, 14",
+            "\t%blit.8 =w loadub %blit.7",
+            "\t%blit.9 =l add %dst, 14",
+            "\tstoreb %blit.8, %blit.9",
+            "\tret",
+        ]
+    );
+
+    // Newer releases keep the instruction
+    assert_eq!(module.lower_for(QbeVersion::V1_1).unwrap(), module);
+}
+
+#[test]
+fn blit_lowering_avoids_existing_names() {
+    let mut module = module_with(vec![Instr::Blit(
+        Value::Temporary("blit.0".into()),
+        Value::Temporary("dst".into()),
+        4,
+    )]);
+    module.functions[0].arguments[0].1 = Value::Temporary("blit.0".into());
+
+    let il = module.to_string_for(QbeVersion::V1_0).unwrap();
+    assert!(il.contains("%blit.1 =w loadw %blit.0"));
+    assert!(il.contains("storew %blit.1, %dst"));
+}
+
+#[test]
+fn debug_info_removed_before_1_2() {
+    let module = module_with(vec![
+        Instr::DbgFile("main.c".into()),
+        Instr::DbgLoc(3, Some(1)),
+    ]);
+
+    let il = module.to_string_for(QbeVersion::V1_1).unwrap();
+    assert!(!il.contains("dbg"));
+
+    let il = module.to_string_for(QbeVersion::V1_2).unwrap();
+    assert!(il.contains("dbgfile \"main.c\""));
+    assert!(il.contains("dbgloc 3, 1"));
+}
+
+#[test]
+fn unsupported_constructs_rejected() {
+    let module = module_with(vec![Instr::Hlt]);
+    let err = module.lower_for(QbeVersion::V1_1).unwrap_err();
+    assert_eq!(
+        err,
+        VersionError {
+            construct: "hlt".into(),
+            required: QbeVersion::V1_2,
+            target: QbeVersion::V1_1,
+            location: Location::Function {
+                name: "f".into(),
+                block: Some("start".into()),
+                item: Some(0),
+            },
+        }
+    );
+    assert_eq!(
+        err.to_string(),
+        "function $f, block @start, item 0: `hlt` requires QBE 1.2 or newer, but QBE 1.1 is targeted"
+    );
+
+    let module = module_with(vec![Instr::Copy(Value::ThreadLocal("tls".into()))]);
+    let err = module.lower_for(QbeVersion::V1_1).unwrap_err();
+    assert_eq!(err.construct, "thread $tls");
+
+    let mut module = Module::new();
+    module.add_data(DataDef::new(
+        Linkage::thread_local(),
+        "tls",
+        None,
+        vec![(Type::Word, DataItem::Const(0))],
+    ));
+    let err = module.lower_for(QbeVersion::V1_0).unwrap_err();
+    assert_eq!(err.location, Location::Data("tls".into()));
+    assert!(module.lower_for(QbeVersion::V1_2).is_ok());
+}
+
+#[test]
+fn subword_abi_types_rejected_before_1_1() {
+    let mut module = Module::new();
+    let mut func = Function::new(
+        Linkage::public(),
+        "f",
+        vec![(Type::SignedByte, Value::Temporary("c".into()))],
+        None,
+    );
+    func.add_block("start");
+    func.add_instr(Instr::Ret(None));
+    module.add_function(func);
+
+    let err = module.lower_for(QbeVersion::V1_0).unwrap_err();
+    assert_eq!(err.construct, "sb");
+    assert_eq!(
+        err.location,
+        Location::Function {
+            name: "f".into(),
+            block: None,
+            item: None,
+        }
+    );
+    assert!(module.lower_for(QbeVersion::V1_1).is_ok());
+
+    let mut module = module_with(Vec::new());
+    module.functions[0].blocks[0].items.insert(
+        0,
+        BlockItem::Statement(Statement::Assign(
+            Value::Temporary("r".into()),
+            Type::UnsignedHalfword,
+            Instr::Call(Value::Global("g".into()), Vec::new(), None),
+        )),
+    );
+    let err = module.lower_for(QbeVersion::V1_0).unwrap_err();
+    assert_eq!(err.construct, "uh");
+}